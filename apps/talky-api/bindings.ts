@@ -3,13 +3,33 @@
 
 export type ChannelType = "chat" | "feed" | "multi_media"
 
+export type DirectMessageResource = { id: string; conversation_id: string; user_id: string; timestamp: string; contents: string }
+
 export type Edge<T> = { cursor: string; node: T }
 
 export type LobbyResource = { id: string; name: string; channel_id: string; niche_id: string; owner_user_id: string }
 
 export type PageInfo = { has_next_page: boolean; has_prev_page: boolean; start_cursor: string | null; end_cursor: string | null; total_count: number }
 
-export type ProceduresLegacy = { queries: { key: "auth_refresh_token"; input: string; result: { access_token: string; refresh_token: string } } | { key: "category_list"; input: { before: string | null; after: string | null; first: number | null; last: number | null; niche_id: string }; result: { page_info: PageInfo; edges: Edge<T>[]; meta: M } } | { key: "channel_find_by_slug"; input: string; result: { id: string; name: string; slug: string; type: ChannelType; niche_id: string; lobbies: LobbyResource[] } } | { key: "channel_list_users"; input: { before: string | null; after: string | null; first: number | null; last: number | null; niche_id: string }; result: { page_info: PageInfo; edges: Edge<T>[]; meta: M } } | { key: "channel_messages"; input: { before: string | null; after: string | null; first: number | null; last: number | null; channel_id: string }; result: { page_info: PageInfo; edges: Edge<T>[]; meta: M } } | { key: "lobby_create_temporary"; input: { name: string; channel_id: string }; result: { id: string; name: string; channel_id: string; niche_id: string; owner_user_id: string } } | { key: "niche_find_by_slug"; input: string; result: { name: string; slug: string; id: string } } | { key: "niche_list"; input: { before: string | null; after: string | null; first: number | null; last: number | null; niche_id: string }; result: { page_info: PageInfo; edges: Edge<T>[]; meta: M } }; mutations: { key: "auth_login"; input: { username: string; password: string }; result: { access_token: string; refresh_token: string } }; subscriptions: never }
+export type ProceduresLegacy = { queries: { key: "auth_refresh_token"; input: string; result: { access_token: string; refresh_token: string } } | { key: "category_list"; input: { before: string | null; after: string | null; first: number | null; last: number | null; niche_id: string }; result: { page_info: PageInfo; edges: Edge<T>[]; meta: M } } | { key: "channel_find_by_slug"; input: string; result: { id: string; name: string; slug: string; type: ChannelType; niche_id: string; lobbies: LobbyResource[] } } | { key: "channel_list_users"; input: { before: string | null; after: string | null; first: number | null; last: number | null; niche_id: string }; result: { page_info: PageInfo; edges: Edge<T>[]; meta: M } } | { key: "channel_messages"; input: { before: string | null; after: string | null; first: number | null; last: number | null; channel_id: string }; result: { page_info: PageInfo; edges: Edge<T>[]; meta: M } } | { key: "conversation_list"; input: { before: string | null; after: string | null; first: number | null; last: number | null }; result: { page_info: PageInfo; edges: Edge<T>[]; meta: M } } | { key: "conversation_messages"; input: { before: string | null; after: string | null; first: number | null; last: number | null; conversation_id: string }; result: { page_info: PageInfo; edges: Edge<T>[]; meta: M } } | { key: "lobby_create_temporary"; input: { name: string; channel_id: string; contract?: TemporaryContract }; result: { id: string; name: string; channel_id: string; niche_id: string; owner_user_id: string } } | { key: "niche_find_by_slug"; input: string; result: { name: string; slug: string; id: string } } | { key: "niche_list"; input: { before: string | null; after: string | null; first: number | null; last: number | null; niche_id: string }; result: { page_info: PageInfo; edges: Edge<T>[]; meta: M } }; mutations: { key: "auth_login"; input: { username: string; password: string }; result: { access_token: string; refresh_token: string } } | { key: "conversation_create"; input: { 
+/**
+ * Everyone to talk to, without the creator.
+ */
+user_ids: string[] }; result: { id: string; member_ids: string[]; last_message: DirectMessageResource | null; 
+/**
+ * Messages from others the member has not read yet.
+ */
+unread_count: number } } | { key: "conversation_mark_read"; input: string; result: null } | { key: "niche_join"; input: string; result: "member" | "moderator" | "admin" }; subscriptions: never }
+
+export type TemporaryContract = 
+/**
+ * Deleted once the last client leaves.
+ */
+{ type: "expire_when_empty" } | 
+/**
+ * Deleted `expires` seconds after creation, even if clients are inside.
+ */
+{ type: "expires"; expires: number }
 
 export type Procedures = {
 	auth_login: { kind: "mutation", input: { username: string; password: string }, output: { access_token: string; refresh_token: string }, error: unknown },
@@ -18,7 +38,12 @@ export type Procedures = {
 	channel_find_by_slug: { kind: "query", input: string, output: { id: string; name: string; slug: string; type: ChannelType; niche_id: string; lobbies: LobbyResource[] }, error: unknown },
 	channel_list_users: { kind: "query", input: { before: string | null; after: string | null; first: number | null; last: number | null; niche_id: string }, output: { page_info: PageInfo; edges: Edge<T>[]; meta: M }, error: unknown },
 	channel_messages: { kind: "query", input: { before: string | null; after: string | null; first: number | null; last: number | null; channel_id: string }, output: { page_info: PageInfo; edges: Edge<T>[]; meta: M }, error: unknown },
-	lobby_create_temporary: { kind: "query", input: { name: string; channel_id: string }, output: { id: string; name: string; channel_id: string; niche_id: string; owner_user_id: string }, error: unknown },
+	conversation_create: { kind: "mutation", input: { user_ids: string[] }, output: { id: string; member_ids: string[]; last_message: DirectMessageResource | null; unread_count: number }, error: unknown },
+	conversation_list: { kind: "query", input: { before: string | null; after: string | null; first: number | null; last: number | null }, output: { page_info: PageInfo; edges: Edge<T>[]; meta: M }, error: unknown },
+	conversation_mark_read: { kind: "mutation", input: string, output: null, error: unknown },
+	conversation_messages: { kind: "query", input: { before: string | null; after: string | null; first: number | null; last: number | null; conversation_id: string }, output: { page_info: PageInfo; edges: Edge<T>[]; meta: M }, error: unknown },
+	lobby_create_temporary: { kind: "query", input: { name: string; channel_id: string; contract?: TemporaryContract }, output: { id: string; name: string; channel_id: string; niche_id: string; owner_user_id: string }, error: unknown },
 	niche_find_by_slug: { kind: "query", input: string, output: { name: string; slug: string; id: string }, error: unknown },
+	niche_join: { kind: "mutation", input: string, output: "member" | "moderator" | "admin", error: unknown },
 	niche_list: { kind: "query", input: { before: string | null; after: string | null; first: number | null; last: number | null; niche_id: string }, output: { page_info: PageInfo; edges: Edge<T>[]; meta: M }, error: unknown },
 }
\ No newline at end of file
@@ -0,0 +1,103 @@
+use std::collections::HashSet;
+
+use talky_services::{
+    conversation::service::{
+        ConversationResource, ConversationService, CreateConversationArgs, DirectMessageResource,
+        ListConversationArgs, ListConversationMeta, ListDirectMessageArgs, ListDirectMessageMeta,
+        MAX_CONVERSATION_MEMBERS,
+    },
+    pagination::ListResult,
+};
+
+use crate::{
+    error::{AppError, AppResult},
+    http::context::Ctx,
+};
+
+pub struct ConversationController {
+    ctx: Ctx,
+    conversation_service: ConversationService,
+}
+
+impl ConversationController {
+    pub async fn create(self, args: CreateConversationArgs) -> AppResult<ConversationResource> {
+        let user = self.ctx.required_user()?;
+        let others: HashSet<&String> = args.user_ids.iter().filter(|id| **id != user.sub).collect();
+        if others.is_empty() || others.len() >= MAX_CONVERSATION_MEMBERS {
+            return Err(AppError::BadRequest(format!(
+                "a conversation has 2 to {} members",
+                MAX_CONVERSATION_MEMBERS
+            )));
+        }
+
+        self.conversation_service
+            .create(&user.sub, &args.user_ids)
+            .await
+            .map_err(|e| AppError::InternalServerError(e.to_string()))?
+            .ok_or_else(|| AppError::BadRequest("unknown user".to_string()))
+    }
+
+    pub async fn list(
+        self,
+        mut args: ListConversationArgs,
+    ) -> AppResult<ListResult<ConversationResource, ListConversationMeta>> {
+        args.user_id = self.ctx.required_user()?.sub.clone();
+
+        let response = self
+            .conversation_service
+            .list(&args)
+            .await
+            .map_err(|e| AppError::InternalServerError(e.to_string()))?;
+
+        Ok(response)
+    }
+
+    pub async fn list_messages(
+        self,
+        args: ListDirectMessageArgs,
+    ) -> AppResult<ListResult<DirectMessageResource, ListDirectMessageMeta>> {
+        self.required_member(&args.conversation_id).await?;
+
+        let response = self
+            .conversation_service
+            .list_messages(&args)
+            .await
+            .map_err(|e| AppError::InternalServerError(e.to_string()))?;
+
+        Ok(response)
+    }
+
+    pub async fn mark_read(self, conversation_id: String) -> AppResult<()> {
+        let user_id = self.required_member(&conversation_id).await?;
+
+        self.conversation_service
+            .mark_read(&conversation_id, &user_id)
+            .await
+            .map_err(|e| AppError::InternalServerError(e.to_string()))
+    }
+
+    /// The caller's user id, if it takes part in the conversation.
+    async fn required_member(&self, conversation_id: &str) -> AppResult<String> {
+        let user = self.ctx.required_user()?;
+        let member_ids = self
+            .conversation_service
+            .list_member_ids(conversation_id)
+            .await
+            .map_err(|e| AppError::InternalServerError(e.to_string()))?;
+
+        if !member_ids.contains(&user.sub) {
+            return Err(AppError::Unauthorized);
+        }
+
+        Ok(user.sub.clone())
+    }
+
+    pub(crate) fn new(ctx: Ctx) -> Self {
+        let conversation_service = ConversationService::new(ctx.pool_clone());
+
+        Self {
+            ctx,
+            conversation_service,
+        }
+    }
+}
@@ -3,6 +3,7 @@ use sqlx::{Pool, Postgres};
 use talky_services::{
     lobby::service::{
         CreateLobbyArgs, ListLobbyArgs, ListLobbyMeta, LobbyResource, LobbyService, LobbyType,
+        TemporaryContract,
     },
     message::service::{ListMessageArgs, ListMessageMeta, MessageResource, MessageService},
     pagination::ListResult,
@@ -28,6 +29,14 @@ pub struct LobbyController {
 impl LobbyController {
     pub async fn create(self, args: CreateLobbyArgs) -> AppResult<LobbyResource> {
         let user = self.ctx.required_user()?;
+        if let TemporaryContract::Expires { expires } = args.contract {
+            if expires <= 0 {
+                return Err(AppError::BadRequest(
+                    "a lobby must expire after a positive number of seconds".to_string(),
+                ));
+            }
+        }
+
         let response = self
             .lobby_service
             .create(&args, &user.sub)
@@ -1,5 +1,6 @@
 pub(crate) mod authentication;
 pub(crate) mod category;
 pub(crate) mod channel;
+pub(crate) mod conversation;
 pub(crate) mod lobby;
 pub(crate) mod niche;
@@ -1,7 +1,7 @@
 use bcrypt::verify;
 use sqlx::{Pool, Postgres};
 use talky_services::{
-    niche::service::{ListNicheArgs, ListNicheMeta, NicheResource, NicheService},
+    niche::service::{ListNicheArgs, ListNicheMeta, NicheResource, NicheRole, NicheService},
     pagination::ListResult,
 };
 
@@ -32,6 +32,16 @@ impl NicheController {
         Ok(response)
     }
 
+    pub async fn join(self, niche_id: String) -> AppResult<NicheRole> {
+        let user = self.ctx.required_user()?;
+
+        self.niche_service
+            .join(&niche_id, &user.sub)
+            .await
+            .map_err(|e| AppError::InternalServerError(e.to_string()))?
+            .ok_or_else(|| AppError::BadRequest("unknown niche".to_string()))
+    }
+
     pub async fn list(
         self,
         args: ListNicheArgs,
@@ -0,0 +1,32 @@
+use rspc::Router;
+use talky_services::conversation::service::{
+    CreateConversationArgs, ListConversationArgs, ListDirectMessageArgs,
+};
+
+use crate::http::{context::Ctx, controllers::conversation::ConversationController};
+
+use super::BaseProcedure;
+
+pub fn create_conversation_router() -> Router<Ctx> {
+    Router::<Ctx>::new()
+        .procedure("conversation_list", {
+            <BaseProcedure>::builder().query(|ctx, args: ListConversationArgs| {
+                ConversationController::new(ctx).list(args)
+            })
+        })
+        .procedure("conversation_messages", {
+            <BaseProcedure>::builder().query(|ctx, args: ListDirectMessageArgs| {
+                ConversationController::new(ctx).list_messages(args)
+            })
+        })
+        .procedure("conversation_create", {
+            <BaseProcedure>::builder().mutation(|ctx, args: CreateConversationArgs| {
+                ConversationController::new(ctx).create(args)
+            })
+        })
+        .procedure("conversation_mark_read", {
+            <BaseProcedure>::builder().mutation(|ctx, conversation_id: String| {
+                ConversationController::new(ctx).mark_read(conversation_id)
+            })
+        })
+}
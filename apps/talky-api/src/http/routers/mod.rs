@@ -3,6 +3,7 @@ use std::{marker::PhantomData, path::PathBuf, sync::Arc};
 use authentication::create_authentication_router;
 use category::create_category_router;
 use channel::create_channel_router;
+use conversation::create_conversation_router;
 use lobby::create_lobby_router;
 use niche::create_niche_router;
 use rspc::{Procedure, ProcedureBuilder, ResolverInput, ResolverOutput};
@@ -20,6 +21,7 @@ use specta::Type;
 mod authentication;
 mod category;
 mod channel;
+mod conversation;
 mod lobby;
 mod niche;
 
@@ -62,6 +64,7 @@ pub fn mount() -> Router<Ctx> {
         .merge(create_niche_router())
         .merge(create_lobby_router())
         .merge(create_category_router())
+        .merge(create_conversation_router())
 }
 
 pub fn timing_middleware<TError, TCtx, TInput, TResult>(
@@ -23,4 +23,8 @@ pub fn create_niche_router() -> Router<Ctx> {
             <BaseProcedure>::builder()
                 .query(|ctx, args: ListNicheArgs| NicheController::new(ctx).list(args))
         })
+        .procedure("niche_join", {
+            <BaseProcedure>::builder()
+                .mutation(|ctx, niche_id: String| NicheController::new(ctx).join(niche_id))
+        })
 }
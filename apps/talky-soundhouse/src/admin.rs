@@ -0,0 +1,99 @@
+use crate::error::AppError;
+use crate::state::AppState;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+/// Read and repair live state. Every route needs `Authorization: Bearer
+/// <SOUNDHOUSE_ADMIN_TOKEN>`.
+///
+/// - `GET /admin/clients`: connected clients and where they are
+/// - `DELETE /admin/clients/:client_id`: disconnects the client
+/// - `GET /admin/rooms`: rooms and their members by niche
+/// - `DELETE /admin/rooms/:lobby_id`: empties the room
+pub fn admin_routes(
+    state: AppState,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let admin = warp::path("admin").and(with_admin(state));
+
+    let list_clients = admin
+        .clone()
+        .and(warp::path("clients"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(|state: AppState| async move {
+            let clients = state
+                .client_summaries()
+                .await
+                .map_err(warp::reject::custom)?;
+            Ok::<_, Rejection>(warp::reply::json(&serde_json::json!({
+                "instance_id": state.config().instance_id,
+                "clients": clients,
+            })))
+        });
+
+    let disconnect_client = admin
+        .clone()
+        .and(warp::path!("clients" / String))
+        .and(warp::delete())
+        .and_then(|state: AppState, client_id: String| async move {
+            state
+                .force_disconnect(&client_id)
+                .await
+                .map_err(warp::reject::custom)?;
+            Ok::<_, Rejection>(StatusCode::NO_CONTENT)
+        });
+
+    let list_rooms = admin
+        .clone()
+        .and(warp::path("rooms"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(|state: AppState| async move {
+            let rooms = state.all_rooms().await.map_err(warp::reject::custom)?;
+            Ok::<_, Rejection>(warp::reply::json(&rooms))
+        });
+
+    let tear_down_room = admin
+        .and(warp::path!("rooms" / String))
+        .and(warp::delete())
+        .and_then(|state: AppState, lobby_id: String| async move {
+            state
+                .tear_down_room(&lobby_id)
+                .await
+                .map_err(warp::reject::custom)?;
+            Ok::<_, Rejection>(StatusCode::NO_CONTENT)
+        });
+
+    list_clients
+        .or(disconnect_client)
+        .or(list_rooms)
+        .or(tear_down_room)
+}
+
+fn with_admin(state: AppState) -> impl Filter<Extract = (AppState,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(
+        move |authorization: Option<String>| {
+            let state = state.clone();
+            async move {
+                // Without a token the admin API does not exist
+                let Some(token) = state.config().admin_token.as_deref() else {
+                    return Err(warp::reject::not_found());
+                };
+
+                let presented = authorization
+                    .as_deref()
+                    .and_then(|value| value.strip_prefix("Bearer "));
+                match presented {
+                    Some(presented) if constant_time_eq(presented.as_bytes(), token.as_bytes()) => {
+                        Ok(state)
+                    }
+                    _ => Err(warp::reject::custom(AppError::Unauthorized)),
+                }
+            }
+        },
+    )
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,442 @@
+use super::room::RoomHandle;
+use super::{Backend, ClientId, Envelope, Heartbeat, LeftRoom, LobbyId, Moderation, NicheId};
+use crate::error::AppResult;
+use crate::state::{RoomClientInfo, RoomResource, UserResource};
+use async_trait::async_trait;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::collections::{HashMap, HashSet};
+use talky_services::lobby::service::LobbyResource;
+use tokio::sync::broadcast;
+
+/// Keeps everything in process. Only suitable for a single soundhouse replica.
+///
+/// Every room runs as its own task (see [`RoomHandle`]) and the maps below
+/// only index them. Map guards are never held across an `.await`, and the
+/// only time two are held at once is `rooms` before `niche_rooms`, so the
+/// indexes cannot deadlock.
+pub struct MemoryBackend {
+    clients: DashMap<ClientId, UserResource>,
+    // user_id -> the user's clients, only written while holding the client's
+    // entry in `clients`
+    user_clients: DashMap<String, HashSet<ClientId>>,
+    rooms: DashMap<LobbyId, RoomHandle>,
+    // Only written while holding the room's entry in `rooms`
+    niche_rooms: DashMap<NicheId, HashSet<LobbyId>>,
+    client_rooms: DashMap<ClientId, HashSet<LobbyId>>,
+    moderation: DashMap<LobbyId, HashMap<String, Moderation>>,
+    sender: broadcast::Sender<Envelope>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(1024);
+
+        Self {
+            clients: DashMap::new(),
+            user_clients: DashMap::new(),
+            rooms: DashMap::new(),
+            niche_rooms: DashMap::new(),
+            client_rooms: DashMap::new(),
+            moderation: DashMap::new(),
+            sender,
+        }
+    }
+
+    fn room(&self, lobby_id: &str) -> Option<RoomHandle> {
+        self.rooms.get(lobby_id).map(|room| room.value().clone())
+    }
+
+    /// Returns the lobby's room, spawning it if nobody is in there yet.
+    fn open_room(&self, lobby: &LobbyResource) -> RoomHandle {
+        match self.rooms.entry(lobby.id.clone()) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => {
+                self.niche_rooms
+                    .entry(lobby.niche_id.clone())
+                    .or_default()
+                    .insert(lobby.id.clone());
+                entry.insert(RoomHandle::spawn(lobby.clone())).value().clone()
+            }
+        }
+    }
+
+    fn forget_user_client(&self, user_id: &str, client_id: &str) {
+        if let Entry::Occupied(mut client_ids) = self.user_clients.entry(user_id.to_string()) {
+            client_ids.get_mut().remove(client_id);
+            if client_ids.get().is_empty() {
+                client_ids.remove();
+            }
+        }
+    }
+
+    /// Drops a closed room from the indexes, unless it was replaced already.
+    fn forget_room(&self, room: &RoomHandle) {
+        let lobby = room.lobby();
+        let Entry::Occupied(entry) = self.rooms.entry(lobby.id.clone()) else {
+            return;
+        };
+        if !entry.get().same_room(room) {
+            return;
+        }
+
+        if let Entry::Occupied(mut lobby_ids) = self.niche_rooms.entry(lobby.niche_id.clone()) {
+            lobby_ids.get_mut().remove(&lobby.id);
+            if lobby_ids.get().is_empty() {
+                lobby_ids.remove();
+            }
+        }
+        entry.remove();
+    }
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Backend for MemoryBackend {
+    async fn add_client(&self, client_id: &str, user: &UserResource) -> AppResult<()> {
+        let mut entry = self
+            .clients
+            .entry(client_id.to_string())
+            .or_insert_with(|| user.clone());
+        if entry.user_id != user.user_id {
+            self.forget_user_client(&entry.user_id, client_id);
+        }
+        *entry = user.clone();
+        self.user_clients
+            .entry(user.user_id.clone())
+            .or_default()
+            .insert(client_id.to_string());
+
+        Ok(())
+    }
+
+    async fn remove_client(&self, client_id: &str) -> AppResult<()> {
+        if let Entry::Occupied(entry) = self.clients.entry(client_id.to_string()) {
+            self.forget_user_client(&entry.get().user_id, client_id);
+            entry.remove();
+        }
+
+        Ok(())
+    }
+
+    async fn clients(&self) -> AppResult<HashMap<ClientId, UserResource>> {
+        Ok(self
+            .clients
+            .iter()
+            .map(|client| (client.key().clone(), client.value().clone()))
+            .collect())
+    }
+
+    async fn client(&self, client_id: &str) -> AppResult<Option<UserResource>> {
+        Ok(self.clients.get(client_id).map(|user| user.value().clone()))
+    }
+
+    async fn has_client(&self, client_id: &str) -> AppResult<bool> {
+        Ok(self.clients.contains_key(client_id))
+    }
+
+    async fn online_user_ids(&self, user_ids: &[String]) -> AppResult<Vec<String>> {
+        Ok(user_ids
+            .iter()
+            .filter(|user_id| self.user_clients.contains_key(user_id.as_str()))
+            .cloned()
+            .collect())
+    }
+
+    async fn join_room(&self, lobby: &LobbyResource, client: RoomClientInfo) -> AppResult<()> {
+        let client_id = client.client_id.clone();
+
+        // Indexed before the room has the client, so that a leave always
+        // finds every room the client may be in
+        self.client_rooms
+            .entry(client_id.clone())
+            .or_default()
+            .insert(lobby.id.clone());
+
+        let room = loop {
+            let room = self.open_room(lobby);
+            if room.add_client(client.clone()).await {
+                break room;
+            }
+            // The last client left while we were joining
+            self.forget_room(&room);
+        };
+
+        // A leave that ran in the meantime took the room out of the index,
+        // and may have been too early to find the client in it
+        let still_indexed = self
+            .client_rooms
+            .get(&client_id)
+            .is_some_and(|lobby_ids| lobby_ids.contains(&lobby.id));
+        if !still_indexed && room.remove_client(&client_id).await == Some(true) {
+            self.forget_room(&room);
+        }
+
+        Ok(())
+    }
+
+    async fn update_room_client(&self, lobby_id: &str, client: RoomClientInfo) -> AppResult<bool> {
+        match self.room(lobby_id) {
+            Some(room) => Ok(room.update_client(client).await),
+            None => Ok(false),
+        }
+    }
+
+    async fn leave_rooms(&self, client_id: &str) -> AppResult<Vec<LeftRoom>> {
+        let Some((_, lobby_ids)) = self.client_rooms.remove(client_id) else {
+            return Ok(Vec::new());
+        };
+
+        let mut left_rooms = Vec::new();
+        for lobby_id in lobby_ids {
+            let Some(room) = self.room(&lobby_id) else {
+                continue;
+            };
+            let Some(is_empty) = room.remove_client(client_id).await else {
+                continue;
+            };
+
+            if is_empty {
+                self.forget_room(&room);
+            }
+            left_rooms.push(LeftRoom {
+                niche_id: room.lobby().niche_id.clone(),
+                lobby_id,
+                is_empty,
+            });
+        }
+
+        Ok(left_rooms)
+    }
+
+    async fn close_room(&self, lobby_id: &str) -> AppResult<Vec<ClientId>> {
+        let Some(room) = self.room(lobby_id) else {
+            return Ok(Vec::new());
+        };
+
+        let client_ids = room.close().await;
+        self.forget_room(&room);
+
+        for client_id in client_ids.iter() {
+            if let Some(mut lobby_ids) = self.client_rooms.get_mut(client_id) {
+                lobby_ids.remove(lobby_id);
+            }
+            self.client_rooms
+                .remove_if(client_id, |_, lobby_ids| lobby_ids.is_empty());
+        }
+
+        Ok(client_ids)
+    }
+
+    async fn rooms(&self, niche_id: &str) -> AppResult<HashMap<LobbyId, RoomResource>> {
+        let lobby_ids: Vec<LobbyId> = self
+            .niche_rooms
+            .get(niche_id)
+            .map(|lobby_ids| lobby_ids.iter().cloned().collect())
+            .unwrap_or_default();
+
+        let mut channels = HashMap::new();
+        for lobby_id in lobby_ids {
+            let Some(room) = self.room(&lobby_id) else {
+                continue;
+            };
+            // A room that just closed has nobody left to show
+            let clients = room.clients().await;
+            if !clients.is_empty() {
+                channels.insert(lobby_id, RoomResource::from_clients(clients.iter()));
+            }
+        }
+
+        Ok(channels)
+    }
+
+    async fn room_counts(&self) -> AppResult<HashMap<NicheId, usize>> {
+        Ok(self
+            .niche_rooms
+            .iter()
+            .filter(|lobby_ids| !lobby_ids.is_empty())
+            .map(|lobby_ids| (lobby_ids.key().clone(), lobby_ids.len()))
+            .collect())
+    }
+
+    async fn room_clients(&self, lobby_id: &str) -> AppResult<Vec<RoomClientInfo>> {
+        match self.room(lobby_id) {
+            Some(room) => Ok(room.clients().await),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    async fn moderation(&self, lobby_id: &str, user_id: &str) -> AppResult<Moderation> {
+        Ok(self
+            .moderation
+            .get(lobby_id)
+            .and_then(|users| users.get(user_id).copied())
+            .unwrap_or_default())
+    }
+
+    async fn update_moderation(
+        &self,
+        lobby_id: &str,
+        user_id: &str,
+        server_muted: Option<bool>,
+        server_deafened: Option<bool>,
+    ) -> AppResult<()> {
+        let mut users = self.moderation.entry(lobby_id.to_string()).or_default();
+        let moderation = users.entry(user_id.to_string()).or_default();
+        if let Some(server_muted) = server_muted {
+            moderation.server_muted = server_muted;
+        }
+        if let Some(server_deafened) = server_deafened {
+            moderation.server_deafened = server_deafened;
+        }
+
+        Ok(())
+    }
+
+    async fn clear_moderation(&self, lobby_id: &str) -> AppResult<()> {
+        self.moderation.remove(lobby_id);
+
+        Ok(())
+    }
+
+    async fn heartbeat(&self) -> AppResult<Heartbeat> {
+        // Nothing outlives this instance that another one would reap
+        Ok(Heartbeat::default())
+    }
+
+    async fn publish(&self, envelope: Envelope) -> AppResult<()> {
+        // No receivers just means there is nobody to deliver to yet
+        let _ = self.sender.send(envelope);
+
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Envelope> {
+        self.sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{lobby, room_client, user};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn users_stay_online_until_their_last_client_leaves() {
+        let backend = MemoryBackend::new();
+        backend.add_client("c1", &user("u1")).await.unwrap();
+        backend.add_client("c2", &user("u1")).await.unwrap();
+        backend.add_client("c3", &user("u2")).await.unwrap();
+        let user_ids = ["u1".to_string(), "u2".to_string(), "u3".to_string()];
+
+        assert_eq!(
+            backend.online_user_ids(&user_ids).await.unwrap(),
+            ["u1", "u2"]
+        );
+        assert_eq!(
+            backend.client("c2").await.unwrap().map(|user| user.user_id),
+            Some("u1".to_string())
+        );
+
+        backend.remove_client("c1").await.unwrap();
+        backend.remove_client("c3").await.unwrap();
+        assert_eq!(backend.online_user_ids(&user_ids).await.unwrap(), ["u1"]);
+
+        backend.remove_client("c2").await.unwrap();
+        assert!(backend.online_user_ids(&user_ids).await.unwrap().is_empty());
+        assert!(backend.client("c2").await.unwrap().is_none());
+        assert!(backend.user_clients.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejoining_a_room_that_emptied_opens_a_new_one() {
+        let backend = MemoryBackend::new();
+        backend
+            .join_room(&lobby("l1"), room_client("c1"))
+            .await
+            .unwrap();
+        let first = backend.room("l1").unwrap();
+
+        let left = backend.leave_rooms("c1").await.unwrap();
+        assert_eq!(left.len(), 1);
+        assert!(left[0].is_empty);
+        assert!(backend.room("l1").is_none());
+
+        backend
+            .join_room(&lobby("l1"), room_client("c1"))
+            .await
+            .unwrap();
+        let second = backend.room("l1").unwrap();
+        assert!(!first.same_room(&second));
+        assert_eq!(backend.room_clients("l1").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn closing_a_room_forgets_its_clients() {
+        let backend = MemoryBackend::new();
+        backend
+            .join_room(&lobby("l1"), room_client("c1"))
+            .await
+            .unwrap();
+        backend
+            .join_room(&lobby("l2"), room_client("c1"))
+            .await
+            .unwrap();
+
+        assert_eq!(backend.close_room("l1").await.unwrap(), vec!["c1"]);
+        assert!(backend.rooms("n1").await.unwrap().contains_key("l2"));
+        assert!(!backend.rooms("n1").await.unwrap().contains_key("l1"));
+
+        let left = backend.leave_rooms("c1").await.unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].lobby_id, "l2");
+        assert_eq!(backend.room_counts().await.unwrap().get("n1"), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_join_and_leave_leave_nothing_behind() {
+        let backend = Arc::new(MemoryBackend::new());
+
+        for round in 0..200 {
+            let client_id = format!("c{}", round);
+            let joining = {
+                let backend = backend.clone();
+                let client_id = client_id.clone();
+                tokio::spawn(async move {
+                    backend
+                        .join_room(&lobby("l1"), room_client(&client_id))
+                        .await
+                })
+            };
+            let leaving = {
+                let backend = backend.clone();
+                let client_id = client_id.clone();
+                tokio::spawn(async move { backend.leave_rooms(&client_id).await })
+            };
+            joining.await.unwrap().unwrap();
+            leaving.await.unwrap().unwrap();
+
+            // Whichever came last, the room and the index agree
+            let in_room = backend
+                .room_clients("l1")
+                .await
+                .unwrap()
+                .iter()
+                .any(|room_client| room_client.client_id == client_id);
+            let indexed = backend
+                .client_rooms
+                .get(&client_id)
+                .is_some_and(|lobby_ids| lobby_ids.contains("l1"));
+            assert_eq!(in_room, indexed);
+
+            backend.leave_rooms(&client_id).await.unwrap();
+            assert!(backend.room_clients("l1").await.unwrap().is_empty());
+        }
+    }
+}
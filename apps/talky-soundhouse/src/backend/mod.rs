@@ -0,0 +1,196 @@
+mod memory;
+mod postgres;
+mod room;
+
+pub use memory::MemoryBackend;
+pub use postgres::PostgresBackend;
+
+use crate::error::AppResult;
+use crate::message::OutgoingMessage;
+use crate::state::{ClientInfo, RoomClientInfo, RoomResource, UserResource};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use talky_services::lobby::service::LobbyResource;
+use tokio::sync::broadcast;
+
+type ClientId = String;
+type LobbyId = String;
+type NicheId = String;
+
+/// How often an instance tells the backend that it is still alive.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Which connected clients a published message is meant for.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Audience {
+    All,
+    Client {
+        client_id: String,
+    },
+    Niche {
+        niche_id: String,
+        except_client_id: Option<String>,
+    },
+    Channel {
+        channel_id: String,
+        except_client_id: Option<String>,
+    },
+    /// Every client of the user.
+    User {
+        user_id: String,
+    },
+    /// Clients of users who are members of any of the niches, whichever
+    /// niche they have open.
+    NicheMembers {
+        niche_ids: Vec<String>,
+        except_user_id: Option<String>,
+    },
+}
+
+impl Audience {
+    pub fn includes(&self, client: &ClientInfo) -> bool {
+        match self {
+            Audience::All => true,
+            Audience::Client { client_id } => &client.id == client_id,
+            Audience::Niche {
+                niche_id,
+                except_client_id,
+            } => {
+                client.current_niche_id.as_ref() == Some(niche_id)
+                    && except_client_id.as_ref() != Some(&client.id)
+            }
+            Audience::Channel {
+                channel_id,
+                except_client_id,
+            } => {
+                client.current_channel_id.as_ref() == Some(channel_id)
+                    && except_client_id.as_ref() != Some(&client.id)
+            }
+            Audience::User { user_id } => &client.resource.user_id == user_id,
+            Audience::NicheMembers {
+                niche_ids,
+                except_user_id,
+            } => {
+                client
+                    .niche_ids
+                    .iter()
+                    .any(|niche_id| niche_ids.contains(niche_id))
+                    && except_user_id.as_ref() != Some(&client.resource.user_id)
+            }
+        }
+    }
+}
+
+/// A room a client was removed from.
+#[derive(Debug, Clone)]
+pub struct LeftRoom {
+    pub niche_id: String,
+    pub lobby_id: String,
+    /// Whether the client was the last one in the room.
+    pub is_empty: bool,
+}
+
+/// What moderators imposed on a user in a lobby. Kept apart from the room so
+/// that it outlasts the user leaving and joining again.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Moderation {
+    pub server_muted: bool,
+    pub server_deafened: bool,
+}
+
+/// What a heartbeat found besides this instance being alive.
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+    /// Whether this instance had been taken for dead, so that its clients and
+    /// room members are gone from the backend.
+    pub was_reaped: bool,
+    /// Users whose clients on dead instances were removed.
+    pub reaped_user_ids: Vec<String>,
+    /// Niches with rooms that lost members of dead instances.
+    pub reaped_niche_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    pub audience: Audience,
+    pub message: OutgoingMessage,
+}
+
+/// Shared view of who is connected, who is in which room, and a fanout
+/// channel that reaches every soundhouse instance.
+///
+/// Sockets themselves never leave the instance that accepted them; every
+/// instance subscribes to the fanout and delivers envelopes to its own
+/// clients.
+///
+/// Media is not shared: each instance forwards it through its own SFU, so a
+/// room is hosted by the instance of whoever joined it first and members on
+/// other instances would never hear each other. Backends refuse those joins
+/// until everyone on the hosting instance has left.
+#[async_trait]
+pub trait Backend: Send + Sync {
+    async fn add_client(&self, client_id: &str, user: &UserResource) -> AppResult<()>;
+
+    async fn remove_client(&self, client_id: &str) -> AppResult<()>;
+
+    async fn clients(&self) -> AppResult<HashMap<ClientId, UserResource>>;
+
+    async fn client(&self, client_id: &str) -> AppResult<Option<UserResource>>;
+
+    async fn has_client(&self, client_id: &str) -> AppResult<bool>;
+
+    /// Those of the users who have a client connected anywhere.
+    async fn online_user_ids(&self, user_ids: &[String]) -> AppResult<Vec<String>>;
+
+    /// Adds the client to the room, failing with
+    /// [`AppError::RoomOnOtherInstance`] if its members are on another
+    /// instance.
+    ///
+    /// [`AppError::RoomOnOtherInstance`]: crate::error::AppError::RoomOnOtherInstance
+    async fn join_room(&self, lobby: &LobbyResource, client: RoomClientInfo) -> AppResult<()>;
+
+    /// Replaces the client's entry in the room, returning whether it was
+    /// still in there.
+    async fn update_room_client(&self, lobby_id: &str, client: RoomClientInfo) -> AppResult<bool>;
+
+    /// Removes the client from every room it is in. Rooms left empty are
+    /// dropped.
+    async fn leave_rooms(&self, client_id: &str) -> AppResult<Vec<LeftRoom>>;
+
+    /// Drops the room with everyone in it and returns the removed clients.
+    async fn close_room(&self, lobby_id: &str) -> AppResult<Vec<ClientId>>;
+
+    async fn rooms(&self, niche_id: &str) -> AppResult<HashMap<LobbyId, RoomResource>>;
+
+    async fn room_clients(&self, lobby_id: &str) -> AppResult<Vec<RoomClientInfo>>;
+
+    /// How many rooms every niche with any has.
+    async fn room_counts(&self) -> AppResult<HashMap<NicheId, usize>>;
+
+    async fn moderation(&self, lobby_id: &str, user_id: &str) -> AppResult<Moderation>;
+
+    /// Changes what is imposed on the user in the lobby, keeping whatever is
+    /// passed as `None`.
+    async fn update_moderation(
+        &self,
+        lobby_id: &str,
+        user_id: &str,
+        server_muted: Option<bool>,
+        server_deafened: Option<bool>,
+    ) -> AppResult<()>;
+
+    /// Forgets what was imposed on anyone in the lobby.
+    async fn clear_moderation(&self, lobby_id: &str) -> AppResult<()>;
+
+    /// Marks this instance as alive and removes the clients and room members
+    /// of instances that stopped doing so. Called every
+    /// [`HEARTBEAT_INTERVAL`].
+    async fn heartbeat(&self) -> AppResult<Heartbeat>;
+
+    async fn publish(&self, envelope: Envelope) -> AppResult<()>;
+
+    fn subscribe(&self) -> broadcast::Receiver<Envelope>;
+}
@@ -0,0 +1,582 @@
+use super::{Backend, ClientId, Envelope, Heartbeat, LeftRoom, LobbyId, Moderation, NicheId};
+use crate::error::{AppError, AppResult};
+use crate::metrics::Metrics;
+use crate::state::{RoomClientInfo, RoomResource, UserResource};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use sqlx::types::Json;
+use std::collections::HashMap;
+use std::time::Duration;
+use talky_services::lobby::service::LobbyResource;
+use talky_services::DatabasePool;
+use tokio::sync::broadcast;
+use tokio::task::AbortHandle;
+
+const CHANNEL: &str = "soundhouse_fanout";
+
+// NOTIFY rejects payloads of 8000 bytes or more
+const MAX_NOTIFY_PAYLOAD: usize = 7500;
+
+// Instances that missed this many seconds of heartbeats are taken for dead
+const INSTANCE_TTL_SECS: i32 = 30;
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Notification {
+    Inline(Box<Envelope>),
+    Stored { outbox_id: i64 },
+}
+
+/// Shares presence through unlogged tables and fans messages out with
+/// `LISTEN`/`NOTIFY`, so any number of instances can serve the same niches.
+pub struct PostgresBackend {
+    connection: DatabasePool,
+    instance_id: String,
+    sender: broadcast::Sender<Envelope>,
+    listener: AbortHandle,
+    metrics: Metrics,
+}
+
+impl PostgresBackend {
+    pub async fn new(
+        connection: DatabasePool,
+        instance_id: String,
+        metrics: Metrics,
+    ) -> AppResult<Self> {
+        // Anything left under our id belongs to a previous run of this instance
+        sqlx::query("DELETE FROM soundhouse_clients WHERE instance_id = $1")
+            .bind(&instance_id)
+            .execute(&*connection)
+            .await?;
+        sqlx::query("DELETE FROM soundhouse_room_members WHERE instance_id = $1")
+            .bind(&instance_id)
+            .execute(&*connection)
+            .await?;
+        sqlx::query(
+            "INSERT INTO soundhouse_instances (instance_id) VALUES ($1)
+             ON CONFLICT (instance_id) DO UPDATE SET heartbeat_at = now()",
+        )
+        .bind(&instance_id)
+        .execute(&*connection)
+        .await?;
+
+        let mut listener = PgListener::connect_with(&connection).await?;
+        listener.listen(CHANNEL).await?;
+
+        let (sender, _) = broadcast::channel(1024);
+
+        let listener = tokio::spawn(Self::listen(listener, connection.clone(), sender.clone()));
+
+        Ok(Self {
+            connection,
+            instance_id,
+            sender,
+            listener: listener.abort_handle(),
+            metrics,
+        })
+    }
+
+    async fn listen(
+        mut listener: PgListener,
+        connection: DatabasePool,
+        sender: broadcast::Sender<Envelope>,
+    ) {
+        loop {
+            let notification = match listener.recv().await {
+                Ok(notification) => notification,
+                Err(e) => {
+                    tracing::error!("Soundhouse fanout listener failed: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            let envelope = match serde_json::from_str::<Notification>(notification.payload()) {
+                Ok(Notification::Inline(envelope)) => *envelope,
+                Ok(Notification::Stored { outbox_id }) => {
+                    match Self::load_outbox(&connection, outbox_id).await {
+                        Ok(envelope) => envelope,
+                        Err(e) => {
+                            tracing::error!("Failed to load outbox entry {}: {}", outbox_id, e);
+                            continue;
+                        }
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("Received malformed fanout payload: {}", e);
+                    continue;
+                }
+            };
+
+            let _ = sender.send(envelope);
+        }
+    }
+
+    async fn load_outbox(connection: &DatabasePool, outbox_id: i64) -> AppResult<Envelope> {
+        let (Json(envelope),): (Json<Envelope>,) =
+            sqlx::query_as("SELECT payload FROM soundhouse_outbox WHERE id = $1")
+                .bind(outbox_id)
+                .fetch_one(&**connection)
+                .await?;
+
+        Ok(envelope)
+    }
+
+    async fn store_outbox(&self, envelope: &Envelope) -> AppResult<i64> {
+        // Every instance reads an entry right after it is notified, so old
+        // entries can go
+        let query = sqlx::query(
+            "DELETE FROM soundhouse_outbox WHERE created_at < now() - interval '1 minute'",
+        );
+        self.metrics
+            .time_db("backend.store_outbox", query.execute(&*self.connection))
+            .await?;
+
+        let query =
+            sqlx::query_as("INSERT INTO soundhouse_outbox (payload) VALUES ($1) RETURNING id")
+                .bind(Json(envelope));
+
+        let (outbox_id,): (i64,) = self
+            .metrics
+            .time_db("backend.store_outbox", query.fetch_one(&*self.connection))
+            .await?;
+
+        Ok(outbox_id)
+    }
+}
+
+impl Drop for PostgresBackend {
+    fn drop(&mut self) {
+        // Gives the listening connection back
+        self.listener.abort();
+    }
+}
+
+#[async_trait]
+impl Backend for PostgresBackend {
+    async fn add_client(&self, client_id: &str, user: &UserResource) -> AppResult<()> {
+        let query = sqlx::query(
+            "INSERT INTO soundhouse_clients (client_id, instance_id, data) VALUES ($1, $2, $3)
+             ON CONFLICT (client_id) DO UPDATE SET instance_id = $2, data = $3",
+        )
+        .bind(client_id)
+        .bind(&self.instance_id)
+        .bind(Json(user));
+        self.metrics
+            .time_db("backend.add_client", query.execute(&*self.connection))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn remove_client(&self, client_id: &str) -> AppResult<()> {
+        let query =
+            sqlx::query("DELETE FROM soundhouse_clients WHERE client_id = $1").bind(client_id);
+        self.metrics
+            .time_db("backend.remove_client", query.execute(&*self.connection))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn clients(&self) -> AppResult<HashMap<ClientId, UserResource>> {
+        let query = sqlx::query_as("SELECT client_id, data FROM soundhouse_clients");
+        let rows: Vec<(String, Json<UserResource>)> = self
+            .metrics
+            .time_db("backend.clients", query.fetch_all(&*self.connection))
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(client_id, Json(user))| (client_id, user))
+            .collect())
+    }
+
+    async fn client(&self, client_id: &str) -> AppResult<Option<UserResource>> {
+        let query = sqlx::query_as("SELECT data FROM soundhouse_clients WHERE client_id = $1")
+            .bind(client_id);
+        let row: Option<(Json<UserResource>,)> = self
+            .metrics
+            .time_db("backend.client", query.fetch_optional(&*self.connection))
+            .await?;
+
+        Ok(row.map(|(Json(user),)| user))
+    }
+
+    async fn has_client(&self, client_id: &str) -> AppResult<bool> {
+        let query =
+            sqlx::query_as("SELECT EXISTS(SELECT 1 FROM soundhouse_clients WHERE client_id = $1)")
+                .bind(client_id);
+        let (exists,): (bool,) = self
+            .metrics
+            .time_db("backend.has_client", query.fetch_one(&*self.connection))
+            .await?;
+
+        Ok(exists)
+    }
+
+    async fn online_user_ids(&self, user_ids: &[String]) -> AppResult<Vec<String>> {
+        let query = sqlx::query_as(
+            "SELECT DISTINCT data->>'user_id' FROM soundhouse_clients
+             WHERE data->>'user_id' = ANY($1)",
+        )
+        .bind(user_ids);
+        let rows: Vec<(String,)> = self
+            .metrics
+            .time_db(
+                "backend.online_user_ids",
+                query.fetch_all(&*self.connection),
+            )
+            .await?;
+
+        Ok(rows.into_iter().map(|(user_id,)| user_id).collect())
+    }
+
+    async fn join_room(&self, lobby: &LobbyResource, client: RoomClientInfo) -> AppResult<()> {
+        let joined = async {
+            let mut transaction = self.connection.begin().await?;
+            // Serializes joins of the lobby, so that two instances can't both
+            // find it empty and each start hosting it
+            sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+                .bind(&lobby.id)
+                .execute(&mut *transaction)
+                .await?;
+            let result = sqlx::query(
+                "INSERT INTO soundhouse_room_members (client_id, niche_id, lobby_id, instance_id, data)
+                 SELECT $1, $2, $3, $4, $5
+                 WHERE NOT EXISTS(
+                    SELECT 1 FROM soundhouse_room_members
+                    WHERE lobby_id = $3 AND instance_id <> $4
+                 )
+                 ON CONFLICT (client_id, lobby_id) DO UPDATE SET instance_id = $4, data = $5",
+            )
+            .bind(&client.client_id)
+            .bind(&lobby.niche_id)
+            .bind(&lobby.id)
+            .bind(&self.instance_id)
+            .bind(Json(&client))
+            .execute(&mut *transaction)
+            .await?;
+            transaction.commit().await?;
+
+            Ok::<_, sqlx::Error>(result.rows_affected() > 0)
+        };
+        if !self.metrics.time_db("backend.join_room", joined).await? {
+            return Err(AppError::RoomOnOtherInstance(lobby.id.clone()));
+        }
+
+        Ok(())
+    }
+
+    async fn update_room_client(&self, lobby_id: &str, client: RoomClientInfo) -> AppResult<bool> {
+        let query = sqlx::query(
+            "UPDATE soundhouse_room_members SET data = $3 WHERE client_id = $1 AND lobby_id = $2",
+        )
+        .bind(&client.client_id)
+        .bind(lobby_id)
+        .bind(Json(&client));
+        let result = self
+            .metrics
+            .time_db(
+                "backend.update_room_client",
+                query.execute(&*self.connection),
+            )
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn leave_rooms(&self, client_id: &str) -> AppResult<Vec<LeftRoom>> {
+        // Rooms only exist through their members, so an empty room is gone
+        // as soon as its last row is
+        let query = sqlx::query_as(
+            "WITH removed AS (
+                DELETE FROM soundhouse_room_members WHERE client_id = $1
+                RETURNING niche_id, lobby_id
+            )
+            SELECT removed.niche_id, removed.lobby_id, NOT EXISTS(
+                SELECT 1 FROM soundhouse_room_members
+                WHERE soundhouse_room_members.lobby_id = removed.lobby_id
+                AND soundhouse_room_members.client_id <> $1
+            )
+            FROM removed",
+        )
+        .bind(client_id);
+        let rows: Vec<(String, String, bool)> = self
+            .metrics
+            .time_db("backend.leave_rooms", query.fetch_all(&*self.connection))
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(niche_id, lobby_id, is_empty)| LeftRoom {
+                niche_id,
+                lobby_id,
+                is_empty,
+            })
+            .collect())
+    }
+
+    async fn close_room(&self, lobby_id: &str) -> AppResult<Vec<ClientId>> {
+        let query = sqlx::query_as(
+            "DELETE FROM soundhouse_room_members WHERE lobby_id = $1 RETURNING client_id",
+        )
+        .bind(lobby_id);
+        let rows: Vec<(String,)> = self
+            .metrics
+            .time_db("backend.close_room", query.fetch_all(&*self.connection))
+            .await?;
+
+        Ok(rows.into_iter().map(|(client_id,)| client_id).collect())
+    }
+
+    async fn rooms(&self, niche_id: &str) -> AppResult<HashMap<LobbyId, RoomResource>> {
+        let query = sqlx::query_as(
+            "SELECT lobby_id, data FROM soundhouse_room_members WHERE niche_id = $1",
+        )
+        .bind(niche_id);
+        let rows: Vec<(String, Json<RoomClientInfo>)> = self
+            .metrics
+            .time_db("backend.rooms", query.fetch_all(&*self.connection))
+            .await?;
+
+        let mut members: HashMap<LobbyId, Vec<RoomClientInfo>> = HashMap::new();
+        for (lobby_id, Json(client)) in rows {
+            members.entry(lobby_id).or_default().push(client);
+        }
+
+        Ok(members
+            .into_iter()
+            .map(|(lobby_id, clients)| (lobby_id, RoomResource::from_clients(clients.iter())))
+            .collect())
+    }
+
+    async fn room_clients(&self, lobby_id: &str) -> AppResult<Vec<RoomClientInfo>> {
+        let query = sqlx::query_as("SELECT data FROM soundhouse_room_members WHERE lobby_id = $1")
+            .bind(lobby_id);
+        let rows: Vec<(Json<RoomClientInfo>,)> = self
+            .metrics
+            .time_db("backend.room_clients", query.fetch_all(&*self.connection))
+            .await?;
+
+        Ok(rows.into_iter().map(|(Json(client),)| client).collect())
+    }
+
+    async fn room_counts(&self) -> AppResult<HashMap<NicheId, usize>> {
+        let query = sqlx::query_as(
+            "SELECT niche_id, COUNT(DISTINCT lobby_id) FROM soundhouse_room_members GROUP BY niche_id",
+        );
+        let rows: Vec<(String, i64)> = self
+            .metrics
+            .time_db("backend.room_counts", query.fetch_all(&*self.connection))
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(niche_id, count)| (niche_id, count as usize))
+            .collect())
+    }
+
+    async fn moderation(&self, lobby_id: &str, user_id: &str) -> AppResult<Moderation> {
+        let query = sqlx::query_as(
+            "SELECT server_muted, server_deafened FROM soundhouse_moderation
+             WHERE lobby_id = $1 AND user_id = $2",
+        )
+        .bind(lobby_id)
+        .bind(user_id);
+        let row: Option<(bool, bool)> = self
+            .metrics
+            .time_db(
+                "backend.moderation",
+                query.fetch_optional(&*self.connection),
+            )
+            .await?;
+
+        Ok(row
+            .map(|(server_muted, server_deafened)| Moderation {
+                server_muted,
+                server_deafened,
+            })
+            .unwrap_or_default())
+    }
+
+    async fn update_moderation(
+        &self,
+        lobby_id: &str,
+        user_id: &str,
+        server_muted: Option<bool>,
+        server_deafened: Option<bool>,
+    ) -> AppResult<()> {
+        let query = sqlx::query(
+            "INSERT INTO soundhouse_moderation (lobby_id, user_id, server_muted, server_deafened)
+             VALUES ($1, $2, COALESCE($3, false), COALESCE($4, false))
+             ON CONFLICT (lobby_id, user_id) DO UPDATE SET
+                server_muted = COALESCE($3, soundhouse_moderation.server_muted),
+                server_deafened = COALESCE($4, soundhouse_moderation.server_deafened)",
+        )
+        .bind(lobby_id)
+        .bind(user_id)
+        .bind(server_muted)
+        .bind(server_deafened);
+        self.metrics
+            .time_db(
+                "backend.update_moderation",
+                query.execute(&*self.connection),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn clear_moderation(&self, lobby_id: &str) -> AppResult<()> {
+        let query =
+            sqlx::query("DELETE FROM soundhouse_moderation WHERE lobby_id = $1").bind(lobby_id);
+        self.metrics
+            .time_db("backend.clear_moderation", query.execute(&*self.connection))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn heartbeat(&self) -> AppResult<Heartbeat> {
+        // The row is only missing if another instance took this one for dead
+        let query = sqlx::query_as(
+            "INSERT INTO soundhouse_instances (instance_id) VALUES ($1)
+             ON CONFLICT (instance_id) DO UPDATE SET heartbeat_at = now()
+             RETURNING xmax = 0",
+        )
+        .bind(&self.instance_id);
+        let (was_reaped,): (bool,) = self
+            .metrics
+            .time_db("backend.heartbeat", query.fetch_one(&*self.connection))
+            .await?;
+
+        let query = sqlx::query_as(
+            "DELETE FROM soundhouse_instances
+             WHERE heartbeat_at < now() - make_interval(secs => $1)
+             RETURNING instance_id",
+        )
+        .bind(INSTANCE_TTL_SECS);
+        let dead: Vec<(String,)> = self
+            .metrics
+            .time_db("backend.heartbeat", query.fetch_all(&*self.connection))
+            .await?;
+        for (instance_id,) in dead {
+            tracing::warn!("Reaping clients of dead instance {}", instance_id);
+        }
+
+        // Rows of instances that never had a heartbeat row are reaped as well
+        let query = sqlx::query_as(
+            "WITH reaped AS (
+                DELETE FROM soundhouse_clients WHERE instance_id NOT IN (
+                    SELECT instance_id FROM soundhouse_instances
+                )
+                RETURNING data->>'user_id' AS user_id
+            )
+            SELECT DISTINCT user_id FROM reaped",
+        );
+        let reaped_user_ids: Vec<(String,)> = self
+            .metrics
+            .time_db("backend.heartbeat", query.fetch_all(&*self.connection))
+            .await?;
+
+        let query = sqlx::query_as(
+            "WITH reaped AS (
+                DELETE FROM soundhouse_room_members WHERE instance_id NOT IN (
+                    SELECT instance_id FROM soundhouse_instances
+                )
+                RETURNING niche_id
+            )
+            SELECT DISTINCT niche_id FROM reaped",
+        );
+        let reaped_niche_ids: Vec<(String,)> = self
+            .metrics
+            .time_db("backend.heartbeat", query.fetch_all(&*self.connection))
+            .await?;
+
+        Ok(Heartbeat {
+            was_reaped,
+            reaped_user_ids: reaped_user_ids
+                .into_iter()
+                .map(|(user_id,)| user_id)
+                .collect(),
+            reaped_niche_ids: reaped_niche_ids
+                .into_iter()
+                .map(|(niche_id,)| niche_id)
+                .collect(),
+        })
+    }
+
+    async fn publish(&self, envelope: Envelope) -> AppResult<()> {
+        let mut payload = serde_json::to_string(&Notification::Inline(Box::new(envelope.clone())))?;
+        if payload.len() > MAX_NOTIFY_PAYLOAD {
+            let outbox_id = self.store_outbox(&envelope).await?;
+            payload = serde_json::to_string(&Notification::Stored { outbox_id })?;
+        }
+
+        let query = sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(CHANNEL)
+            .bind(payload);
+
+        self.metrics
+            .time_db("backend.publish", query.execute(&*self.connection))
+            .await?;
+
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Envelope> {
+        self.sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::AppError;
+    use crate::test_support::{database, lobby, room_client};
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+
+    async fn backend(connection: &DatabasePool, instance_id: &str) -> PostgresBackend {
+        PostgresBackend::new(
+            connection.clone(),
+            instance_id.to_string(),
+            Metrics::new().unwrap(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn rooms_are_joined_on_the_instance_hosting_them(
+        pool_options: PgPoolOptions,
+        connect_options: PgConnectOptions,
+    ) {
+        let connection = database(pool_options, connect_options).await;
+        let first = backend(&connection, "i1").await;
+        let second = backend(&connection, "i2").await;
+
+        first
+            .join_room(&lobby("l1"), room_client("c1"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            second.join_room(&lobby("l1"), room_client("c2")).await,
+            Err(AppError::RoomOnOtherInstance(lobby_id)) if lobby_id == "l1"
+        ));
+        first
+            .join_room(&lobby("l1"), room_client("c3"))
+            .await
+            .unwrap();
+        assert_eq!(first.room_clients("l1").await.unwrap().len(), 2);
+
+        first.leave_rooms("c1").await.unwrap();
+        first.leave_rooms("c3").await.unwrap();
+        second
+            .join_room(&lobby("l1"), room_client("c2"))
+            .await
+            .unwrap();
+        assert_eq!(second.room_clients("l1").await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,193 @@
+use super::ClientId;
+use crate::state::RoomClientInfo;
+use std::collections::HashMap;
+use talky_services::lobby::service::LobbyResource;
+use tokio::sync::{mpsc, oneshot};
+
+const MAILBOX_SIZE: usize = 64;
+
+enum RoomCommand {
+    Add {
+        client: RoomClientInfo,
+        reply: oneshot::Sender<bool>,
+    },
+    Update {
+        client: RoomClientInfo,
+        reply: oneshot::Sender<bool>,
+    },
+    Remove {
+        client_id: ClientId,
+        reply: oneshot::Sender<Option<bool>>,
+    },
+    Clients {
+        reply: oneshot::Sender<Vec<RoomClientInfo>>,
+    },
+    Close {
+        reply: oneshot::Sender<Vec<ClientId>>,
+    },
+}
+
+/// A room is a task that owns its members and works through its mailbox one
+/// command at a time, so nothing about a room is ever locked.
+///
+/// A room closes for good once its last client leaves or it is closed
+/// explicitly. Handles to a closed room refuse new clients and callers
+/// spawn a fresh room instead.
+#[derive(Clone, Debug)]
+pub struct RoomHandle {
+    lobby: LobbyResource,
+    sender: mpsc::Sender<RoomCommand>,
+}
+
+impl RoomHandle {
+    pub fn spawn(lobby: LobbyResource) -> Self {
+        let (sender, receiver) = mpsc::channel(MAILBOX_SIZE);
+        tokio::spawn(run(receiver));
+
+        Self { lobby, sender }
+    }
+
+    pub fn lobby(&self) -> &LobbyResource {
+        &self.lobby
+    }
+
+    /// Whether both handles point at the same room task.
+    pub fn same_room(&self, other: &RoomHandle) -> bool {
+        self.sender.same_channel(&other.sender)
+    }
+
+    /// Returns false when the room has closed in the meantime.
+    pub async fn add_client(&self, client: RoomClientInfo) -> bool {
+        self.ask(|reply| RoomCommand::Add { client, reply })
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Returns whether the client was in this room.
+    pub async fn update_client(&self, client: RoomClientInfo) -> bool {
+        self.ask(|reply| RoomCommand::Update { client, reply })
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Returns whether the room is empty now, or `None` when the client was
+    /// not in it.
+    pub async fn remove_client(&self, client_id: &str) -> Option<bool> {
+        let client_id = client_id.to_string();
+        self.ask(|reply| RoomCommand::Remove { client_id, reply })
+            .await
+            .flatten()
+    }
+
+    pub async fn clients(&self) -> Vec<RoomClientInfo> {
+        self.ask(|reply| RoomCommand::Clients { reply })
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Closes the room and returns who was still in it.
+    pub async fn close(&self) -> Vec<ClientId> {
+        self.ask(|reply| RoomCommand::Close { reply })
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Sends a command and waits for the answer. `None` means the room task
+    /// is gone.
+    async fn ask<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> RoomCommand) -> Option<T> {
+        let (reply, answer) = oneshot::channel();
+        self.sender.send(command(reply)).await.ok()?;
+        answer.await.ok()
+    }
+}
+
+async fn run(mut receiver: mpsc::Receiver<RoomCommand>) {
+    let mut clients: HashMap<ClientId, RoomClientInfo> = HashMap::new();
+    let mut closed = false;
+
+    // A dropped reply only means the caller stopped waiting
+    while let Some(command) = receiver.recv().await {
+        match command {
+            RoomCommand::Add { client, reply } => {
+                if !closed {
+                    clients.insert(client.client_id.clone(), client);
+                }
+                let _ = reply.send(!closed);
+            }
+            RoomCommand::Update { client, reply } => {
+                let updated = match clients.get_mut(&client.client_id) {
+                    Some(existing) => {
+                        *existing = client;
+                        true
+                    }
+                    None => false,
+                };
+                let _ = reply.send(updated);
+            }
+            RoomCommand::Remove { client_id, reply } => {
+                let removed = clients.remove(&client_id).is_some();
+                if removed && clients.is_empty() {
+                    closed = true;
+                    receiver.close();
+                }
+                let _ = reply.send(removed.then_some(clients.is_empty()));
+            }
+            RoomCommand::Clients { reply } => {
+                let _ = reply.send(clients.values().cloned().collect());
+            }
+            RoomCommand::Close { reply } => {
+                closed = true;
+                receiver.close();
+                let _ = reply.send(clients.drain().map(|(client_id, _)| client_id).collect());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{lobby, room_client};
+
+    fn room() -> RoomHandle {
+        RoomHandle::spawn(lobby("l1"))
+    }
+
+    #[tokio::test]
+    async fn closes_once_the_last_client_leaves() {
+        let room = room();
+        assert!(room.add_client(room_client("c1")).await);
+        assert!(room.add_client(room_client("c2")).await);
+
+        assert_eq!(room.remove_client("c1").await, Some(false));
+        assert_eq!(room.remove_client("c1").await, None);
+        assert_eq!(room.remove_client("c2").await, Some(true));
+
+        assert!(!room.add_client(room_client("c1")).await);
+        assert!(room.clients().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn closing_hands_back_the_clients_and_refuses_new_ones() {
+        let room = room();
+        room.add_client(room_client("c1")).await;
+
+        assert_eq!(room.close().await, vec!["c1"]);
+        assert!(!room.add_client(room_client("c2")).await);
+        assert!(!room.update_client(room_client("c1")).await);
+        assert_eq!(room.remove_client("c1").await, None);
+        assert!(room.close().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_updates_clients_in_the_room() {
+        let room = room();
+        room.add_client(room_client("c1")).await;
+
+        let mut muted = room_client("c1");
+        muted.self_muted = true;
+        assert!(room.update_client(muted).await);
+        assert!(!room.update_client(room_client("c2")).await);
+        assert!(room.clients().await[0].self_muted);
+    }
+}
@@ -1,21 +1,36 @@
 use lib::{
+    ice::{IceConfig, IceServer, IceTransportPolicy},
     message::{ClientInfoMsg, IncomingMessage, OutgoingMessage},
+    protocol::{RateLimit, ServerLimits},
+    role::Role,
     state::{RoomResource, UserResource, UserRoomResource},
 };
 use serde_json::Value;
-use talky_services::message::service::MessageResource;
+use talky_services::conversation::service::DirectMessageResource;
+use talky_services::message::service::{MessageResource, ReactionResource};
+use talky_services::user::service::{UserProfileResource, UserStatus};
 
 fn main() {
     std::fs::write(
         "./types.d.ts",
         format!(
-            "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+            "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+            specta_typescript::export::<ReactionResource>(&Default::default()).unwrap(),
             specta_typescript::export::<MessageResource>(&Default::default()).unwrap(),
+            specta_typescript::export::<DirectMessageResource>(&Default::default()).unwrap(),
             specta_typescript::export::<UserResource>(&Default::default()).unwrap(),
+            specta_typescript::export::<Role>(&Default::default()).unwrap(),
             specta_typescript::export::<UserRoomResource>(&Default::default()).unwrap(),
             specta_typescript::export::<ClientInfoMsg>(&Default::default()).unwrap(),
             specta_typescript::export::<Value>(&Default::default()).unwrap(),
             specta_typescript::export::<RoomResource>(&Default::default()).unwrap(),
+            specta_typescript::export::<RateLimit>(&Default::default()).unwrap(),
+            specta_typescript::export::<ServerLimits>(&Default::default()).unwrap(),
+            specta_typescript::export::<IceServer>(&Default::default()).unwrap(),
+            specta_typescript::export::<IceTransportPolicy>(&Default::default()).unwrap(),
+            specta_typescript::export::<IceConfig>(&Default::default()).unwrap(),
+            specta_typescript::export::<UserStatus>(&Default::default()).unwrap(),
+            specta_typescript::export::<UserProfileResource>(&Default::default()).unwrap(),
             specta_typescript::export::<IncomingMessage>(&Default::default()).unwrap(),
             specta_typescript::export::<OutgoingMessage>(&Default::default()).unwrap()
         ),
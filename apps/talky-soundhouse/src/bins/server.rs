@@ -11,7 +11,7 @@ async fn main() -> AppResult<()> {
     tracing::info!("Configuration loaded successfully.");
     tracing::debug!("Server Address: {}", config.server_addr);
 
-    let app_state = AppState::new(&config.database_url).await;
+    let app_state = AppState::new(&config).await?;
     tracing::info!("Application state initialized.");
 
     let routes = build_routes(app_state.clone());
@@ -38,6 +38,9 @@ async fn main() -> AppResult<()> {
         }
     }
 
+    // Clients are told to leave while warp still serves them
+    app_state.drain().await;
+
     let _ = tx.send(());
     tracing::info!("Shutdown signal sent. Server exiting.");
 
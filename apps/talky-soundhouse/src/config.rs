@@ -1,11 +1,77 @@
 use crate::error::{AppError, AppResult};
+use crate::ice::IceTransportPolicy;
 use std::env;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Presence and fanout stay inside this process.
+    Memory,
+    /// Presence lives in Postgres and fanout goes over `LISTEN`/`NOTIFY`.
+    Postgres,
+}
+
+/// A token bucket: `burst` messages at once, refilled at `per_second`.
+#[derive(Clone, Copy, Debug)]
+pub struct BucketConfig {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    pub chat: BucketConfig,
+    pub signaling: BucketConfig,
+    pub control: BucketConfig,
+    /// How many refused messages a client may send within
+    /// `violation_window` before it is disconnected.
+    pub max_violations: u32,
+    pub violation_window: Duration,
+}
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub server_addr: SocketAddr,
     pub database_url: String,
+    pub backend: BackendKind,
+    pub instance_id: String,
+    /// How often the server pings each client.
+    pub heartbeat_interval: Duration,
+    /// How long a client may stay silent before it is disconnected.
+    pub heartbeat_timeout: Duration,
+    /// How long a dropped client keeps its session for resumption.
+    pub resume_grace: Duration,
+    /// How often expired temporary lobbies are looked for.
+    pub lobby_reaper_interval: Duration,
+    /// How long a client counts as typing after its last notice.
+    pub typing_timeout: Duration,
+    /// How often a typing client is announced again at most.
+    pub typing_throttle: Duration,
+    pub rate_limits: RateLimitConfig,
+    /// How long a shutdown waits for clients to leave on their own.
+    pub drain_deadline: Duration,
+    /// How long clients are told to wait before reconnecting on shutdown.
+    pub reconnect_delay: Duration,
+    /// Bearer token for the admin API, which is disabled without one.
+    pub admin_token: Option<String>,
+    /// How many messages may wait to be written to a client before it is
+    /// disconnected as too slow.
+    pub outbound_queue_size: usize,
+    /// The `static-auth-secret` shared with coturn. TURN credentials are only
+    /// handed out with one.
+    pub turn_secret: Option<String>,
+    /// How long handed out TURN credentials stay valid.
+    pub turn_credential_ttl: Duration,
+    /// STUN servers handed to clients.
+    pub stun_urls: Vec<String>,
+    /// TURN servers handed to clients, only together with credentials.
+    pub turn_urls: Vec<String>,
+    pub ice_transport_policy: IceTransportPolicy,
+    /// Where lobby recordings are written, one directory per recording.
+    pub recordings_dir: PathBuf,
 }
 
 impl Config {
@@ -19,9 +85,181 @@ impl Config {
         let database_url =
             env::var("DATABASE_URL").map_err(|e| AppError::Config(env::VarError::NotPresent))?;
 
+        let backend = match env::var("SOUNDHOUSE_BACKEND").as_deref() {
+            Ok("memory") | Err(_) => BackendKind::Memory,
+            Ok("postgres") => BackendKind::Postgres,
+            Ok(other) => {
+                return Err(AppError::InvalidConfig(format!(
+                    "Unknown SOUNDHOUSE_BACKEND '{}'",
+                    other
+                )))
+            }
+        };
+
+        let instance_id =
+            env::var("SOUNDHOUSE_INSTANCE_ID").unwrap_or_else(|_| ulid::Ulid::new().to_string());
+
+        let heartbeat_interval = secs_from_env("SOUNDHOUSE_HEARTBEAT_INTERVAL_SECS", 15)?;
+        let heartbeat_timeout = secs_from_env("SOUNDHOUSE_HEARTBEAT_TIMEOUT_SECS", 45)?;
+        if heartbeat_timeout <= heartbeat_interval {
+            return Err(AppError::InvalidConfig(
+                "SOUNDHOUSE_HEARTBEAT_TIMEOUT_SECS must be greater than the interval".to_string(),
+            ));
+        }
+
+        let resume_grace = secs_from_env("SOUNDHOUSE_RESUME_GRACE_SECS", 30)?;
+
+        let lobby_reaper_interval = secs_from_env("SOUNDHOUSE_LOBBY_REAPER_INTERVAL_SECS", 30)?;
+
+        let typing_timeout = secs_from_env("SOUNDHOUSE_TYPING_TIMEOUT_SECS", 8)?;
+        let typing_throttle = secs_from_env("SOUNDHOUSE_TYPING_THROTTLE_SECS", 3)?;
+        if typing_timeout <= typing_throttle {
+            return Err(AppError::InvalidConfig(
+                "SOUNDHOUSE_TYPING_TIMEOUT_SECS must be greater than the throttle".to_string(),
+            ));
+        }
+
+        let rate_limits = RateLimitConfig {
+            chat: bucket_from_env("CHAT", 2.0, 10.0)?,
+            signaling: bucket_from_env("SIGNALING", 50.0, 200.0)?,
+            control: bucket_from_env("CONTROL", 10.0, 30.0)?,
+            max_violations: number_from_env("SOUNDHOUSE_RATE_LIMIT_MAX_VIOLATIONS", 20)?,
+            violation_window: secs_from_env("SOUNDHOUSE_RATE_LIMIT_VIOLATION_WINDOW_SECS", 60)?,
+        };
+        if rate_limits.max_violations == 0 || rate_limits.violation_window.is_zero() {
+            return Err(AppError::InvalidConfig(
+                "Rate limit violations need a positive maximum and window".to_string(),
+            ));
+        }
+
+        let drain_deadline = secs_from_env("SOUNDHOUSE_DRAIN_DEADLINE_SECS", 30)?;
+        let reconnect_delay = secs_from_env("SOUNDHOUSE_RECONNECT_DELAY_SECS", 5)?;
+
+        let admin_token = env::var("SOUNDHOUSE_ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty());
+
+        let outbound_queue_size = number_from_env("SOUNDHOUSE_OUTBOUND_QUEUE_SIZE", 256)?;
+        if outbound_queue_size == 0 {
+            return Err(AppError::InvalidConfig(
+                "SOUNDHOUSE_OUTBOUND_QUEUE_SIZE must be positive".to_string(),
+            ));
+        }
+
+        let turn_secret = env::var("SOUNDHOUSE_TURN_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty());
+        let turn_credential_ttl = secs_from_env("SOUNDHOUSE_TURN_CREDENTIAL_TTL_SECS", 86400)?;
+        if turn_credential_ttl.is_zero() {
+            return Err(AppError::InvalidConfig(
+                "SOUNDHOUSE_TURN_CREDENTIAL_TTL_SECS must be positive".to_string(),
+            ));
+        }
+
+        let stun_urls = urls_from_env("SOUNDHOUSE_STUN_URLS", &["stun:server.loc:31899"]);
+        let turn_urls = urls_from_env(
+            "SOUNDHOUSE_TURN_URLS",
+            &[
+                "turn:server.loc:30665?transport=udp",
+                "turn:server.loc:31953?transport=tcp",
+            ],
+        );
+        let ice_transport_policy = match env::var("SOUNDHOUSE_ICE_TRANSPORT_POLICY").as_deref() {
+            Ok("all") | Err(_) => IceTransportPolicy::All,
+            Ok("relay") => IceTransportPolicy::Relay,
+            Ok(other) => {
+                return Err(AppError::InvalidConfig(format!(
+                    "Unknown SOUNDHOUSE_ICE_TRANSPORT_POLICY '{}'",
+                    other
+                )))
+            }
+        };
+        if ice_transport_policy == IceTransportPolicy::Relay
+            && (turn_secret.is_none() || turn_urls.is_empty())
+        {
+            return Err(AppError::InvalidConfig(
+                "A relay-only ICE transport policy needs SOUNDHOUSE_TURN_URLS and SOUNDHOUSE_TURN_SECRET"
+                    .to_string(),
+            ));
+        }
+
+        let recordings_dir = env::var("SOUNDHOUSE_RECORDINGS_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("recordings"));
+
         Ok(Config {
             server_addr,
             database_url,
+            backend,
+            instance_id,
+            heartbeat_interval,
+            heartbeat_timeout,
+            resume_grace,
+            lobby_reaper_interval,
+            typing_timeout,
+            typing_throttle,
+            rate_limits,
+            drain_deadline,
+            reconnect_delay,
+            admin_token,
+            outbound_queue_size,
+            turn_secret,
+            turn_credential_ttl,
+            stun_urls,
+            turn_urls,
+            ice_transport_policy,
+            recordings_dir,
         })
     }
 }
+
+/// Reads `SOUNDHOUSE_<NAME>_RATE` and `SOUNDHOUSE_<NAME>_BURST`.
+fn bucket_from_env(name: &str, per_second: f64, burst: f64) -> AppResult<BucketConfig> {
+    let rate_name = format!("SOUNDHOUSE_{}_RATE", name);
+    let burst_name = format!("SOUNDHOUSE_{}_BURST", name);
+    let bucket = BucketConfig {
+        per_second: number_from_env(&rate_name, per_second)?,
+        burst: number_from_env(&burst_name, burst)?,
+    };
+
+    if bucket.per_second <= 0.0 || bucket.burst < 1.0 {
+        return Err(AppError::InvalidConfig(format!(
+            "{} must be positive and {} at least 1",
+            rate_name, burst_name
+        )));
+    }
+
+    Ok(bucket)
+}
+
+/// Reads a comma separated list, where an empty value means no entries.
+fn urls_from_env(name: &str, default: &[&str]) -> Vec<String> {
+    match env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Err(_) => default.iter().map(|url| url.to_string()).collect(),
+    }
+}
+
+fn number_from_env<T: FromStr>(name: &str, default: T) -> AppResult<T> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse::<T>()
+            .map_err(|_| AppError::InvalidConfig(format!("{} must be a number", name))),
+        Err(_) => Ok(default),
+    }
+}
+
+fn secs_from_env(name: &str, default: u64) -> AppResult<Duration> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse::<u64>()
+            .map(Duration::from_secs)
+            .map_err(|_| AppError::InvalidConfig(format!("{} must be a number of seconds", name))),
+        Err(_) => Ok(Duration::from_secs(default)),
+    }
+}
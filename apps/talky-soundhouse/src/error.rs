@@ -1,3 +1,4 @@
+use crate::rate_limit::MessageBudget;
 use talky_services::error::ServicesError;
 use thiserror::Error;
 use warp::ws::Message;
@@ -17,21 +18,99 @@ pub enum AppError {
     #[error("JSON serialization/deserialization error: {0}")]
     Json(#[from] serde_json::Error),
 
+    #[error("MessagePack encoding error: {0}")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+
+    #[error("MessagePack decoding error: {0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+
     #[error("Client sent invalid message format")]
     InvalidMessageFormat,
 
     #[error("Missing or invalid field in message: {0}")]
     MissingField(String),
 
+    #[error("Field {0} is longer than {1} characters")]
+    TooLong(String, usize),
+
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("Channel {0} does not exist")]
+    ChannelNotFound(String),
+
+    #[error("Message {0} does not exist")]
+    MessageNotFound(String),
+
+    #[error("Client {0} is not connected")]
+    ClientNotFound(String),
+
+    #[error("Room {0} does not exist")]
+    RoomNotFound(String),
+
+    #[error("Missing or invalid admin token")]
+    Unauthorized,
+
+    #[error("Disconnected by an administrator")]
+    ForceDisconnected,
+
+    #[error("Server is shutting down")]
+    ShuttingDown,
+
+    #[error("Client {0} is not in room {1}")]
+    NotInRoom(String, String),
+
     #[error("Client send error")]
     ClientSendError,
 
     #[error("Client disconnected unexpectedly")]
     ClientDisconnected,
 
+    #[error("Client stopped answering heartbeats")]
+    HeartbeatTimeout,
+
+    #[error("Too many {0} messages, slow down")]
+    RateLimited(MessageBudget),
+
+    #[error("Client kept exceeding its rate limits")]
+    Flooding,
+
+    #[error("Client fell too far behind on outgoing messages")]
+    SlowConsumer,
+
     #[error("Internal Server Error: {0}")]
     InternalServerError(String),
 
+    #[error("WebRTC error: {0}")]
+    WebRtc(#[from] webrtc::Error),
+
+    #[error("Media error: {0}")]
+    Media(#[from] webrtc::media::Error),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Room {0} is hosted by another instance")]
+    RoomOnOtherInstance(String),
+
+    #[error("Lobby {0} is already being recorded")]
+    AlreadyRecording(String),
+
+    #[error("Lobby {0} is not being recorded here")]
+    NotRecording(String),
+
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Metrics error: {0}")]
+    Metrics(#[from] prometheus::Error),
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("Protocol version {0} is not supported")]
+    UnsupportedProtocolVersion(u32),
+
     #[error("Initialization message error: {0}")]
     InitializationError(String),
 
@@ -41,6 +120,8 @@ pub enum AppError {
 
 pub type AppResult<T> = Result<T, AppError>;
 
+impl warp::reject::Reject for AppError {}
+
 impl From<ServicesError> for AppError {
     fn from(value: ServicesError) -> Self {
         eprintln!("Services Error: {:?}", value);
@@ -52,10 +133,16 @@ impl AppError {
     pub fn to_ws_close_message(&self) -> Message {
         let (code, reason) = match self {
             AppError::JwtAuth(_) => (1008, "Authentication failed"),
-            AppError::InvalidMessageFormat | AppError::MissingField(_) => {
+            AppError::InvalidMessageFormat | AppError::MissingField(_) | AppError::TooLong(..) => {
                 (1007, "Invalid message format")
             }
             AppError::InitializationError(_) => (1002, "Protocol error"),
+            AppError::UnsupportedProtocolVersion(_) => (1002, "Unsupported protocol version"),
+            AppError::HeartbeatTimeout => (1001, "Heartbeat timeout"),
+            AppError::Flooding => (1008, "Rate limit exceeded"),
+            AppError::SlowConsumer => (1008, "Too many pending messages"),
+            AppError::ForceDisconnected => (1008, "Disconnected by an administrator"),
+            AppError::ShuttingDown => (1001, "Server shutting down"),
             _ => (1011, "Internal server error"),
         };
         Message::close_with(code as u16, reason)
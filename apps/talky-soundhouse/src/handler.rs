@@ -1,24 +1,30 @@
 use crate::error::{AppError, AppResult};
 use crate::message::{IncomingMessage, OutgoingMessage};
-use crate::state::{AppState, ClientInfo, ClientSender, UserResource};
-use futures::{SinkExt, StreamExt, TryStreamExt};
-use std::sync::Arc;
+use crate::outbound::ClientSender;
+use crate::protocol::{Encoding, Protocol, ServerLimits, CAPABILITIES};
+use crate::rate_limit::{MessageBudget, RateLimiter};
+use crate::state::{AppState, ClientInfo, UserResource};
+use futures::{StreamExt, TryStreamExt};
 use talky_auth::JwtService;
-use tokio::sync::Mutex;
+use tokio::time::{Instant, MissedTickBehavior};
 use ulid::Ulid;
 use warp::ws::{Message, WebSocket};
 
 pub async fn handle_connection(ws: WebSocket, state: AppState) {
     tracing::info!("New WebSocket connection established");
 
-    let (sender, receiver) = ws.split();
-    let sender = Arc::new(Mutex::new(sender));
+    let (sink, receiver) = ws.split();
+    let sender = ClientSender::spawn(
+        sink,
+        state.config().outbound_queue_size,
+        state.metrics().clone(),
+    );
     let mut receiver = receiver.map_err(AppError::WebSocket);
 
     if let Err(e) = initialize_client(&mut receiver, sender.clone(), state.clone()).await {
         tracing::error!("Initialization error: {:?}", e);
         let close_msg = e.to_ws_close_message();
-        let _ = sender.lock().await.send(close_msg).await;
+        let _ = sender.send(close_msg);
         return;
     }
 }
@@ -28,31 +34,89 @@ async fn initialize_client(
     sender: ClientSender,
     state: AppState,
 ) -> AppResult<()> {
-    let (client_id, initial_client_info) =
+    if state.is_draining() {
+        return Err(AppError::ShuttingDown);
+    }
+
+    let (initial_client_info, resume_token) =
         validate_initialization(receiver, sender.clone()).await?;
 
-    if let Err(e) = state.add_client(initial_client_info).await {
-        handle_client_error(sender, client_id.clone(), e).await;
-        return Ok(());
+    let resumed = match resume_token {
+        Some(token) => state.resume_client(&token, &initial_client_info).await,
+        None => None,
+    };
+    let is_resumed = resumed.is_some();
+    let client_info = resumed.unwrap_or(initial_client_info);
+    let client_id = client_info.id.clone();
+    let encoding = client_info.protocol.encoding();
+    // After a resume this is the session's sender, which now owns our socket
+    let sender = client_info.sender.clone();
+
+    let ack = OutgoingMessage::InitAck {
+        client_id: client_id.clone(),
+        resume_token: state.issue_resume_token(&client_info).await,
+        protocol_version: client_info.protocol.version,
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        limits: ServerLimits::from_config(state.config()),
+        ice: state.ice_config(&client_info.resource.user_id),
+    };
+    client_info.send(&ack)?;
+
+    if !is_resumed {
+        if let Err(e) = state.add_client(client_info).await {
+            handle_client_error(&sender, &client_id, encoding, e);
+            state.remove_client(&client_id).await;
+            return Ok(());
+        }
     }
 
-    if let Err(e) = handle_messages(receiver, sender.clone(), state.clone(), &client_id).await {
-        tracing::error!(
-            "Error during message loop for client {}: {:?}",
-            client_id,
-            e
-        );
+    match handle_messages(
+        receiver,
+        sender.clone(),
+        state.clone(),
+        &client_id,
+        encoding,
+    )
+    .await
+    {
+        Ok(()) => {
+            tracing::info!("Client {} disconnected", client_id);
+            state.remove_client(&client_id).await;
+        }
+        // Flooding clients do not get to resume
+        Err(AppError::Flooding) => {
+            tracing::warn!("Client {} disconnected for flooding", client_id);
+            state.remove_client(&client_id).await;
+        }
+        // Nor do clients that could not keep up with their messages
+        Err(AppError::SlowConsumer) => {
+            tracing::warn!("Client {} disconnected for falling behind", client_id);
+            state.remove_client(&client_id).await;
+        }
+        // Nor do clients that stopped answering heartbeats
+        Err(AppError::HeartbeatTimeout) => state.remove_client(&client_id).await,
+        // Nor does anyone once this instance is going away
+        Err(e) if state.is_draining() => {
+            tracing::info!("Client {} dropped while draining: {:?}", client_id, e);
+            state.remove_client(&client_id).await;
+        }
+        Err(e) => {
+            tracing::error!(
+                "Error during message loop for client {}: {:?}",
+                client_id,
+                e
+            );
+            state.suspend_client(&client_id).await;
+        }
     }
 
-    tracing::info!("Client {} disconnected", client_id);
-    state.remove_client(&client_id).await;
     Ok(())
 }
 
 async fn validate_initialization(
     receiver: &mut (impl StreamExt<Item = AppResult<Message>> + Unpin),
     sender: ClientSender,
-) -> AppResult<(String, ClientInfo)> {
+) -> AppResult<(ClientInfo, Option<String>)> {
     let init_msg = receiver
         .next()
         .await
@@ -71,7 +135,7 @@ async fn validate_initialization(
     tracing::debug!("Init msg: {}", init_text);
 
     let init_data: IncomingMessage = serde_json::from_str(init_text).map_err(AppError::Json)?;
-    let auth_code = parse_init_data(init_data)?;
+    let (auth_code, resume_token, protocol) = parse_init_data(init_data)?;
 
     let token_data = JwtService::decode(&auth_code)?;
     tracing::debug!(
@@ -87,20 +151,31 @@ async fn validate_initialization(
         },
         sender,
         current_niche_id: None,
+        current_channel_id: None,
+        niche_ids: Vec::new(),
+        membership: None,
+        protocol,
     };
 
-    Ok((client_id, client_info))
+    Ok((client_info, resume_token))
 }
 
-fn parse_init_data(init_data: IncomingMessage) -> AppResult<String> {
+fn parse_init_data(init_data: IncomingMessage) -> AppResult<(String, Option<String>, Protocol)> {
     match init_data {
-        IncomingMessage::Init { auth_code } => {
+        IncomingMessage::Init {
+            auth_code,
+            resume_token,
+            protocol_version,
+            capabilities,
+        } => {
+            let protocol = Protocol::negotiate(protocol_version, &capabilities)?;
+
             if auth_code.is_empty() {
                 return Err(AppError::InitializationError(
                     "auth_code cannot be empty".to_string(),
                 ));
             }
-            Ok(auth_code)
+            Ok((auth_code, resume_token, protocol))
         }
         _ => Err(AppError::InitializationError(
             "First message must be of type 'init'".to_string(),
@@ -108,22 +183,18 @@ fn parse_init_data(init_data: IncomingMessage) -> AppResult<String> {
     }
 }
 
-async fn handle_client_error(sender: ClientSender, client_id: String, error: AppError) {
+fn handle_client_error(sender: &ClientSender, client_id: &str, encoding: Encoding, error: AppError) {
     tracing::error!("Failed to add client {} : {:?}", client_id, error);
 
     let err_msg = OutgoingMessage::Error {
         message: format!("Failed to connect: {:?}", error),
     };
-    let _ = sender
-        .lock()
-        .await
-        .send(
-            err_msg
-                .to_ws_message()
-                .unwrap_or(Message::text("Error connecting")),
-        )
-        .await;
-    let _ = sender.lock().await.close().await;
+    let _ = sender.send(
+        err_msg
+            .to_ws_message(encoding)
+            .unwrap_or(Message::text("Error connecting")),
+    );
+    let _ = sender.send(Message::close());
 }
 
 async fn handle_messages(
@@ -131,9 +202,59 @@ async fn handle_messages(
     sender: ClientSender,
     state: AppState,
     client_id: &str,
+    encoding: Encoding,
 ) -> AppResult<()> {
-    while let Some(message_result) = receiver.next().await {
-        process_message(message_result, sender.clone(), &state, client_id).await?;
+    let heartbeat_timeout = state.config().heartbeat_timeout;
+    let mut heartbeat = tokio::time::interval(state.config().heartbeat_interval);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_seen = Instant::now();
+    let mut rate_limiter = RateLimiter::new(&state.config().rate_limits);
+
+    loop {
+        tokio::select! {
+            message_result = receiver.next() => {
+                // A stream that ends without a close frame was dropped, and
+                // the client may still resume its session
+                let Some(message_result) = message_result else {
+                    return Err(AppError::ClientDisconnected);
+                };
+                // Any frame, not just a pong, proves the connection is alive
+                last_seen = Instant::now();
+                let is_close = matches!(&message_result, Ok(msg) if msg.is_close());
+                process_message(
+                    message_result,
+                    sender.clone(),
+                    &state,
+                    client_id,
+                    encoding,
+                    &mut rate_limiter,
+                )
+                .await?;
+                if is_close {
+                    break;
+                }
+            }
+            _ = sender.overflowed() => {
+                return Err(AppError::SlowConsumer);
+            }
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > heartbeat_timeout {
+                    tracing::warn!(
+                        "Client {} missed heartbeats for {:?}, disconnecting",
+                        client_id,
+                        last_seen.elapsed()
+                    );
+                    let error = AppError::HeartbeatTimeout;
+                    let _ = sender.send(error.to_ws_close_message());
+                    return Err(error);
+                }
+
+                tracing::trace!("Sending Ping to {}", client_id);
+                sender.send(Message::ping(Vec::new())).inspect_err(|e| {
+                    tracing::warn!("Failed to send Ping to {}: {:?}", client_id, e);
+                })?;
+            }
+        }
     }
 
     tracing::info!("Client {} connection stream ended.", client_id);
@@ -145,26 +266,28 @@ async fn process_message(
     sender: ClientSender,
     state: &AppState,
     client_id: &str,
+    encoding: Encoding,
+    rate_limiter: &mut RateLimiter,
 ) -> AppResult<()> {
     match message_result {
-        Ok(msg) if msg.is_text() => {
-            let text = msg.to_str().unwrap_or("");
-            if let Err(e) = handle_text_message(text, state, client_id).await {
+        Ok(msg) if msg.is_text() || msg.is_binary() => {
+            if let Err(e) = handle_incoming_message(&msg, state, client_id, rate_limiter).await {
+                if let AppError::Flooding = e {
+                    let _ = sender.send(e.to_ws_close_message());
+                    return Err(e);
+                }
+
                 let err_msg = OutgoingMessage::Error {
                     message: format!("Error processing message: {:?}", e),
                 };
-                let _ = sender
-                    .lock()
-                    .await
-                    .send(
-                        err_msg
-                            .to_ws_message()
-                            .unwrap_or(Message::text("Processing error")),
-                    )
-                    .await;
+                let _ = sender.send(
+                    err_msg
+                        .to_ws_message(encoding)
+                        .unwrap_or(Message::text("Processing error")),
+                );
             }
         }
-        Ok(msg) => process_non_text_message(msg, sender, client_id).await?,
+        Ok(msg) => process_non_text_message(msg, &sender, client_id)?,
         Err(e) => {
             tracing::error!("WebSocket read error for client {}: {:?}", client_id, e);
             return Err(e);
@@ -173,23 +296,13 @@ async fn process_message(
     Ok(())
 }
 
-async fn process_non_text_message(
-    msg: Message,
-    sender: ClientSender,
-    client_id: &str,
-) -> AppResult<()> {
-    if msg.is_binary() {
-        tracing::debug!("Received binary message from {}", client_id);
-    } else if msg.is_ping() {
+fn process_non_text_message(msg: Message, sender: &ClientSender, client_id: &str) -> AppResult<()> {
+    if msg.is_ping() {
         tracing::trace!("Received Ping from {}", client_id);
         sender
-            .lock()
-            .await
             .send(Message::pong(msg.into_bytes()))
-            .await
-            .map_err(|e| {
-                tracing::warn!("Failed to send Pong to {}: {}", client_id, e);
-                AppError::ClientSendError
+            .inspect_err(|e| {
+                tracing::warn!("Failed to send Pong to {}: {:?}", client_id, e);
             })?;
     } else if msg.is_pong() {
         tracing::trace!("Received Pong from {}", client_id);
@@ -199,8 +312,25 @@ async fn process_non_text_message(
     Ok(())
 }
 
-async fn handle_text_message(text: &str, state: &AppState, client_id: &str) -> AppResult<()> {
-    match serde_json::from_str::<IncomingMessage>(text) {
+async fn handle_incoming_message(
+    msg: &Message,
+    state: &AppState,
+    client_id: &str,
+    rate_limiter: &mut RateLimiter,
+) -> AppResult<()> {
+    let message = IncomingMessage::from_ws_message(msg);
+    // Messages that do not parse are paid for all the same
+    let budget = match &message {
+        Ok(incoming_msg) => MessageBudget::of(incoming_msg),
+        Err(_) => MessageBudget::Control,
+    };
+    state.metrics().message_received(match &message {
+        Ok(incoming_msg) => incoming_msg.kind(),
+        Err(_) => "invalid",
+    });
+    rate_limiter.check(budget)?;
+
+    match message {
         Ok(incoming_msg) => {
             tracing::debug!("Received message from {}: {:?}", client_id, incoming_msg);
             match incoming_msg {
@@ -213,6 +343,9 @@ async fn handle_text_message(text: &str, state: &AppState, client_id: &str) -> A
                 IncomingMessage::UpdateNiche { niche_id } => {
                     state.update_niche(client_id, &niche_id).await?
                 }
+                IncomingMessage::UpdateChannel { channel_id } => {
+                    state.update_channel(client_id, channel_id).await?
+                }
                 IncomingMessage::Join { channel_id, role } => {
                     state.join(client_id, channel_id, role).await?
                 }
@@ -235,26 +368,118 @@ async fn handle_text_message(text: &str, state: &AppState, client_id: &str) -> A
                 IncomingMessage::Offer {
                     offer,
                     channel_id,
-                    niche_id,
+                    target_client_id,
                 } => {
-                    state.offer(client_id, offer, channel_id, niche_id).await?;
+                    state
+                        .offer(client_id, offer, channel_id, target_client_id)
+                        .await?;
                 }
                 IncomingMessage::Candidate {
                     candidate,
                     channel_id,
-                    niche_id,
+                    target_client_id,
                 } => {
                     state
-                        .candidate(client_id, candidate, channel_id, niche_id)
+                        .candidate(client_id, candidate, channel_id, target_client_id)
                         .await?;
                 }
                 IncomingMessage::Answer {
                     answer,
                     channel_id,
-                    niche_id,
+                    target_client_id,
+                } => {
+                    state
+                        .answer(client_id, answer, channel_id, target_client_id)
+                        .await?;
+                }
+                IncomingMessage::EditMessage {
+                    message_id,
+                    contents,
+                } => {
+                    state.edit_message(client_id, &message_id, contents).await?;
+                }
+                IncomingMessage::DeleteMessage { message_id } => {
+                    state.delete_message(client_id, &message_id).await?;
+                }
+                IncomingMessage::AddReaction { message_id, emoji } => {
+                    state.react(client_id, &message_id, emoji, true).await?;
+                }
+                IncomingMessage::RemoveReaction { message_id, emoji } => {
+                    state.react(client_id, &message_id, emoji, false).await?;
+                }
+                IncomingMessage::TypingStart { channel_id } => {
+                    state.start_typing(client_id, channel_id).await?;
+                }
+                IncomingMessage::TypingStop { channel_id } => {
+                    state.stop_typing(client_id, Some(&channel_id)).await;
+                }
+                IncomingMessage::SelfMute { muted } => {
+                    state.set_self_muted(client_id, muted).await?;
+                }
+                IncomingMessage::SelfDeafen { deafened } => {
+                    state.set_self_deafened(client_id, deafened).await?;
+                }
+                IncomingMessage::Speaking { speaking } => {
+                    state.set_speaking(client_id, speaking).await?;
+                }
+                IncomingMessage::Kick {
+                    channel_id,
+                    target_client_id,
+                } => {
+                    state.kick(client_id, channel_id, target_client_id).await?;
+                }
+                IncomingMessage::ServerMute {
+                    channel_id,
+                    target_client_id,
+                    muted,
+                } => {
+                    state
+                        .server_mute(client_id, channel_id, target_client_id, muted)
+                        .await?;
+                }
+                IncomingMessage::ServerDeafen {
+                    channel_id,
+                    target_client_id,
+                    deafened,
+                } => {
+                    state
+                        .server_deafen(client_id, channel_id, target_client_id, deafened)
+                        .await?;
+                }
+                IncomingMessage::Move {
+                    channel_id,
+                    target_client_id,
+                    destination_channel_id,
+                } => {
+                    state
+                        .move_client(
+                            client_id,
+                            channel_id,
+                            target_client_id,
+                            destination_channel_id,
+                        )
+                        .await?;
+                }
+                IncomingMessage::Record {
+                    channel_id,
+                    recording,
+                } => {
+                    state
+                        .set_recording(client_id, channel_id, recording)
+                        .await?;
+                }
+                IncomingMessage::SetStatus {
+                    status,
+                    custom_status,
+                } => {
+                    state.set_status(client_id, status, custom_status).await?;
+                }
+                IncomingMessage::DirectMessage {
+                    conversation_id,
+                    content,
                 } => {
                     state
-                        .answer(client_id, answer, channel_id, niche_id)
+                        .send_direct_message(client_id, conversation_id, content)
                         .await?;
                 }
             }
@@ -262,7 +487,7 @@ async fn handle_text_message(text: &str, state: &AppState, client_id: &str) -> A
         }
         Err(e) => {
             tracing::info!("Passing message from {} along", client_id,);
-            Err(AppError::Json(e))
+            Err(e)
             // Ok(())
         }
     }
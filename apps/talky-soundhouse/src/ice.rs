@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+/// One entry of an `RTCConfiguration.iceServers` list. STUN servers come
+/// without credentials, which are left out rather than sent as null.
+#[derive(Type, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+/// Which candidates a peer connection may use, as in
+/// `RTCConfiguration.iceTransportPolicy`.
+#[derive(Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IceTransportPolicy {
+    All,
+    /// Only candidates relayed through TURN, which hides client addresses.
+    Relay,
+}
+
+/// Everything a client needs to configure its peer connections. Clients
+/// use it as is instead of shipping their own server list, so STUN and TURN
+/// can move without a client release.
+#[derive(Type, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IceConfig {
+    pub ice_servers: Vec<IceServer>,
+    pub ice_transport_policy: IceTransportPolicy,
+}
@@ -1,6 +1,21 @@
+pub mod admin;
+pub mod backend;
 pub mod config;
 pub mod error;
 pub mod handler;
+pub mod ice;
 pub mod message;
+pub mod metrics;
+pub mod outbound;
+pub mod protocol;
+pub mod rate_limit;
+pub mod recording;
+pub mod role;
 pub mod server;
+pub mod session;
+pub mod sfu;
 pub mod state;
+#[cfg(test)]
+mod test_support;
+pub mod turn;
+pub mod typing;
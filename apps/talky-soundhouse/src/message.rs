@@ -3,9 +3,15 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use serde_json::{map::Values, Value};
 use specta::Type;
+use talky_services::conversation::service::DirectMessageResource;
 use talky_services::message::service::MessageResource;
+use talky_services::user::service::{UserProfileResource, UserStatus};
 
-use crate::state::{RoomClientInfo, RoomResource};
+use crate::error::AppResult;
+use crate::protocol::{Encoding, ServerLimits};
+use crate::role::Role;
+use crate::state::{RoomClientInfo, RoomResource, UserRoomResource};
+use crate::ice::IceConfig;
 
 #[derive(Type, Deserialize, Debug, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -13,32 +19,40 @@ use crate::state::{RoomClientInfo, RoomResource};
 pub enum IncomingMessage {
     Init {
         auth_code: String,
+        /// Token from an earlier `init_ack`, to pick up a dropped session.
+        #[serde(default)]
+        resume_token: Option<String>,
+        /// Absent for clients that predate versioning, which speak version 1.
+        #[serde(default)]
+        protocol_version: Option<u32>,
+        #[serde(default)]
+        capabilities: Vec<String>,
     },
     UpdateNiche {
         niche_id: String,
     },
+    /// The text channel the client is looking at, if any.
+    UpdateChannel {
+        channel_id: Option<String>,
+    },
     Join {
         channel_id: String,
-        role: String,
+        role: Role,
     },
     Candidate {
         candidate: Value,
-
         channel_id: String,
-        // todo remove this
-        niche_id: String,
+        target_client_id: String,
     },
     Answer {
         answer: String,
         channel_id: String,
-        // todo remove this
-        niche_id: String,
+        target_client_id: String,
     },
     Offer {
         offer: String,
         channel_id: String,
-        // todo remove this
-        niche_id: String,
+        target_client_id: String,
     },
     ChatMessage {
         content: String,
@@ -48,30 +62,127 @@ pub enum IncomingMessage {
         target_client_id: String,
         signal_data: Value,
     },
+    EditMessage {
+        message_id: String,
+        contents: String,
+    },
+    DeleteMessage {
+        message_id: String,
+    },
+    AddReaction {
+        message_id: String,
+        emoji: String,
+    },
+    RemoveReaction {
+        message_id: String,
+        emoji: String,
+    },
+    TypingStart {
+        channel_id: String,
+    },
+    TypingStop {
+        channel_id: String,
+    },
+    /// The client muted or unmuted its microphone.
+    SelfMute {
+        muted: bool,
+    },
+    /// The client muted or unmuted the lobby's audio.
+    SelfDeafen {
+        deafened: bool,
+    },
+    /// The client started or stopped talking.
+    Speaking {
+        speaking: bool,
+    },
+    /// Removes a client from the lobby.
+    Kick {
+        channel_id: String,
+        target_client_id: String,
+    },
+    /// Stops forwarding a client's audio to the lobby.
+    ServerMute {
+        channel_id: String,
+        target_client_id: String,
+        muted: bool,
+    },
+    /// Stops forwarding the lobby's audio to a client.
+    ServerDeafen {
+        channel_id: String,
+        target_client_id: String,
+        deafened: bool,
+    },
+    /// Moves a client to another lobby of the same niche.
+    Move {
+        channel_id: String,
+        target_client_id: String,
+        destination_channel_id: String,
+    },
+    /// Starts or stops recording the lobby the client is in.
+    Record {
+        channel_id: String,
+        recording: bool,
+    },
+    /// Changes the status the user shows in its niches.
+    SetStatus {
+        status: UserStatus,
+        #[serde(default)]
+        custom_status: Option<String>,
+    },
+    /// Writes to a conversation the user takes part in.
+    DirectMessage {
+        conversation_id: String,
+        content: String,
+    },
 }
 
-#[derive(Type, Serialize, Debug, Clone)]
+#[derive(Type, Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 #[specta(rename = "IncomingMessage")]
 pub enum OutgoingMessage {
+    InitAck {
+        client_id: String,
+        resume_token: String,
+        protocol_version: u32,
+        capabilities: Vec<String>,
+        limits: ServerLimits,
+        /// How the client should set up its peer connections.
+        ice: IceConfig,
+    },
+
     ActiveChannels {
         channels: HashMap<String, RoomResource>,
     },
 
     Candidate {
         candidate: Value,
+        sender_client_id: String,
+        target_client_id: String,
     },
 
     Answer {
         answer: String,
+        sender_client_id: String,
+        target_client_id: String,
     },
 
     Offer {
         offer: String,
+        sender_client_id: String,
+        target_client_id: String,
     },
 
+    /// Everyone online who shares a niche with the client, and the client's
+    /// own user. Sent once after connecting, `presence_update` follows.
     ActiveClientsUpdate {
-        clients: Vec<ClientInfoMsg>,
+        clients: Vec<UserProfileResource>,
+    },
+
+    /// A user sharing a niche with the client came online, changed its
+    /// profile or status, or went offline, which leaves `presence` empty.
+    PresenceUpdate {
+        user_id: String,
+        presence: Option<UserProfileResource>,
     },
 
     ChatMessageBroadcast {
@@ -80,10 +191,91 @@ pub enum OutgoingMessage {
         channel_id: String,
     },
 
+    /// A message in one of the user's conversations, sent to every client of
+    /// every participant, the sender's included.
+    DirectMessageBroadcast {
+        sender_id: String,
+        conversation_id: String,
+        message: DirectMessageResource,
+    },
+
+    /// A message was edited, deleted or reacted to.
+    ChatMessageUpdated {
+        channel_id: String,
+        message: MessageResource,
+    },
+
     WebRtcSignal {
         sender_client_id: String,
         signal_data: Value,
     },
+
+    /// The lobby the client was in has expired and was deleted.
+    LobbyClosed {
+        channel_id: String,
+    },
+
+    /// A client started or stopped typing in the channel.
+    Typing {
+        channel_id: String,
+        client_id: String,
+        user_id: String,
+        typing: bool,
+    },
+
+    /// One client's state in a room changed.
+    RoomClientUpdate {
+        channel_id: String,
+        client: UserRoomResource,
+    },
+
+    Kicked {
+        channel_id: String,
+        client_id: String,
+        moderator_client_id: String,
+    },
+
+    ServerMuted {
+        channel_id: String,
+        client_id: String,
+        moderator_client_id: String,
+        muted: bool,
+    },
+
+    ServerDeafened {
+        channel_id: String,
+        client_id: String,
+        moderator_client_id: String,
+        deafened: bool,
+    },
+
+    Moved {
+        channel_id: String,
+        destination_channel_id: String,
+        client_id: String,
+        moderator_client_id: String,
+    },
+
+    /// The lobby started or stopped being recorded. Sent to the niche when
+    /// that happens and to everyone who joins while it lasts.
+    Recording {
+        channel_id: String,
+        recording_id: String,
+        owner_user_id: String,
+        recording: bool,
+    },
+
+    /// The server is shutting down. Clients should leave and reconnect
+    /// after the delay, when they will land on another instance.
+    Shutdown {
+        reconnect_delay_secs: u32,
+    },
+
+    /// The server is about to close the connection.
+    Disconnected {
+        reason: String,
+    },
+
     Error {
         message: String,
     },
@@ -94,9 +286,53 @@ pub struct ClientInfoMsg {
     pub user_id: String,
 }
 
+impl IncomingMessage {
+    /// The `type` tag of the message.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            IncomingMessage::Init { .. } => "init",
+            IncomingMessage::UpdateNiche { .. } => "update_niche",
+            IncomingMessage::UpdateChannel { .. } => "update_channel",
+            IncomingMessage::Join { .. } => "join",
+            IncomingMessage::Candidate { .. } => "candidate",
+            IncomingMessage::Answer { .. } => "answer",
+            IncomingMessage::Offer { .. } => "offer",
+            IncomingMessage::ChatMessage { .. } => "chat_message",
+            IncomingMessage::WebRtcSignal { .. } => "web_rtc_signal",
+            IncomingMessage::EditMessage { .. } => "edit_message",
+            IncomingMessage::DeleteMessage { .. } => "delete_message",
+            IncomingMessage::AddReaction { .. } => "add_reaction",
+            IncomingMessage::RemoveReaction { .. } => "remove_reaction",
+            IncomingMessage::TypingStart { .. } => "typing_start",
+            IncomingMessage::TypingStop { .. } => "typing_stop",
+            IncomingMessage::SelfMute { .. } => "self_mute",
+            IncomingMessage::SelfDeafen { .. } => "self_deafen",
+            IncomingMessage::Speaking { .. } => "speaking",
+            IncomingMessage::Kick { .. } => "kick",
+            IncomingMessage::ServerMute { .. } => "server_mute",
+            IncomingMessage::ServerDeafen { .. } => "server_deafen",
+            IncomingMessage::Move { .. } => "move",
+            IncomingMessage::Record { .. } => "record",
+            IncomingMessage::SetStatus { .. } => "set_status",
+            IncomingMessage::DirectMessage { .. } => "direct_message",
+        }
+    }
+
+    pub fn from_ws_message(message: &warp::ws::Message) -> AppResult<Self> {
+        if message.is_binary() {
+            Ok(rmp_serde::from_slice(message.as_bytes())?)
+        } else {
+            Ok(serde_json::from_slice(message.as_bytes())?)
+        }
+    }
+}
+
 impl OutgoingMessage {
-    pub fn to_ws_message(&self) -> Result<warp::ws::Message, serde_json::Error> {
-        let json_string = serde_json::to_string(self)?;
-        Ok(warp::ws::Message::text(json_string))
+    pub fn to_ws_message(&self, encoding: Encoding) -> AppResult<warp::ws::Message> {
+        match encoding {
+            Encoding::Json => Ok(warp::ws::Message::text(serde_json::to_string(self)?)),
+            // Named fields, so the `type` tag and field names survive
+            Encoding::MessagePack => Ok(warp::ws::Message::binary(rmp_serde::to_vec_named(self)?)),
+        }
     }
 }
@@ -0,0 +1,121 @@
+use crate::error::{AppError, AppResult};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::collections::HashMap;
+use std::future::Future;
+use tokio::time::Instant;
+
+/// Prometheus metrics of this instance, served on `/metrics`.
+///
+/// Prometheus metrics are reference counted, so clones share their values.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    registry: Registry,
+    connected_clients: IntGauge,
+    rooms: IntGaugeVec,
+    messages_received: IntCounterVec,
+    fanout_latency: Histogram,
+    send_failures: IntCounter,
+    outbound_overflows: IntCounter,
+    db_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> AppResult<Self> {
+        let registry = Registry::new_custom(Some("soundhouse".to_string()), None)?;
+
+        let connected_clients =
+            IntGauge::new("connected_clients", "Clients connected to this instance")?;
+        let rooms = IntGaugeVec::new(
+            Opts::new("rooms", "Rooms with anyone in them, by niche"),
+            &["niche_id"],
+        )?;
+        let messages_received = IntCounterVec::new(
+            Opts::new("messages_received_total", "Messages received from clients"),
+            &["type"],
+        )?;
+        let fanout_latency = Histogram::with_opts(HistogramOpts::new(
+            "fanout_latency_seconds",
+            "Time to deliver a broadcast to every local recipient",
+        ))?;
+        let send_failures = IntCounter::new(
+            "send_failures_total",
+            "Messages that could not be written to a client socket",
+        )?;
+        let outbound_overflows = IntCounter::new(
+            "outbound_queue_overflows_total",
+            "Clients disconnected because their outbound queue filled up",
+        )?;
+        let db_duration = HistogramVec::new(
+            HistogramOpts::new("db_query_duration_seconds", "Time spent in database calls"),
+            &["operation"],
+        )?;
+
+        registry.register(Box::new(connected_clients.clone()))?;
+        registry.register(Box::new(rooms.clone()))?;
+        registry.register(Box::new(messages_received.clone()))?;
+        registry.register(Box::new(fanout_latency.clone()))?;
+        registry.register(Box::new(send_failures.clone()))?;
+        registry.register(Box::new(outbound_overflows.clone()))?;
+        registry.register(Box::new(db_duration.clone()))?;
+
+        Ok(Self {
+            registry,
+            connected_clients,
+            rooms,
+            messages_received,
+            fanout_latency,
+            send_failures,
+            outbound_overflows,
+            db_duration,
+        })
+    }
+
+    pub fn message_received(&self, kind: &str) {
+        self.messages_received.with_label_values(&[kind]).inc();
+    }
+
+    pub fn observe_fanout(&self, started_at: Instant) {
+        self.fanout_latency
+            .observe(started_at.elapsed().as_secs_f64());
+    }
+
+    pub fn send_failed(&self) {
+        self.send_failures.inc();
+    }
+
+    pub fn outbound_overflowed(&self) {
+        self.outbound_overflows.inc();
+    }
+
+    /// Runs a database call and records how long it took.
+    pub async fn time_db<T>(&self, operation: &str, call: impl Future<Output = T>) -> T {
+        let started_at = Instant::now();
+        let result = call.await;
+        self.db_duration
+            .with_label_values(&[operation])
+            .observe(started_at.elapsed().as_secs_f64());
+        result
+    }
+
+    /// Renders every metric in the Prometheus text format. Gauges are
+    /// sampled by the caller right before.
+    pub fn render(
+        &self,
+        connected_clients: usize,
+        rooms: HashMap<String, usize>,
+    ) -> AppResult<String> {
+        self.connected_clients.set(connected_clients as i64);
+        // Niches whose rooms all closed must not keep their last value
+        self.rooms.reset();
+        for (niche_id, count) in rooms {
+            self.rooms.with_label_values(&[&niche_id]).set(count as i64);
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| AppError::InternalServerError(e.to_string()))
+    }
+}
@@ -0,0 +1,142 @@
+use crate::error::{AppError, AppResult};
+use crate::metrics::Metrics;
+use futures::stream::SplitSink;
+use futures::SinkExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::task::AbortHandle;
+use warp::ws::{Message, WebSocket};
+
+type Socket = Arc<Mutex<SplitSink<WebSocket, Message>>>;
+
+// A client too slow to keep up may not take a close frame either
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The sending half of a client's socket.
+///
+/// Messages go into a bounded queue that a dedicated task writes out, so
+/// sending never waits on the network and a stalled socket only holds up
+/// its own client. A client whose queue fills up is cut off: its writer is
+/// stopped, a policy close frame is written past the queue, and the message
+/// loop is told through [`ClientSender::overflowed`].
+#[derive(Clone, Debug)]
+pub struct ClientSender {
+    queue: mpsc::Sender<Message>,
+    socket: Socket,
+    writer: AbortHandle,
+    overflowed: Arc<AtomicBool>,
+    overflow: Arc<Notify>,
+    metrics: Metrics,
+}
+
+impl ClientSender {
+    pub fn spawn(sink: SplitSink<WebSocket, Message>, capacity: usize, metrics: Metrics) -> Self {
+        let (queue, receiver) = mpsc::channel(capacity);
+        let socket = Arc::new(Mutex::new(sink));
+        let writer = tokio::spawn(write(receiver, socket.clone(), metrics.clone()));
+
+        Self {
+            queue,
+            socket,
+            writer: writer.abort_handle(),
+            overflowed: Arc::new(AtomicBool::new(false)),
+            overflow: Arc::new(Notify::new()),
+            metrics,
+        }
+    }
+
+    /// Queues a message without waiting. Fails once the client is gone or
+    /// its queue is full, in which case the client is cut off.
+    pub fn send(&self, message: Message) -> AppResult<()> {
+        match self.queue.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                if !self.overflowed.swap(true, Ordering::SeqCst) {
+                    self.writer.abort();
+                    tokio::spawn(close(self.socket.clone()));
+                    self.metrics.outbound_overflowed();
+                    self.overflow.notify_one();
+                }
+                Err(AppError::SlowConsumer)
+            }
+            Err(TrySendError::Closed(_)) => Err(AppError::ClientSendError),
+        }
+    }
+
+    /// Resolves once the queue has overflowed.
+    pub async fn overflowed(&self) {
+        self.overflow.notified().await
+    }
+
+    /// Moves the other sender's socket into this one, so everything holding
+    /// a clone of this sender writes to it from now on.
+    pub async fn take_socket(&self, other: &ClientSender) {
+        std::mem::swap(
+            &mut *self.socket.lock().await,
+            &mut *other.socket.lock().await,
+        );
+    }
+}
+
+/// Writes the close frame for a client that fell behind.
+async fn close(socket: Socket) {
+    let message = AppError::SlowConsumer.to_ws_close_message();
+    let sent = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        socket.lock().await.send(message).await
+    })
+    .await;
+
+    match sent {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => tracing::debug!("Failed to close socket: {}", e),
+        Err(_) => tracing::debug!("Timed out closing socket"),
+    }
+}
+
+async fn write(mut receiver: mpsc::Receiver<Message>, socket: Socket, metrics: Metrics) {
+    while let Some(message) = receiver.recv().await {
+        let is_close = message.is_close();
+        if let Err(e) = socket.lock().await.send(message).await {
+            metrics.send_failed();
+            tracing::debug!("Failed to write to socket: {}", e);
+        }
+        // Nothing may follow a close frame
+        if is_close {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_sender;
+
+    #[tokio::test]
+    async fn overflowing_closes_with_a_policy_violation() {
+        let (sender, mut client) = test_sender(1).await;
+
+        sender.send(Message::text("first")).unwrap();
+        assert!(matches!(
+            sender.send(Message::text("second")),
+            Err(AppError::SlowConsumer)
+        ));
+
+        use futures::StreamExt;
+        use tokio_tungstenite::tungstenite;
+
+        let close = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let tungstenite::Message::Close(frame) = client.next().await.unwrap().unwrap() {
+                    return frame.unwrap();
+                }
+            }
+        })
+        .await
+        .expect("no close frame");
+        assert_eq!(u16::from(close.code), 1008);
+    }
+}
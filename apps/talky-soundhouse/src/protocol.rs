@@ -0,0 +1,178 @@
+use crate::config::{BucketConfig, Config};
+use crate::error::{AppError, AppResult};
+use crate::state::{MAX_CUSTOM_STATUS_CHARS, MAX_REACTION_CHARS};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use talky_services::conversation::service::MAX_DIRECT_MESSAGE_CHARS;
+use talky_services::message::service::MAX_CHAT_MESSAGE_CHARS;
+
+/// The protocol version this server speaks.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest protocol version this server still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features this server supports. Clients announce the
+/// ones they understand in `init` and anything else is ignored.
+pub const CAPABILITIES: &[&str] = &[
+    "resume",
+    "typing",
+    "reactions",
+    "moderation",
+    "recording",
+    "presence",
+    "direct_messages",
+    MSGPACK,
+];
+
+/// Capability that switches a client to MessagePack binary frames after
+/// `init`.
+pub const MSGPACK: &str = "msgpack";
+
+/// How messages to a client are put on the wire. Messages from a client are
+/// decoded by frame type, text as JSON and binary as MessagePack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+}
+
+/// What a client and this server agreed on during init.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Protocol {
+    pub version: u32,
+    /// Capabilities both sides support.
+    pub capabilities: Vec<String>,
+}
+
+impl Protocol {
+    /// Settles on the newest version both sides speak. Clients that predate
+    /// versioning do not send one and are treated as version 1.
+    pub fn negotiate(version: Option<u32>, capabilities: &[String]) -> AppResult<Self> {
+        let requested = version.unwrap_or(1);
+        if requested < MIN_PROTOCOL_VERSION {
+            return Err(AppError::UnsupportedProtocolVersion(requested));
+        }
+
+        Ok(Self {
+            version: requested.min(PROTOCOL_VERSION),
+            capabilities: capabilities
+                .iter()
+                .filter(|capability| CAPABILITIES.contains(&capability.as_str()))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        if self.supports(MSGPACK) {
+            Encoding::MessagePack
+        } else {
+            Encoding::Json
+        }
+    }
+}
+
+#[derive(Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl From<&BucketConfig> for RateLimit {
+    fn from(bucket: &BucketConfig) -> Self {
+        Self {
+            per_second: bucket.per_second,
+            burst: bucket.burst,
+        }
+    }
+}
+
+/// The limits a client has to stay within, sent along with `init_ack`.
+#[derive(Type, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerLimits {
+    pub heartbeat_interval_secs: u32,
+    pub heartbeat_timeout_secs: u32,
+    pub resume_grace_secs: u32,
+    pub typing_timeout_secs: u32,
+    pub typing_throttle_secs: u32,
+    pub max_reaction_chars: u32,
+    pub max_custom_status_chars: u32,
+    pub max_direct_message_chars: u32,
+    pub max_chat_message_chars: u32,
+    pub chat: RateLimit,
+    pub signaling: RateLimit,
+    pub control: RateLimit,
+}
+
+impl ServerLimits {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            heartbeat_interval_secs: config.heartbeat_interval.as_secs() as u32,
+            heartbeat_timeout_secs: config.heartbeat_timeout.as_secs() as u32,
+            resume_grace_secs: config.resume_grace.as_secs() as u32,
+            typing_timeout_secs: config.typing_timeout.as_secs() as u32,
+            typing_throttle_secs: config.typing_throttle.as_secs() as u32,
+            max_reaction_chars: MAX_REACTION_CHARS as u32,
+            max_custom_status_chars: MAX_CUSTOM_STATUS_CHARS as u32,
+            max_direct_message_chars: MAX_DIRECT_MESSAGE_CHARS as u32,
+            max_chat_message_chars: MAX_CHAT_MESSAGE_CHARS as u32,
+            chat: RateLimit::from(&config.rate_limits.chat),
+            signaling: RateLimit::from(&config.rate_limits.signaling),
+            control: RateLimit::from(&config.rate_limits.control),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn clients_without_a_version_speak_version_1() {
+        let protocol = Protocol::negotiate(None, &[]).unwrap();
+        assert_eq!(protocol.version, 1);
+        assert!(protocol.capabilities.is_empty());
+    }
+
+    #[test]
+    fn newer_clients_get_the_server_version() {
+        let protocol = Protocol::negotiate(Some(PROTOCOL_VERSION + 1), &[]).unwrap();
+        assert_eq!(protocol.version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn versions_below_the_minimum_are_refused() {
+        let requested = MIN_PROTOCOL_VERSION - 1;
+        assert!(matches!(
+            Protocol::negotiate(Some(requested), &[]),
+            Err(AppError::UnsupportedProtocolVersion(version)) if version == requested
+        ));
+    }
+
+    #[test]
+    fn unknown_capabilities_are_dropped() {
+        let protocol =
+            Protocol::negotiate(Some(1), &capabilities(&["typing", "telepathy", "resume"]))
+                .unwrap();
+        assert_eq!(protocol.capabilities, capabilities(&["typing", "resume"]));
+        assert!(protocol.supports("typing"));
+        assert!(!protocol.supports("telepathy"));
+    }
+
+    #[test]
+    fn msgpack_switches_the_encoding() {
+        let json = Protocol::negotiate(Some(1), &capabilities(&["typing"])).unwrap();
+        assert_eq!(json.encoding(), Encoding::Json);
+
+        let msgpack = Protocol::negotiate(Some(1), &capabilities(&[MSGPACK])).unwrap();
+        assert_eq!(msgpack.encoding(), Encoding::MessagePack);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::config::{BucketConfig, RateLimitConfig};
+use crate::error::{AppError, AppResult};
+use crate::message::IncomingMessage;
+use std::fmt;
+use tokio::time::Instant;
+
+/// Which allowance a client message is paid from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageBudget {
+    /// Messages that end up in Postgres.
+    Chat,
+    /// WebRTC negotiation, which comes in bursts of candidates.
+    Signaling,
+    /// Everything else.
+    Control,
+}
+
+impl MessageBudget {
+    pub fn of(message: &IncomingMessage) -> Self {
+        match message {
+            IncomingMessage::ChatMessage { .. }
+            | IncomingMessage::DirectMessage { .. }
+            | IncomingMessage::EditMessage { .. }
+            | IncomingMessage::DeleteMessage { .. }
+            | IncomingMessage::AddReaction { .. }
+            | IncomingMessage::RemoveReaction { .. } => MessageBudget::Chat,
+            IncomingMessage::Candidate { .. }
+            | IncomingMessage::Answer { .. }
+            | IncomingMessage::Offer { .. }
+            | IncomingMessage::WebRtcSignal { .. } => MessageBudget::Signaling,
+            _ => MessageBudget::Control,
+        }
+    }
+}
+
+impl fmt::Display for MessageBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageBudget::Chat => write!(f, "chat"),
+            MessageBudget::Signaling => write!(f, "signaling"),
+            MessageBudget::Control => write!(f, "control"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, per_second: f64) -> Self {
+        Self {
+            capacity,
+            per_second,
+            tokens: capacity,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn from_config(config: &BucketConfig) -> Self {
+        Self::new(config.burst, config.per_second)
+    }
+
+    fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Message allowances of a single connection.
+///
+/// Every message over an allowance is refused and counts as a violation.
+/// Violations are themselves kept in a bucket, so a client that keeps
+/// hitting its limits runs out of them and is disconnected.
+#[derive(Debug)]
+pub struct RateLimiter {
+    chat: TokenBucket,
+    signaling: TokenBucket,
+    control: TokenBucket,
+    violations: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            chat: TokenBucket::from_config(&config.chat),
+            signaling: TokenBucket::from_config(&config.signaling),
+            control: TokenBucket::from_config(&config.control),
+            violations: TokenBucket::new(
+                config.max_violations as f64,
+                config.max_violations as f64 / config.violation_window.as_secs_f64(),
+            ),
+        }
+    }
+
+    /// Takes a message from the budget, or fails with `RateLimited`, or with
+    /// `Flooding` once the client has run out of violations.
+    pub fn check(&mut self, budget: MessageBudget) -> AppResult<()> {
+        let bucket = match budget {
+            MessageBudget::Chat => &mut self.chat,
+            MessageBudget::Signaling => &mut self.signaling,
+            MessageBudget::Control => &mut self.control,
+        };
+
+        if bucket.try_take() {
+            return Ok(());
+        }
+
+        if self.violations.try_take() {
+            Err(AppError::RateLimited(budget))
+        } else {
+            Err(AppError::Flooding)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config() -> RateLimitConfig {
+        let bucket = BucketConfig {
+            per_second: 2.0,
+            burst: 3.0,
+        };
+        RateLimitConfig {
+            chat: bucket,
+            signaling: bucket,
+            control: bucket,
+            max_violations: 2,
+            violation_window: Duration::from_secs(10),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_allows_a_burst_then_refills() {
+        let mut bucket = TokenBucket::new(3.0, 2.0);
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_does_not_refill_past_its_capacity() {
+        let mut bucket = TokenBucket::new(2.0, 2.0);
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn budgets_are_kept_apart() {
+        let mut limiter = RateLimiter::new(&config());
+        for _ in 0..3 {
+            limiter.check(MessageBudget::Chat).unwrap();
+        }
+
+        assert!(matches!(
+            limiter.check(MessageBudget::Chat),
+            Err(AppError::RateLimited(MessageBudget::Chat))
+        ));
+        assert!(limiter.check(MessageBudget::Signaling).is_ok());
+        assert!(limiter.check(MessageBudget::Control).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn running_out_of_violations_is_flooding() {
+        let mut limiter = RateLimiter::new(&config());
+        for _ in 0..3 {
+            limiter.check(MessageBudget::Control).unwrap();
+        }
+
+        assert!(matches!(
+            limiter.check(MessageBudget::Control),
+            Err(AppError::RateLimited(_))
+        ));
+        assert!(matches!(
+            limiter.check(MessageBudget::Control),
+            Err(AppError::RateLimited(_))
+        ));
+        assert!(matches!(
+            limiter.check(MessageBudget::Control),
+            Err(AppError::Flooding)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn violations_are_forgiven_over_their_window() {
+        let mut limiter = RateLimiter::new(&config());
+        for _ in 0..3 {
+            limiter.check(MessageBudget::Control).unwrap();
+        }
+        assert!(limiter.check(MessageBudget::Control).is_err());
+        assert!(limiter.check(MessageBudget::Control).is_err());
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        for _ in 0..3 {
+            limiter.check(MessageBudget::Control).unwrap();
+        }
+        assert!(matches!(
+            limiter.check(MessageBudget::Control),
+            Err(AppError::RateLimited(_))
+        ));
+    }
+}
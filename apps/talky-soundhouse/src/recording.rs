@@ -0,0 +1,314 @@
+use crate::error::AppResult;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+use webrtc::media::io::ogg_writer::OggWriter;
+use webrtc::media::io::Writer;
+use webrtc::rtp::packet::Packet;
+
+const OPUS_SAMPLE_RATE: u32 = 48000;
+const OPUS_CHANNELS: u8 = 2;
+// Packets waiting for the disk, a few seconds of a busy room
+const WRITE_QUEUE_SIZE: usize = 2048;
+
+/// A running recording of one room. The SFU hands it every packet it
+/// receives from the room's publishers, and each published track ends up in
+/// its own Ogg/Opus file named after the publishing user and the track's
+/// SSRC, so a user who reconnects gets a second file.
+///
+/// There is no mixed file: mixing means decoding every track, which the SFU
+/// never does otherwise, so the tracks are left to be mixed afterwards.
+pub struct Recording {
+    id: String,
+    lobby_id: String,
+    niche_id: String,
+    owner_user_id: String,
+    directory: PathBuf,
+    // Packets go to a blocking writer so the disk never holds up forwarding
+    writes: mpsc::Sender<Write>,
+    finished: AtomicBool,
+}
+
+enum Write {
+    Packet(String, Packet),
+    Finish(oneshot::Sender<()>),
+}
+
+struct TrackFile {
+    writer: OggWriter<BufWriter<File>>,
+    name: String,
+    segment: u32,
+    last_timestamp: u32,
+}
+
+impl TrackFile {
+    fn create(directory: &Path, name: String, segment: u32, timestamp: u32) -> AppResult<Self> {
+        let file_name = match segment {
+            0 => format!("{}.ogg", name),
+            segment => format!("{}-{}.ogg", name, segment),
+        };
+        let file = File::create(directory.join(file_name))?;
+        let writer = OggWriter::new(BufWriter::new(file), OPUS_SAMPLE_RATE, OPUS_CHANNELS)?;
+
+        Ok(Self {
+            writer,
+            name,
+            segment,
+            last_timestamp: timestamp,
+        })
+    }
+
+    fn write(&mut self, directory: &Path, packet: &Packet) -> AppResult<()> {
+        let timestamp = packet.header.timestamp;
+        // Packets that arrive out of order are dropped, Ogg pages only go
+        // forwards
+        if timestamp.wrapping_sub(self.last_timestamp) > u32::MAX / 2 {
+            return Ok(());
+        }
+        // The writer cannot follow the RTP clock around, so a wrapped
+        // timestamp starts the next file
+        if timestamp < self.last_timestamp {
+            self.close();
+            *self = TrackFile::create(directory, self.name.clone(), self.segment + 1, timestamp)?;
+        }
+
+        self.last_timestamp = timestamp;
+        self.writer.write_rtp(packet)?;
+
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        if let Err(e) = self.writer.close() {
+            tracing::warn!("Failed to finish recording file {}: {:?}", self.name, e);
+        }
+    }
+}
+
+/// Writes the packets to their tracks' files until the recording finishes
+/// or is dropped, then closes every file.
+fn write_tracks(directory: PathBuf, mut writes: mpsc::Receiver<Write>) {
+    // SSRC -> file the track is written to
+    let mut tracks = HashMap::new();
+
+    let finished = loop {
+        match writes.blocking_recv() {
+            Some(Write::Packet(user_id, packet)) => {
+                write_packet(&directory, &mut tracks, &user_id, &packet)
+            }
+            Some(Write::Finish(finished)) => break Some(finished),
+            None => break None,
+        }
+    };
+
+    for (_, mut track) in tracks.drain() {
+        track.close();
+    }
+    if let Some(finished) = finished {
+        let _ = finished.send(());
+    }
+}
+
+/// A track whose file fails is left out of the rest of the recording.
+fn write_packet(
+    directory: &Path,
+    tracks: &mut HashMap<u32, TrackFile>,
+    user_id: &str,
+    packet: &Packet,
+) {
+    let ssrc = packet.header.ssrc;
+
+    let track = match tracks.entry(ssrc) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let name = format!("{}-{}", user_id, ssrc);
+            match TrackFile::create(directory, name, 0, packet.header.timestamp) {
+                Ok(track) => entry.insert(track),
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to start recording track {} of {}: {:?}",
+                        ssrc,
+                        user_id,
+                        e
+                    );
+                    return;
+                }
+            }
+        }
+    };
+
+    if let Err(e) = track.write(directory, packet) {
+        tracing::warn!(
+            "Failed to record track {} of {}, dropping it: {:?}",
+            ssrc,
+            user_id,
+            e
+        );
+        if let Some(mut track) = tracks.remove(&ssrc) {
+            track.close();
+        }
+    }
+}
+
+impl Recording {
+    /// Creates the directory the recording is written to and starts its
+    /// writer.
+    pub fn create(
+        id: String,
+        lobby_id: String,
+        niche_id: String,
+        owner_user_id: String,
+        root: &Path,
+    ) -> AppResult<Self> {
+        let directory = root.join(&id);
+        std::fs::create_dir_all(&directory)?;
+
+        let (writes, queued) = mpsc::channel(WRITE_QUEUE_SIZE);
+        let writer_directory = directory.clone();
+        tokio::task::spawn_blocking(move || write_tracks(writer_directory, queued));
+
+        Ok(Self {
+            id,
+            lobby_id,
+            niche_id,
+            owner_user_id,
+            directory,
+            writes,
+            finished: AtomicBool::new(false),
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn lobby_id(&self) -> &str {
+        &self.lobby_id
+    }
+
+    pub fn niche_id(&self) -> &str {
+        &self.niche_id
+    }
+
+    pub fn owner_user_id(&self) -> &str {
+        &self.owner_user_id
+    }
+
+    /// Queues an Opus packet the user published. Packets are dropped while
+    /// the disk is too slow to keep up, and once the recording finished.
+    pub fn write(&self, user_id: &str, packet: &Packet) {
+        if self.finished.load(Ordering::Acquire) {
+            return;
+        }
+
+        match self
+            .writes
+            .try_send(Write::Packet(user_id.to_string(), packet.clone()))
+        {
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!(
+                    "Recording {} fell behind, dropping a packet of {}",
+                    self.id,
+                    user_id
+                );
+            }
+        }
+    }
+
+    /// Closes every file once the packets queued so far are written. Later
+    /// packets are dropped.
+    pub async fn finish(&self) {
+        if self.finished.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let (finished, written) = oneshot::channel();
+        if self.writes.send(Write::Finish(finished)).await.is_ok() {
+            let _ = written.await;
+        }
+        tracing::info!(
+            "Finished recording {} of lobby {} in {}",
+            self.id,
+            self.lobby_id,
+            self.directory.display()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::rtp::header::Header;
+
+    const END_OF_STREAM: u8 = 0x04;
+
+    fn recording() -> Recording {
+        let root = std::env::temp_dir().join("talky-recording-tests");
+        Recording::create(
+            ulid::Ulid::new().to_string(),
+            "l1".to_string(),
+            "n1".to_string(),
+            "u1".to_string(),
+            &root,
+        )
+        .unwrap()
+    }
+
+    fn packet(ssrc: u32, timestamp: u32) -> Packet {
+        Packet {
+            header: Header {
+                ssrc,
+                timestamp,
+                ..Default::default()
+            },
+            payload: vec![0xfc, 0xff, 0xfe].into(),
+        }
+    }
+
+    fn files(recording: &Recording) -> Vec<String> {
+        let mut files: Vec<String> = std::fs::read_dir(&recording.directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn finishing_ends_every_track_file() {
+        let recording = recording();
+        for i in 0..10 {
+            recording.write("u1", &packet(1, i * 960));
+            recording.write("u2", &packet(2, i * 960));
+        }
+        recording.finish().await;
+
+        assert_eq!(files(&recording), ["u1-1.ogg", "u2-2.ogg"]);
+        for file in files(&recording) {
+            let bytes = std::fs::read(recording.directory.join(file)).unwrap();
+            let last_page = bytes.windows(4).rposition(|b| b == b"OggS").unwrap();
+            assert_eq!(bytes[last_page + 5], END_OF_STREAM);
+        }
+        std::fs::remove_dir_all(&recording.directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn packets_after_finishing_start_no_files() {
+        let recording = recording();
+        recording.write("u1", &packet(1, 0));
+        recording.finish().await;
+
+        recording.write("u1", &packet(1, 960));
+        recording.write("u2", &packet(2, 0));
+        recording.finish().await;
+
+        assert_eq!(files(&recording), ["u1-1.ogg"]);
+        std::fs::remove_dir_all(&recording.directory).unwrap();
+    }
+}
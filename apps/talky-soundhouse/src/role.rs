@@ -0,0 +1,61 @@
+use crate::error::{AppError, AppResult};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use talky_services::lobby::service::LobbyResource;
+use talky_services::niche::service::NicheRole;
+
+/// What a participant is in a lobby.
+#[derive(Type, Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Speaker,
+    Listener,
+    Moderator,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub send_media: bool,
+    pub send_chat: bool,
+    pub moderate: bool,
+}
+
+impl Role {
+    pub fn permissions(&self) -> Permissions {
+        match self {
+            Role::Speaker => Permissions {
+                send_media: true,
+                send_chat: true,
+                moderate: false,
+            },
+            Role::Listener => Permissions {
+                send_media: false,
+                send_chat: false,
+                moderate: false,
+            },
+            Role::Moderator => Permissions {
+                send_media: true,
+                send_chat: true,
+                moderate: true,
+            },
+        }
+    }
+
+    /// Checks that the user may take this role in the lobby. Moderating is
+    /// reserved for the lobby's owner and the niche's moderators.
+    pub fn authorize(
+        self,
+        lobby: &LobbyResource,
+        user_id: &str,
+        niche_role: NicheRole,
+    ) -> AppResult<Self> {
+        if self == Role::Moderator && lobby.owner_user_id != user_id && !niche_role.can_moderate() {
+            return Err(AppError::PermissionDenied(format!(
+                "moderator role in lobby {}",
+                lobby.id
+            )));
+        }
+
+        Ok(self)
+    }
+}
@@ -1,6 +1,10 @@
+use crate::admin::admin_routes;
+use crate::error::AppError;
 use crate::handler::handle_connection;
 use crate::state::AppState;
 use std::convert::Infallible;
+use warp::http::StatusCode;
+use warp::reply::with_status;
 use warp::Filter;
 
 fn with_state(state: AppState) -> impl Filter<Extract = (AppState,), Error = Infallible> + Clone {
@@ -9,23 +13,57 @@ fn with_state(state: AppState) -> impl Filter<Extract = (AppState,), Error = Inf
 
 pub fn build_routes(
     state: AppState,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+) -> impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone {
     let soundhouse_route = warp::path("soundhouse")
         .and(warp::ws())
-        .and(with_state(state))
+        .and(with_state(state.clone()))
         .map(|ws: warp::ws::Ws, state: AppState| {
             ws.on_upgrade(move |socket| handle_connection(socket, state))
         });
 
-    // let health_route = warp::path("health").map(|| warp::reply::json(&serde_json::json!({"status": "ok"})));
+    // Liveness: the process is up and serving requests
+    let health_route = warp::path("health")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(|| warp::reply::json(&serde_json::json!({"status": "ok"})));
 
-    soundhouse_route // .or(health_route)
-}
+    let ready_route = warp::path("ready")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .then(|state: AppState| async move {
+            let (status, code) = if state.is_ready().await {
+                ("ready", StatusCode::OK)
+            } else {
+                ("unavailable", StatusCode::SERVICE_UNAVAILABLE)
+            };
+            warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({ "status": status })),
+                code,
+            )
+        });
 
-/*
-use crate::error::AppError;
-use warp::http::StatusCode;
-use warp::reply::with_status;
+    let metrics_route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .then(|state: AppState| async move {
+            match state.render_metrics().await {
+                Ok(metrics) => warp::reply::with_status(metrics, StatusCode::OK),
+                Err(e) => {
+                    tracing::error!("Failed to render metrics: {:?}", e);
+                    warp::reply::with_status(String::new(), StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            }
+        });
+
+    soundhouse_route
+        .or(health_route)
+        .or(ready_route)
+        .or(metrics_route)
+        .or(admin_routes(state))
+        .recover(handle_rejection)
+}
 
 pub async fn handle_rejection(rej: warp::Rejection) -> Result<impl warp::Reply, Infallible> {
     let code;
@@ -35,12 +73,15 @@ pub async fn handle_rejection(rej: warp::Rejection) -> Result<impl warp::Reply,
         code = StatusCode::NOT_FOUND;
         message = "NOT_FOUND".to_string();
     } else if let Some(e) = rej.find::<AppError>() {
-
         match e {
-            AppError::JwtAuth(_) => {
+            AppError::JwtAuth(_) | AppError::Unauthorized => {
                 code = StatusCode::UNAUTHORIZED;
                 message = "Authentication failed".to_string();
             }
+            AppError::ClientNotFound(_) | AppError::RoomNotFound(_) => {
+                code = StatusCode::NOT_FOUND;
+                message = e.to_string();
+            }
             _ => {
                 tracing::error!("Unhandled rejection: {:?}", e);
                 code = StatusCode::INTERNAL_SERVER_ERROR;
@@ -51,7 +92,6 @@ pub async fn handle_rejection(rej: warp::Rejection) -> Result<impl warp::Reply,
         code = StatusCode::METHOD_NOT_ALLOWED;
         message = "METHOD_NOT_ALLOWED".to_string();
     } else {
-
         tracing::error!("Unhandled warp rejection: {:?}", rej);
         code = StatusCode::INTERNAL_SERVER_ERROR;
         message = "INTERNAL_SERVER_ERROR".to_string();
@@ -64,4 +104,3 @@ pub async fn handle_rejection(rej: warp::Rejection) -> Result<impl warp::Reply,
 
     Ok(with_status(json, code))
 }
-*/
@@ -0,0 +1,203 @@
+use crate::state::ClientInfo;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+type ClientId = String;
+type ResumeToken = String;
+
+#[derive(Debug)]
+struct Session {
+    client_id: ClientId,
+    user_id: String,
+    // Set while the client is disconnected and may still come back
+    suspended: Option<SuspendedClient>,
+}
+
+#[derive(Debug)]
+struct SuspendedClient {
+    client: ClientInfo,
+    expires_at: Instant,
+}
+
+/// Resume tokens for the clients connected to this instance.
+///
+/// A client that drops without a close frame is suspended instead of
+/// removed: it keeps its presence and room membership until the grace
+/// window runs out, and a reconnect with its token picks the session up
+/// again.
+#[derive(Clone, Default)]
+pub struct SessionStore {
+    sessions: Arc<Mutex<HashMap<ResumeToken, Session>>>,
+}
+
+impl SessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Issues a fresh token for the client, revoking any earlier one.
+    pub async fn issue(&self, client: &ClientInfo) -> ResumeToken {
+        let mut sessions = self.sessions.lock().await;
+        sessions.retain(|_, session| session.client_id != client.id);
+
+        let token = Uuid::new_v4().simple().to_string();
+        sessions.insert(
+            token.clone(),
+            Session {
+                client_id: client.id.clone(),
+                user_id: client.resource.user_id.clone(),
+                suspended: None,
+            },
+        );
+
+        token
+    }
+
+    /// Returns whether the client had a session to suspend.
+    pub async fn suspend(&self, client: ClientInfo, expires_at: Instant) -> bool {
+        let mut sessions = self.sessions.lock().await;
+        match sessions
+            .values_mut()
+            .find(|session| session.client_id == client.id)
+        {
+            Some(session) => {
+                session.suspended = Some(SuspendedClient { client, expires_at });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Hands back the suspended client if the token is valid, belongs to the
+    /// same user and its grace window is still open. The token is consumed.
+    pub async fn resume(&self, token: &str, user_id: &str) -> Option<ClientInfo> {
+        let mut sessions = self.sessions.lock().await;
+        let session = sessions.get(token)?;
+        if session.user_id != user_id {
+            return None;
+        }
+
+        match &session.suspended {
+            Some(suspended) if suspended.expires_at > Instant::now() => {}
+            _ => return None,
+        }
+
+        sessions
+            .remove(token)
+            .and_then(|session| session.suspended)
+            .map(|suspended| suspended.client)
+    }
+
+    /// Drops the session if it is still in the suspension that ends at
+    /// `expires_at`, returning whether it was. A client that resumed and
+    /// dropped again in the meantime has a later deadline and is kept.
+    pub async fn expire(&self, client_id: &str, expires_at: Instant) -> bool {
+        let mut sessions = self.sessions.lock().await;
+        let before = sessions.len();
+        sessions.retain(|_, session| {
+            session.client_id != client_id
+                || session
+                    .suspended
+                    .as_ref()
+                    .is_none_or(|suspended| suspended.expires_at != expires_at)
+        });
+
+        sessions.len() != before
+    }
+
+    /// Clients that dropped and have not come back yet.
+    pub async fn suspended_clients(&self) -> Vec<String> {
+        self.sessions
+            .lock()
+            .await
+            .values()
+            .filter(|session| session.suspended.is_some())
+            .map(|session| session.client_id.clone())
+            .collect()
+    }
+
+    pub async fn remove(&self, client_id: &str) {
+        self.sessions
+            .lock()
+            .await
+            .retain(|_, session| session.client_id != client_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::client;
+    use std::time::Duration;
+
+    const GRACE: Duration = Duration::from_secs(30);
+
+    #[tokio::test(start_paused = true)]
+    async fn resumes_within_the_grace_window_once() {
+        let store = SessionStore::new();
+        let client = client("c1", "u1").await;
+        let token = store.issue(&client).await;
+        assert!(store.suspend(client, Instant::now() + GRACE).await);
+
+        tokio::time::advance(GRACE - Duration::from_secs(1)).await;
+        let resumed = store.resume(&token, "u1").await.unwrap();
+        assert_eq!(resumed.id, "c1");
+        assert!(store.resume(&token, "u1").await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn does_not_resume_once_the_grace_window_closed() {
+        let store = SessionStore::new();
+        let client = client("c1", "u1").await;
+        let token = store.issue(&client).await;
+        store.suspend(client, Instant::now() + GRACE).await;
+
+        tokio::time::advance(GRACE).await;
+        assert!(store.resume(&token, "u1").await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn does_not_resume_for_another_user_or_a_connected_client() {
+        let store = SessionStore::new();
+        let client = client("c1", "u1").await;
+        let token = store.issue(&client).await;
+        assert!(store.resume(&token, "u1").await.is_none());
+
+        store.suspend(client, Instant::now() + GRACE).await;
+        assert!(store.resume(&token, "u2").await.is_none());
+        assert!(store.resume(&token, "u1").await.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn issuing_revokes_the_earlier_token() {
+        let store = SessionStore::new();
+        let client = client("c1", "u1").await;
+        let first = store.issue(&client).await;
+        let second = store.issue(&client).await;
+        store.suspend(client, Instant::now() + GRACE).await;
+
+        assert!(store.resume(&first, "u1").await.is_none());
+        assert!(store.resume(&second, "u1").await.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expiring_keeps_a_later_suspension() {
+        let store = SessionStore::new();
+        let client = client("c1", "u1").await;
+        let token = store.issue(&client).await;
+        let first_deadline = Instant::now() + GRACE;
+        store.suspend(client, first_deadline).await;
+
+        // Resumed and dropped again before the first deadline passed
+        tokio::time::advance(Duration::from_secs(5)).await;
+        let client = store.resume(&token, "u1").await.unwrap();
+        let token = store.issue(&client).await;
+        store.suspend(client, Instant::now() + GRACE).await;
+
+        assert!(!store.expire("c1", first_deadline).await);
+        assert!(store.resume(&token, "u1").await.is_some());
+    }
+}
@@ -0,0 +1,616 @@
+use crate::backend::Moderation;
+use crate::error::{AppError, AppResult};
+use crate::message::OutgoingMessage;
+use crate::recording::Recording;
+use crate::state::ClientInfo;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::Mutex;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_OPUS};
+use webrtc::api::{APIBuilder, API};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::interceptor::registry::Registry;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::signaling_state::RTCSignalingState;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
+use webrtc::track::track_remote::TrackRemote;
+
+/// Reserved `target_client_id` that addresses soundhouse's own peer
+/// connection instead of another client in the room.
+pub const SFU_CLIENT_ID: &str = "soundhouse";
+
+type ClientId = String;
+type RoomId = String;
+type RoomTracks = HashMap<ClientId, Arc<TrackLocalStaticRTP>>;
+
+/// A server-side peer connection for a single client in a room.
+pub struct SfuPeer {
+    client_id: ClientId,
+    // Replaced when the client resumes its session, possibly with another
+    // encoding
+    client: RwLock<ClientInfo>,
+    room_id: RoomId,
+    connection: Arc<RTCPeerConnection>,
+    // publisher client_id -> sender forwarding that publisher's track to this peer
+    senders: Mutex<HashMap<ClientId, Arc<RTCRtpSender>>>,
+    needs_renegotiation: AtomicBool,
+    // Server-muted: the client's tracks are received but not forwarded
+    muted: Arc<AtomicBool>,
+    // Server-deafened: no tracks are forwarded to the client
+    deafened: AtomicBool,
+}
+
+impl SfuPeer {
+    fn send(&self, message: &OutgoingMessage) -> AppResult<()> {
+        self.client.read().unwrap().send(message)
+    }
+
+    /// Sends a fresh offer to the client, or defers it until the current
+    /// offer/answer exchange has finished.
+    async fn negotiate(&self) -> AppResult<()> {
+        if self.connection.signaling_state() != RTCSignalingState::Stable {
+            self.needs_renegotiation.store(true, Ordering::SeqCst);
+            return Ok(());
+        }
+        self.needs_renegotiation.store(false, Ordering::SeqCst);
+
+        let offer = self.connection.create_offer(None).await?;
+        self.connection.set_local_description(offer).await?;
+
+        if let Some(local_desc) = self.connection.local_description().await {
+            tracing::debug!("Sending renegotiation offer to client {}", self.client_id);
+            self.send(&OutgoingMessage::Offer {
+                offer: local_desc.sdp,
+                sender_client_id: SFU_CLIENT_ID.to_string(),
+                target_client_id: self.client_id.clone(),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    async fn negotiate_if_needed(&self) -> AppResult<()> {
+        if self.needs_renegotiation.load(Ordering::SeqCst) {
+            self.negotiate().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn add_forwarded_track(
+        &self,
+        publisher_id: &str,
+        track: Arc<TrackLocalStaticRTP>,
+    ) -> AppResult<()> {
+        let sender = self
+            .connection
+            .add_track(track as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+
+        // RTCP has to be drained for the interceptors to keep working
+        let rtcp_sender = sender.clone();
+        tokio::spawn(async move {
+            let mut rtcp_buf = vec![0u8; 1500];
+            while let Ok((_, _)) = rtcp_sender.read(&mut rtcp_buf).await {}
+        });
+
+        self.senders
+            .lock()
+            .await
+            .insert(publisher_id.to_string(), sender);
+
+        Ok(())
+    }
+
+    async fn remove_forwarded_track(&self, publisher_id: &str) -> AppResult<bool> {
+        let sender = self.senders.lock().await.remove(publisher_id);
+
+        match sender {
+            Some(sender) => {
+                self.connection.remove_track(&sender).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Selective forwarding unit: terminates one peer connection per client and
+/// relays every published track to the other members of the same room.
+#[derive(Clone)]
+pub struct Sfu {
+    api: Arc<API>,
+    peers: Arc<DashMap<ClientId, Arc<SfuPeer>>>,
+    // room_id -> publisher client_id -> forwarded track
+    tracks: Arc<DashMap<RoomId, RoomTracks>>,
+    // room_id -> recording every publisher's packets also go to
+    recordings: Arc<DashMap<RoomId, Arc<Recording>>>,
+}
+
+impl Sfu {
+    pub fn new() -> AppResult<Self> {
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs()?;
+
+        let registry = register_default_interceptors(Registry::new(), &mut media_engine)?;
+
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .build();
+
+        Ok(Self {
+            api: Arc::new(api),
+            peers: Arc::new(DashMap::new()),
+            tracks: Arc::new(DashMap::new()),
+            recordings: Arc::new(DashMap::new()),
+        })
+    }
+
+    /// Creates the client's peer connection for the room. Tracks from clients
+    /// that may not publish are received but never forwarded, and server
+    /// mutes and deafens carry over from earlier visits.
+    pub async fn join(
+        &self,
+        client: ClientInfo,
+        room_id: &str,
+        can_publish: bool,
+        moderation: Moderation,
+    ) -> AppResult<()> {
+        self.leave(&client.id).await;
+
+        let connection = Arc::new(
+            self.api
+                .new_peer_connection(RTCConfiguration::default())
+                .await?,
+        );
+
+        let peer = Arc::new(SfuPeer {
+            client_id: client.id.clone(),
+            client: RwLock::new(client.clone()),
+            room_id: room_id.to_string(),
+            connection: connection.clone(),
+            senders: Mutex::new(HashMap::new()),
+            needs_renegotiation: AtomicBool::new(false),
+            muted: Arc::new(AtomicBool::new(moderation.server_muted)),
+            deafened: AtomicBool::new(moderation.server_deafened),
+        });
+
+        // Weak, as the peer owns the connection holding this handler
+        let candidate_peer = Arc::downgrade(&peer);
+        connection.on_ice_candidate(Box::new(move |candidate| {
+            let peer = candidate_peer.clone();
+            Box::pin(async move {
+                let (Some(candidate), Some(peer)) = (candidate, peer.upgrade()) else {
+                    return;
+                };
+                match candidate.to_json() {
+                    Ok(candidate_init) => {
+                        let message = OutgoingMessage::Candidate {
+                            candidate: serde_json::to_value(candidate_init).unwrap_or_default(),
+                            sender_client_id: SFU_CLIENT_ID.to_string(),
+                            target_client_id: peer.client_id.clone(),
+                        };
+                        if let Err(e) = peer.send(&message) {
+                            tracing::warn!(
+                                "Failed to send ICE candidate to {}: {:?}",
+                                peer.client_id,
+                                e
+                            );
+                        }
+                    }
+                    Err(e) => tracing::warn!("Failed to serialize ICE candidate: {:?}", e),
+                }
+            })
+        }));
+
+        let state_client_id = client.id.clone();
+        connection.on_peer_connection_state_change(Box::new(move |state| {
+            tracing::info!(
+                "SFU peer connection for client {} changed state: {}",
+                state_client_id,
+                state
+            );
+            if state == RTCPeerConnectionState::Failed {
+                tracing::warn!("SFU peer connection for {} failed", state_client_id);
+            }
+            Box::pin(async {})
+        }));
+
+        let sfu = self.clone();
+        let publisher_id = client.id.clone();
+        let publisher_room_id = room_id.to_string();
+        connection.on_track(Box::new(move |track, _receiver, _transceiver| {
+            let sfu = sfu.clone();
+            let publisher_id = publisher_id.clone();
+            let room_id = publisher_room_id.clone();
+            Box::pin(async move {
+                if !can_publish {
+                    tracing::warn!(
+                        "Ignoring {} track from {}, who may not send media",
+                        track.kind(),
+                        publisher_id
+                    );
+                    return;
+                }
+                sfu.publish(&room_id, &publisher_id, track).await;
+            })
+        }));
+
+        // Deafened clients are not sent anything to begin with
+        let existing_tracks: Vec<(ClientId, Arc<TrackLocalStaticRTP>)> = self
+            .tracks
+            .get(room_id)
+            .filter(|_| !moderation.server_deafened)
+            .map(|tracks| {
+                tracks
+                    .iter()
+                    .map(|(id, track)| (id.clone(), track.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        for (publisher_id, track) in existing_tracks.iter() {
+            peer.add_forwarded_track(publisher_id, track.clone())
+                .await?;
+        }
+
+        self.peers.insert(client.id.clone(), peer.clone());
+
+        tracing::info!("Client {} joined SFU room {}", client.id, room_id);
+
+        if !existing_tracks.is_empty() {
+            peer.negotiate().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops the client's peer connection. Returns the room's recording if
+    /// it was the last peer in the room, which stops the recording.
+    pub async fn leave(&self, client_id: &str) -> Option<Arc<Recording>> {
+        let (_, peer) = self.peers.remove(client_id)?;
+
+        let removed_track = self
+            .tracks
+            .get_mut(&peer.room_id)
+            .and_then(|mut room_tracks| room_tracks.remove(client_id));
+        self.tracks
+            .remove_if(&peer.room_id, |_, room_tracks| room_tracks.is_empty());
+
+        if removed_track.is_some() {
+            for other in self.room_peers(&peer.room_id).await.iter() {
+                match other.remove_forwarded_track(client_id).await {
+                    Ok(true) => {
+                        if let Err(e) = other.negotiate().await {
+                            tracing::warn!(
+                                "Failed to renegotiate with client {}: {:?}",
+                                other.client_id,
+                                e
+                            );
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => tracing::warn!(
+                        "Failed to remove track of {} from client {}: {:?}",
+                        client_id,
+                        other.client_id,
+                        e
+                    ),
+                }
+            }
+        }
+
+        if let Err(e) = peer.connection.close().await {
+            tracing::warn!("Failed to close SFU peer for {}: {:?}", client_id, e);
+        }
+
+        tracing::info!("Client {} left SFU room {}", client_id, peer.room_id);
+
+        if self.room_peers(&peer.room_id).await.is_empty() {
+            return self.stop_recording(&peer.room_id).await;
+        }
+
+        None
+    }
+
+    /// Starts writing every track published in the room to the recording.
+    pub fn start_recording(&self, room_id: &str, recording: Recording) -> AppResult<()> {
+        match self.recordings.entry(room_id.to_string()) {
+            Entry::Occupied(_) => Err(AppError::AlreadyRecording(room_id.to_string())),
+            Entry::Vacant(entry) => {
+                tracing::info!("Recording room {} as {}", room_id, recording.id());
+                entry.insert(Arc::new(recording));
+                Ok(())
+            }
+        }
+    }
+
+    pub fn recording(&self, room_id: &str) -> Option<Arc<Recording>> {
+        self.recordings
+            .get(room_id)
+            .map(|recording| recording.clone())
+    }
+
+    /// Stops and finishes the room's recording, if it has one.
+    pub async fn stop_recording(&self, room_id: &str) -> Option<Arc<Recording>> {
+        let (_, recording) = self.recordings.remove(room_id)?;
+        recording.finish().await;
+        Some(recording)
+    }
+
+    /// Stops and finishes every recording of this instance.
+    pub async fn stop_recordings(&self) -> Vec<Arc<Recording>> {
+        let room_ids: Vec<RoomId> = self
+            .recordings
+            .iter()
+            .map(|recording| recording.key().clone())
+            .collect();
+
+        let mut recordings = Vec::new();
+        for room_id in room_ids {
+            recordings.extend(self.stop_recording(&room_id).await);
+        }
+        recordings
+    }
+
+    /// Makes the client's peer signal through the client as it is now, after
+    /// it resumed its session.
+    pub fn update_client(&self, client: &ClientInfo) {
+        if let Some(peer) = self.peers.get(&client.id) {
+            *peer.client.write().unwrap() = client.clone();
+        }
+    }
+
+    /// Stops or resumes forwarding the client's tracks to the room.
+    pub async fn set_muted(&self, client_id: &str, muted: bool) {
+        if let Some(peer) = self.peers.get(client_id) {
+            peer.muted.store(muted, Ordering::SeqCst);
+        }
+    }
+
+    /// Stops or resumes forwarding the room's tracks to the client.
+    pub async fn set_deafened(&self, client_id: &str, deafened: bool) -> AppResult<()> {
+        let Some(peer) = self.peers.get(client_id).map(|peer| peer.clone()) else {
+            return Ok(());
+        };
+
+        if peer.deafened.swap(deafened, Ordering::SeqCst) == deafened {
+            return Ok(());
+        }
+
+        if deafened {
+            let publisher_ids: Vec<ClientId> = peer.senders.lock().await.keys().cloned().collect();
+            for publisher_id in publisher_ids.iter() {
+                peer.remove_forwarded_track(publisher_id).await?;
+            }
+        } else {
+            let room_tracks: Vec<(ClientId, Arc<TrackLocalStaticRTP>)> = self
+                .tracks
+                .get(&peer.room_id)
+                .map(|tracks| {
+                    tracks
+                        .iter()
+                        .filter(|(publisher_id, _)| *publisher_id != client_id)
+                        .map(|(publisher_id, track)| (publisher_id.clone(), track.clone()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            for (publisher_id, track) in room_tracks {
+                peer.add_forwarded_track(&publisher_id, track).await?;
+            }
+        }
+
+        peer.negotiate().await
+    }
+
+    pub async fn handle_offer(&self, client_id: &str, room_id: &str, sdp: String) -> AppResult<()> {
+        let peer = self.get_peer(client_id, room_id).await?;
+
+        // The server is always the polite side: drop our own pending offer
+        // and let the client's win.
+        if peer.connection.signaling_state() == RTCSignalingState::HaveLocalOffer {
+            let mut rollback = RTCSessionDescription::default();
+            rollback.sdp_type = RTCSdpType::Rollback;
+            peer.connection.set_local_description(rollback).await?;
+            peer.needs_renegotiation.store(true, Ordering::SeqCst);
+        }
+
+        peer.connection
+            .set_remote_description(RTCSessionDescription::offer(sdp)?)
+            .await?;
+
+        let answer = peer.connection.create_answer(None).await?;
+        peer.connection.set_local_description(answer).await?;
+
+        if let Some(local_desc) = peer.connection.local_description().await {
+            peer.send(&OutgoingMessage::Answer {
+                answer: local_desc.sdp,
+                sender_client_id: SFU_CLIENT_ID.to_string(),
+                target_client_id: peer.client_id.clone(),
+            })?;
+        }
+
+        peer.negotiate_if_needed().await
+    }
+
+    pub async fn handle_answer(
+        &self,
+        client_id: &str,
+        room_id: &str,
+        sdp: String,
+    ) -> AppResult<()> {
+        let peer = self.get_peer(client_id, room_id).await?;
+
+        peer.connection
+            .set_remote_description(RTCSessionDescription::answer(sdp)?)
+            .await?;
+
+        peer.negotiate_if_needed().await
+    }
+
+    pub async fn handle_candidate(
+        &self,
+        client_id: &str,
+        room_id: &str,
+        candidate: Value,
+    ) -> AppResult<()> {
+        let peer = self.get_peer(client_id, room_id).await?;
+        let candidate_init: RTCIceCandidateInit = serde_json::from_value(candidate)?;
+
+        peer.connection.add_ice_candidate(candidate_init).await?;
+
+        Ok(())
+    }
+
+    async fn publish(&self, room_id: &str, publisher_id: &str, remote_track: Arc<TrackRemote>) {
+        tracing::info!(
+            "Client {} is publishing a {} track in room {}",
+            publisher_id,
+            remote_track.codec().capability.mime_type,
+            room_id
+        );
+
+        let local_track = Arc::new(TrackLocalStaticRTP::new(
+            remote_track.codec().capability,
+            format!("{}-{}", remote_track.kind(), publisher_id),
+            publisher_id.to_string(),
+        ));
+
+        self.tracks
+            .entry(room_id.to_string())
+            .or_default()
+            .insert(publisher_id.to_string(), local_track.clone());
+
+        let (muted, publisher_user_id) = match self.peers.get(publisher_id) {
+            Some(peer) => (
+                peer.muted.clone(),
+                peer.client.read().unwrap().resource.user_id.clone(),
+            ),
+            None => (Arc::new(AtomicBool::new(false)), publisher_id.to_string()),
+        };
+
+        let forward_track = local_track.clone();
+        let forward_publisher_id = publisher_id.to_string();
+        // Recordings are Ogg/Opus, other codecs are only forwarded
+        let recordable = remote_track
+            .codec()
+            .capability
+            .mime_type
+            .eq_ignore_ascii_case(MIME_TYPE_OPUS);
+        let recordings = self.recordings.clone();
+        let recording_room_id = room_id.to_string();
+        tokio::spawn(async move {
+            while let Ok((packet, _)) = remote_track.read_rtp().await {
+                // Keep reading while muted so the publisher's buffers drain
+                if muted.load(Ordering::SeqCst) {
+                    continue;
+                }
+                if recordable {
+                    if let Some(recording) = recordings.get(&recording_room_id) {
+                        recording.write(&publisher_user_id, &packet);
+                    }
+                }
+                if let Err(e) = forward_track.write_rtp(&packet).await {
+                    if webrtc::Error::ErrClosedPipe != e {
+                        tracing::warn!(
+                            "Failed to forward RTP from {}: {:?}",
+                            forward_publisher_id,
+                            e
+                        );
+                        break;
+                    }
+                }
+            }
+            tracing::debug!("Stopped forwarding track of {}", forward_publisher_id);
+        });
+
+        for peer in self.room_peers(room_id).await.iter() {
+            if peer.client_id == publisher_id || peer.deafened.load(Ordering::SeqCst) {
+                continue;
+            }
+
+            if let Err(e) = peer
+                .add_forwarded_track(publisher_id, local_track.clone())
+                .await
+            {
+                tracing::warn!(
+                    "Failed to forward track of {} to {}: {:?}",
+                    publisher_id,
+                    peer.client_id,
+                    e
+                );
+                continue;
+            }
+
+            if let Err(e) = peer.negotiate().await {
+                tracing::warn!(
+                    "Failed to renegotiate with client {}: {:?}",
+                    peer.client_id,
+                    e
+                );
+            }
+        }
+    }
+
+    async fn get_peer(&self, client_id: &str, room_id: &str) -> AppResult<Arc<SfuPeer>> {
+        match self.peers.get(client_id) {
+            Some(peer) if peer.room_id == room_id => Ok(peer.clone()),
+            _ => Err(AppError::Anyhow(anyhow::anyhow!(
+                "Client is not connected to room {}",
+                room_id
+            ))),
+        }
+    }
+
+    async fn room_peers(&self, room_id: &str) -> Vec<Arc<SfuPeer>> {
+        self.peers
+            .iter()
+            .filter(|peer| peer.room_id == room_id)
+            .map(|peer| peer.value().clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Protocol, MSGPACK};
+    use crate::test_support::connected_client;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn peers_signal_in_the_encoding_of_the_resumed_client() {
+        let sfu = Sfu::new().unwrap();
+        let (client, _) = connected_client("c1", "u1").await;
+        sfu.join(client, "l1", true, Moderation::default())
+            .await
+            .unwrap();
+
+        let (mut resumed, mut socket) = connected_client("c1", "u1").await;
+        resumed.protocol = Protocol::negotiate(None, &[MSGPACK.to_string()]).unwrap();
+        sfu.update_client(&resumed);
+        let peer = sfu.peers.get("c1").unwrap().clone();
+        peer.negotiate().await.unwrap();
+
+        let frame = socket.next().await.unwrap().unwrap();
+        assert!(frame.is_binary());
+        match rmp_serde::from_slice(&frame.into_data()).unwrap() {
+            OutgoingMessage::Offer {
+                target_client_id, ..
+            } => assert_eq!(target_client_id, "c1"),
+            message => panic!("expected an offer, got {:?}", message),
+        }
+    }
+}
@@ -1,45 +1,99 @@
+use crate::backend::{
+    Audience, Backend, Envelope, Heartbeat, MemoryBackend, PostgresBackend, HEARTBEAT_INTERVAL,
+};
+use crate::config::{BackendKind, Config};
 use crate::error::{AppError, AppResult};
+use crate::ice::{IceConfig, IceServer};
 use crate::message::{ClientInfoMsg, OutgoingMessage};
-use futures::{stream::SplitSink, SinkExt};
-use serde::Serialize;
+use crate::metrics::Metrics;
+use crate::outbound::ClientSender;
+use crate::protocol::{Encoding, Protocol};
+use crate::recording::Recording;
+use crate::role::{Permissions, Role};
+use crate::session::SessionStore;
+use crate::sfu::{Sfu, SFU_CLIENT_ID};
+use crate::turn::TurnCredentials;
+use crate::typing::{TypingStart, TypingStore};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use specta::Type;
-use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use talky_data::database::create_connection;
-use talky_services::channel::service::ChannelService;
+use talky_services::channel::service::{ChannelResource, ChannelService};
+use talky_services::conversation::service::{ConversationService, MAX_DIRECT_MESSAGE_CHARS};
 use talky_services::lobby::service::{LobbyResource, LobbyService};
-use talky_services::message::service::{AddChatMessageArgs, MessageResource, MessageService};
+use talky_services::message::service::{
+    AddChatMessageArgs, MessageResource, MessageService, MAX_CHAT_MESSAGE_CHARS,
+};
 use talky_services::niche::service::NicheService;
+use talky_services::recording::service::RecordingService;
+use talky_services::user::service::{UserProfileResource, UserService, UserStatus};
 use talky_services::DatabasePool;
-use tokio::sync::{Mutex, MutexGuard};
-use warp::ws::{Message, WebSocket};
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use warp::ws::Message;
 
-pub type ClientSender = Arc<Mutex<SplitSink<WebSocket, Message>>>;
+// How long a new `ExpireWhenEmpty` lobby may wait for its first client
+const UNUSED_LOBBY_MIN_AGE_SECS: i32 = 300;
 
-#[derive(Clone, Debug)]
+// Enough for emoji built from several code points, like flags and families
+pub const MAX_REACTION_CHARS: usize = 16;
+
+pub const MAX_CUSTOM_STATUS_CHARS: usize = 128;
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct RoomClientInfo {
-    pub client: ClientInfo,
-    pub role: String,
+    pub client_id: String,
+    pub user: UserResource,
+    pub role: Role,
+    #[serde(default)]
+    pub server_muted: bool,
+    #[serde(default)]
+    pub server_deafened: bool,
+    #[serde(default)]
+    pub self_muted: bool,
+    #[serde(default)]
+    pub self_deafened: bool,
+    #[serde(default)]
+    pub speaking: bool,
 }
 
 impl RoomClientInfo {
     pub fn get_resource(&self) -> UserRoomResource {
         UserRoomResource {
-            user: self.client.resource.clone(),
-            role: self.role.clone(),
+            client_id: self.client_id.clone(),
+            user: self.user.clone(),
+            role: self.role,
+            server_muted: self.server_muted,
+            server_deafened: self.server_deafened,
+            self_muted: self.self_muted,
+            self_deafened: self.self_deafened,
+            speaking: self.speaking,
         }
     }
 }
 
-#[derive(Type, Serialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Type, Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub struct UserRoomResource {
+    pub client_id: String,
     pub user: UserResource,
-    pub role: String,
+    pub role: Role,
+    pub server_muted: bool,
+    pub server_deafened: bool,
+    pub self_muted: bool,
+    pub self_deafened: bool,
+    pub speaking: bool,
 }
 
-#[derive(Type, Serialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Type, Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub struct UserResource {
     pub user_id: String,
@@ -50,161 +104,632 @@ pub struct ClientInfo {
     pub id: String,
     pub sender: ClientSender,
     pub current_niche_id: Option<String>,
+    pub current_channel_id: Option<String>,
+    /// Niches the user is a member of, whose members see its presence.
+    pub niche_ids: Vec<String>,
     pub resource: UserResource,
+    pub membership: Option<Membership>,
+    pub protocol: Protocol,
+}
+
+/// What the admin API shows of a connected client. Clients connected to
+/// other instances only carry their user.
+#[derive(Serialize, Debug, Clone)]
+pub struct ClientSummary {
+    pub client_id: String,
+    #[serde(flatten)]
+    pub user: ClientInfoMsg,
+    pub local: bool,
+    pub current_niche_id: Option<String>,
+    pub current_channel_id: Option<String>,
+    pub lobby_id: Option<String>,
+    pub role: Option<Role>,
+}
+
+/// The lobby a client is in, what it may do there and what it set for
+/// itself, enough to put it back into the room.
+#[derive(Clone, Debug)]
+pub struct Membership {
+    pub lobby_id: String,
+    pub niche_id: String,
+    pub channel_id: String,
+    pub role: Role,
+    pub self_muted: bool,
+    pub self_deafened: bool,
 }
 
 impl ClientInfo {
-    pub async fn send(&self, message: &OutgoingMessage) -> AppResult<()> {
+    /// Queues the message in the client's encoding.
+    pub fn send(&self, message: &OutgoingMessage) -> AppResult<()> {
         self.sender
-            .lock()
-            .await
-            .send(
-                message
-                    .to_ws_message()
-                    .map_err(|e| AppError::Anyhow(anyhow::Error::msg(e)))?,
-            )
-            .await?;
-
-        Ok(())
+            .send(message.to_ws_message(self.protocol.encoding())?)
     }
 }
 
 type UserId = String;
-type NicheId = String;
-type ClientId = String;
-#[derive(Type, Serialize, Debug, Clone)]
+#[derive(Type, Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub struct RoomResource {
     users: HashMap<UserId, Vec<UserRoomResource>>,
 }
 impl RoomResource {
-    async fn generate_active_lobbies_message(rooms: &HashMap<NicheId, Room>) -> OutgoingMessage {
-        let mut channels = HashMap::new();
-        for (channel_id, room) in rooms.iter() {
-            channels.insert(channel_id.clone(), room.to_resource().await);
+    pub fn from_clients<'a>(clients: impl Iterator<Item = &'a RoomClientInfo>) -> Self {
+        let mut users: HashMap<String, Vec<UserRoomResource>> = HashMap::new();
+        for client_info in clients {
+            let user_id = client_info.user.user_id.clone();
+            users
+                .entry(user_id)
+                .or_default()
+                .push(client_info.get_resource());
         }
 
-        OutgoingMessage::ActiveChannels { channels }
+        RoomResource { users }
     }
 }
 
-#[derive(Debug)]
-pub struct Room {
-    clients: Arc<Mutex<HashMap<ClientId, RoomClientInfo>>>,
-    channel: LobbyResource,
+#[derive(Clone)]
+pub struct AppState {
+    // Sockets accepted by this instance
+    clients: Arc<DashMap<String, ClientInfo>>,
+    backend: Arc<dyn Backend>,
+    connection: DatabasePool,
+    sfu: Sfu,
+    sessions: SessionStore,
+    typing: TypingStore,
+    metrics: Metrics,
+    // Set once a shutdown started, after which nobody may connect or join
+    draining: Arc<AtomicBool>,
+    config: Config,
 }
 
-impl Room {
-    fn new(channel: LobbyResource) -> Self {
-        let clients = Arc::new(Mutex::new(HashMap::new()));
+impl AppState {
+    pub async fn new(config: &Config) -> AppResult<Self> {
+        let connection = create_connection(&config.database_url).await;
+        Self::with_connection(config, connection).await
+    }
+
+    pub(crate) async fn with_connection(
+        config: &Config,
+        connection: DatabasePool,
+    ) -> AppResult<Self> {
+        let metrics = Metrics::new()?;
+        let backend: Arc<dyn Backend> = match config.backend {
+            BackendKind::Memory => Arc::new(MemoryBackend::new()),
+            BackendKind::Postgres => Arc::new(
+                PostgresBackend::new(
+                    connection.clone(),
+                    config.instance_id.clone(),
+                    metrics.clone(),
+                )
+                .await?,
+            ),
+        };
+
+        let state = AppState {
+            clients: Arc::new(DashMap::new()),
+            backend,
+            connection,
+            sfu: Sfu::new()?,
+            sessions: SessionStore::new(),
+            typing: TypingStore::new(),
+            metrics,
+            draining: Arc::new(AtomicBool::new(false)),
+            config: config.clone(),
+        };
+        state.spawn_fanout();
+        state.spawn_lobby_reaper();
+        state.spawn_heartbeat();
 
-        Self { clients, channel }
+        Ok(state)
     }
 
-    async fn add_client(&self, client: ClientInfo, role: String) {
-        let client_id = client.id.clone();
-        let room_client = RoomClientInfo { role, client };
-        self.clients.lock().await.insert(client_id, room_client);
+    pub fn config(&self) -> &Config {
+        &self.config
     }
 
-    pub fn get_channel(&self) -> &LobbyResource {
-        &self.channel
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
     }
 
-    pub async fn to_resource(&self) -> RoomResource {
-        let mut users: HashMap<String, Vec<UserRoomResource>> = HashMap::new();
-        for client_info in self.clients.lock().await.values() {
-            let user_id = client_info.get_resource().user.user_id.clone();
-            users
-                .entry(user_id)
-                .or_insert_with(Vec::new)
-                .push(client_info.get_resource());
+    /// Samples the gauges and renders every metric for a scrape.
+    pub async fn render_metrics(&self) -> AppResult<String> {
+        let connected_clients = self.clients.len();
+        let rooms = self.backend.room_counts().await?;
+        self.metrics.render(connected_clients, rooms)
+    }
+
+    /// Whether the database answers, which every instance needs for chat
+    /// and lobbies whatever its backend.
+    pub async fn is_ready(&self) -> bool {
+        if self.is_draining() {
+            return false;
         }
 
-        RoomResource { users }
+        let ping = sqlx::query("SELECT 1").execute(&*self.connection);
+        match self.metrics.time_db("ready", ping).await {
+            Ok(_) => true,
+            Err(e) => {
+                tracing::warn!("Database is not reachable: {}", e);
+                false
+            }
+        }
     }
 
-    pub async fn remove_client(&self, client_id: &str) {
-        self.clients.lock().await.remove(client_id);
-    }
-}
+    /// Delivers everything published through the backend to the matching
+    /// clients connected to this instance.
+    fn spawn_fanout(&self) {
+        let mut receiver = self.backend.subscribe();
+        let state = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let envelope = match receiver.recv().await {
+                    Ok(envelope) => envelope,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Fanout lagged, dropped {} messages", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let started_at = Instant::now();
+
+                // Encoded once per encoding the recipients use
+                let mut encoded: HashMap<Encoding, Message> = HashMap::new();
+
+                // Collect relevant clients so no shard stays locked while
+                // sending
+                let recipients: Vec<(ClientSender, String, Encoding)> = state
+                    .clients
+                    .iter()
+                    .filter(|client| envelope.audience.includes(client.value()))
+                    .map(|client| {
+                        (
+                            client.sender.clone(),
+                            client.id.clone(),
+                            client.protocol.encoding(),
+                        )
+                    })
+                    .collect();
+
+                for (sender, client_id, encoding) in recipients.iter() {
+                    if let Audience::Client { .. } = &envelope.audience {
+                        // Off the fanout loop, since moving a client joins
+                        // another room and publishes in turn
+                        let state = state.clone();
+                        let client_id = client_id.clone();
+                        let message = envelope.message.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = state.apply_to_local_client(&client_id, &message).await
+                            {
+                                tracing::error!(
+                                    "Failed to apply {:?} to client {}: {:?}",
+                                    message,
+                                    client_id,
+                                    e
+                                );
+                            }
+                        });
+                    }
 
-#[derive(Clone)]
-pub struct AppState {
-    clients: Arc<Mutex<HashMap<String, ClientInfo>>>,
-    // niche_id -> channel_id -> room
-    lobbies: Arc<Mutex<HashMap<String, HashMap<String, Room>>>>,
-    connection: DatabasePool,
-}
+                    let ws_message = match encoded.entry(*encoding) {
+                        Entry::Occupied(entry) => entry.get().clone(),
+                        Entry::Vacant(entry) => match envelope.message.to_ws_message(*encoding) {
+                            Ok(msg) => entry.insert(msg).clone(),
+                            Err(e) => {
+                                tracing::error!("Failed to serialize broadcast message: {}", e);
+                                continue;
+                            }
+                        },
+                    };
+
+                    if let Err(e) = sender.send(ws_message) {
+                        tracing::warn!(
+                            "Failed to queue broadcast message for client {}: {:?}",
+                            client_id,
+                            e
+                        );
+                    }
+                }
 
-impl AppState {
-    pub async fn new(database_url: &String) -> Self {
-        AppState {
-            clients: Arc::new(Mutex::new(HashMap::new())),
-            connection: create_connection(database_url).await,
-            lobbies: Arc::new(Mutex::new(HashMap::new())),
-        }
+                state.metrics.observe_fanout(started_at);
+            }
+        });
     }
 
-    pub async fn add_client(&self, client_info: ClientInfo) -> AppResult<()> {
+    pub async fn add_client(&self, mut client_info: ClientInfo) -> AppResult<()> {
+        let user_id = client_info.resource.user_id.clone();
+        client_info.niche_ids = self
+            .metrics
+            .time_db(
+                "niche.list_member_niche_ids",
+                NicheService::new(self.connection.clone()).list_member_niche_ids(&user_id),
+            )
+            .await?;
+        let profile = self
+            .metrics
+            .time_db(
+                "user.find_profile",
+                UserService::new(self.connection.clone()).find_profile(&user_id),
+            )
+            .await?;
+
+        self.backend
+            .add_client(&client_info.id, &client_info.resource)
+            .await?;
+
         self.clients
-            .lock()
-            .await
             .insert(client_info.id.clone(), client_info.clone());
 
-        self.broadcast_active_clients().await;
+        self.send_presence(&client_info).await?;
+        self.publish_presence(&user_id, &client_info.niche_ids, Some(profile))
+            .await;
 
         Ok(())
     }
 
+    pub async fn issue_resume_token(&self, client: &ClientInfo) -> String {
+        self.sessions.issue(client).await
+    }
+
+    /// The ICE servers the user should connect through. TURN servers come
+    /// with fresh credentials and are left out if this instance shares no
+    /// secret with coturn.
+    pub fn ice_config(&self, user_id: &str) -> IceConfig {
+        let mut ice_servers = Vec::new();
+        if !self.config.stun_urls.is_empty() {
+            ice_servers.push(IceServer {
+                urls: self.config.stun_urls.clone(),
+                username: None,
+                credential: None,
+            });
+        }
+        if let Some(secret) = self.config.turn_secret.as_deref() {
+            if !self.config.turn_urls.is_empty() {
+                let credentials =
+                    TurnCredentials::issue(secret, user_id, self.config.turn_credential_ttl);
+                ice_servers.push(IceServer {
+                    urls: self.config.turn_urls.clone(),
+                    username: Some(credentials.username),
+                    credential: Some(credentials.credential),
+                });
+            }
+        }
+
+        IceConfig {
+            ice_servers,
+            ice_transport_policy: self.config.ice_transport_policy,
+        }
+    }
+
+    /// Reattaches a reconnecting client to its suspended session by moving
+    /// the new socket into the session's sender. Presence, room membership
+    /// and the SFU peer connection are kept, so nobody else notices.
+    pub async fn resume_client(&self, token: &str, reconnected: &ClientInfo) -> Option<ClientInfo> {
+        let mut client = self
+            .sessions
+            .resume(token, &reconnected.resource.user_id)
+            .await?;
+        // The client may have reconnected with a newer build
+        client.protocol = reconnected.protocol.clone();
+
+        // Everything holding the session's sender now writes to the new socket
+        client.sender.take_socket(&reconnected.sender).await;
+
+        self.clients.insert(client.id.clone(), client.clone());
+        self.sfu.update_client(&client);
+
+        // Presence changes while suspended were not delivered
+        if let Err(e) = self.send_presence(&client).await {
+            tracing::error!("Failed to send presence to client {}: {:?}", client.id, e);
+        }
+
+        tracing::info!("Client {} resumed its session", client.id);
+
+        Some(client)
+    }
+
+    /// Keeps a client that dropped without closing around for the resume
+    /// grace window, and removes it for good if it does not come back.
+    pub async fn suspend_client(&self, client_id: &str) {
+        self.stop_typing(client_id, None).await;
+
+        let Some((_, client)) = self.clients.remove(client_id) else {
+            return;
+        };
+
+        let expires_at = Instant::now() + self.config.resume_grace;
+        if !self.sessions.suspend(client, expires_at).await {
+            self.remove_client(client_id).await;
+            return;
+        }
+
+        tracing::info!(
+            "Client {} suspended for {:?}",
+            client_id,
+            self.config.resume_grace
+        );
+
+        let state = self.clone();
+        let client_id = client_id.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(expires_at).await;
+            if state.sessions.expire(&client_id, expires_at).await {
+                tracing::info!("Session of client {} expired", client_id);
+                state.remove_client(&client_id).await;
+            }
+        });
+    }
+
     pub async fn remove_client(&self, client_id: &str) {
-        let niche_id = {
-            let mut clients_locked = self.clients.lock().await;
-            if let Some(client) = clients_locked.remove(client_id) {
-                client.current_niche_id.clone()
-            } else {
+        self.stop_typing(client_id, None).await;
+        self.clients.remove(client_id);
+        self.sessions.remove(client_id).await;
+
+        // Suspended clients are gone from `clients` already
+        let user_id = match self.backend.client(client_id).await {
+            Ok(user) => user.map(|user| user.user_id),
+            Err(e) => {
+                tracing::error!("Failed to find client {}: {:?}", client_id, e);
                 None
             }
         };
 
+        if let Err(e) = self.backend.remove_client(client_id).await {
+            tracing::error!(
+                "Failed to remove client {} from backend: {:?}",
+                client_id,
+                e
+            );
+        }
+
         self.remove_client_from_current_room(client_id).await;
 
-        self.broadcast_active_clients().await;
+        if let Some(user_id) = user_id {
+            self.publish_offline(&user_id).await;
+        }
     }
 
     pub async fn remove_client_from_current_room(&self, client_id: &str) {
-        let niche_keys: Vec<String> = {
-            let rooms_locked = self.lobbies.lock().await;
-            rooms_locked.keys().cloned().collect()
+        let left_rooms = match self.backend.leave_rooms(client_id).await {
+            Ok(left_rooms) => left_rooms,
+            Err(e) => {
+                tracing::error!("Failed to remove client {} from rooms: {:?}", client_id, e);
+                Vec::new()
+            }
         };
 
-        let mut niches_to_notify = Vec::new();
+        self.leave_sfu(client_id).await;
 
-        for niche_key in niche_keys.iter() {
-            let lock = self.lobbies.lock().await;
-            let lobby_map = lock.get(niche_key);
-
-            if let Some(lobby_map) = lobby_map {
-                let client_removed = {
-                    let mut client_removed = false;
-                    for room in lobby_map.values() {
-                        let mut clients_locked = room.clients.lock().await;
-                        if clients_locked.remove(client_id).is_some() {
-                            client_removed = true;
-                        }
-                    }
-                    client_removed
-                };
+        if let Some(mut client) = self.clients.get_mut(client_id) {
+            client.membership = None;
+        }
 
-                if client_removed {
-                    niches_to_notify.push(niche_key.clone());
+        let lobby_service = LobbyService::new(self.connection.clone());
+        let mut niches_to_notify = Vec::new();
+        for left_room in left_rooms.iter() {
+            if left_room.is_empty {
+                match self
+                    .metrics
+                    .time_db(
+                        "lobby.delete_if_expire_when_empty",
+                        lobby_service.delete_if_expire_when_empty(&left_room.lobby_id),
+                    )
+                    .await
+                {
+                    Ok(true) => {
+                        tracing::info!("Deleted empty lobby {}", left_room.lobby_id);
+                        self.forget_moderation(&left_room.lobby_id).await;
+                    }
+                    Ok(false) => {}
+                    Err(e) => tracing::error!(
+                        "Failed to delete empty lobby {}: {:?}",
+                        left_room.lobby_id,
+                        e
+                    ),
                 }
             }
+
+            if !niches_to_notify.contains(&left_room.niche_id) {
+                niches_to_notify.push(left_room.niche_id.clone());
+            }
         }
 
         for niche_key in niches_to_notify.iter() {
             self.broadcast_niche_clients(niche_key).await;
-            // we should really clean up empty "rooms"
+        }
+    }
+
+    /// Periodically deletes temporary lobbies whose contract has run out.
+    fn spawn_lobby_reaper(&self) {
+        let state = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(state.config.lobby_reaper_interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = state.reap_lobbies().await {
+                    tracing::error!("Failed to reap temporary lobbies: {:?}", e);
+                }
+            }
+        });
+    }
+
+    async fn reap_lobbies(&self) -> AppResult<()> {
+        let lobby_service = LobbyService::new(self.connection.clone());
+
+        for lobby in self
+            .metrics
+            .time_db("lobby.delete_expired", lobby_service.delete_expired())
+            .await?
+        {
+            tracing::info!("Lobby {} expired", lobby.id);
+            self.close_lobby(&lobby).await?;
+        }
+
+        // Lobbies that were never joined, or whose last client left while no
+        // instance was around to notice
+        for lobby in self
+            .metrics
+            .time_db(
+                "lobby.list_expire_when_empty",
+                lobby_service.list_expire_when_empty(UNUSED_LOBBY_MIN_AGE_SECS),
+            )
+            .await?
+        {
+            if self.backend.room_clients(&lobby.id).await?.is_empty()
+                && self
+                    .metrics
+                    .time_db(
+                        "lobby.delete_if_expire_when_empty",
+                        lobby_service.delete_if_expire_when_empty(&lobby.id),
+                    )
+                    .await?
+            {
+                tracing::info!("Deleted unused lobby {}", lobby.id);
+                self.forget_moderation(&lobby.id).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn spawn_heartbeat(&self) {
+        let state = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                match state.backend.heartbeat().await {
+                    Ok(heartbeat) => state.apply_heartbeat(heartbeat).await,
+                    Err(e) => tracing::error!("Failed to record heartbeat: {:?}", e),
+                }
+            }
+        });
+    }
+
+    /// Tells the niches about clients that went away with a dead instance,
+    /// and puts this instance's clients back if it was taken for dead.
+    async fn apply_heartbeat(&self, heartbeat: Heartbeat) {
+        let mut niche_ids = heartbeat.reaped_niche_ids;
+        if heartbeat.was_reaped {
+            tracing::warn!("Instance was taken for dead, registering its clients again");
+            for niche_id in self.register_local_clients().await {
+                if !niche_ids.contains(&niche_id) {
+                    niche_ids.push(niche_id);
+                }
+            }
+        }
+
+        for niche_id in niche_ids.iter() {
+            self.broadcast_niche_clients(niche_id).await;
+        }
+        for user_id in heartbeat.reaped_user_ids.iter() {
+            self.publish_offline(user_id).await;
+        }
+    }
+
+    /// Adds the connected clients and their room entries to the backend
+    /// again, returning the niches whose rooms they are back in. Suspended
+    /// clients are let go instead.
+    async fn register_local_clients(&self) -> Vec<String> {
+        for client_id in self.sessions.suspended_clients().await {
+            self.remove_client(&client_id).await;
+        }
+
+        let mut niche_ids = Vec::new();
+        for client in self.local_clients() {
+            match self.register_local_client(&client).await {
+                Ok(Some(niche_id)) if !niche_ids.contains(&niche_id) => niche_ids.push(niche_id),
+                Ok(_) => {}
+                Err(e) => tracing::error!("Failed to register client {}: {:?}", client.id, e),
+            }
+        }
+
+        niche_ids
+    }
+
+    async fn register_local_client(&self, client: &ClientInfo) -> AppResult<Option<String>> {
+        let user_id = &client.resource.user_id;
+        self.backend
+            .add_client(&client.id, &client.resource)
+            .await?;
+        let profile = self
+            .metrics
+            .time_db(
+                "user.find_profile",
+                UserService::new(self.connection.clone()).find_profile(user_id),
+            )
+            .await?;
+        self.publish_presence(user_id, &client.niche_ids, Some(profile))
+            .await;
+
+        let Some(membership) = &client.membership else {
+            return Ok(None);
+        };
+        let lobby = self
+            .metrics
+            .time_db(
+                "lobby.find_by_id",
+                LobbyService::new(self.connection.clone()).find_by_id(membership.lobby_id.clone()),
+            )
+            .await?;
+        let moderation = self.backend.moderation(&lobby.id, user_id).await?;
+        let room_client = RoomClientInfo {
+            client_id: client.id.clone(),
+            user: client.resource.clone(),
+            role: membership.role,
+            server_muted: moderation.server_muted,
+            server_deafened: moderation.server_deafened,
+            self_muted: membership.self_muted,
+            self_deafened: membership.self_deafened,
+            speaking: false,
+        };
+        match self.backend.join_room(&lobby, room_client).await {
+            Ok(()) => Ok(Some(lobby.niche_id)),
+            Err(e @ AppError::RoomOnOtherInstance(_)) => {
+                // Someone joined the room on another instance while this one
+                // was taken for dead, and it can't be hosted here as well
+                self.remove_client_from_current_room(&client.id).await;
+                let _ = client.send(&OutgoingMessage::Error {
+                    message: e.to_string(),
+                });
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Empties the room of a deleted lobby and tells its clients.
+    async fn close_lobby(&self, lobby: &LobbyResource) -> AppResult<()> {
+        let message = OutgoingMessage::LobbyClosed {
+            channel_id: lobby.id.clone(),
+        };
+
+        for client_id in self.backend.close_room(&lobby.id).await? {
+            // Published rather than sent directly, so that the instance
+            // holding the client also drops its SFU peer
+            self.publish(
+                Audience::Client {
+                    client_id: client_id.clone(),
+                },
+                &message,
+            )
+            .await;
+        }
+
+        self.forget_moderation(&lobby.id).await;
+        self.broadcast_niche_clients(&lobby.niche_id).await;
+
+        Ok(())
+    }
+
+    /// Lifts every server mute and deafen in a lobby that was deleted or torn
+    /// down.
+    async fn forget_moderation(&self, lobby_id: &str) {
+        if let Err(e) = self.backend.clear_moderation(lobby_id).await {
+            tracing::error!("Failed to clear moderation of lobby {}: {:?}", lobby_id, e);
         }
     }
 
@@ -213,10 +738,24 @@ impl AppState {
         client_id: &str,
         message: &OutgoingMessage,
     ) -> AppResult<()> {
-        match self.clients.lock().await.get(client_id) {
-            Some(client) => client.send(message).await,
-            _ => Err(AppError::Anyhow(anyhow::anyhow!("Client not found."))),
+        let local_client = self.clients.get(client_id).map(|client| client.clone());
+        if let Some(client) = local_client {
+            return client.send(message);
+        }
+
+        // The client may be connected to another instance
+        if !self.backend.has_client(client_id).await? {
+            return Err(AppError::Anyhow(anyhow::anyhow!("Client not found.")));
         }
+
+        self.backend
+            .publish(Envelope {
+                audience: Audience::Client {
+                    client_id: client_id.to_string(),
+                },
+                message: message.clone(),
+            })
+            .await
     }
 
     pub async fn handle_webrtc_signal(
@@ -250,171 +789,1262 @@ impl AppState {
     }
 
     pub async fn update_niche(&self, client_id: &str, niche_id: &str) -> AppResult<()> {
-        self.clients
-            .lock()
-            .await
-            .get_mut(client_id)
-            .ok_or_else(|| AppError::Anyhow(anyhow::anyhow!("Client not found")))?
-            .current_niche_id = Some(niche_id.to_string());
+        let user_id = self
+            .clients
+            .get(client_id)
+            .map(|client| client.resource.user_id.clone())
+            .ok_or_else(|| AppError::Anyhow(anyhow::anyhow!("Client not found")))?;
+
+        // Memberships may have changed since the client connected
+        let is_member = self
+            .metrics
+            .time_db(
+                "niche.find_member_role",
+                NicheService::new(self.connection.clone()).find_member_role(niche_id, &user_id),
+            )
+            .await?
+            .is_some();
+
+        let niche_changed = {
+            let mut client = self
+                .clients
+                .get_mut(client_id)
+                .ok_or_else(|| AppError::Anyhow(anyhow::anyhow!("Client not found")))?;
+
+            let known_member = client.niche_ids.iter().any(|id| id == niche_id);
+            if is_member && !known_member {
+                client.niche_ids.push(niche_id.to_string());
+            } else if !is_member && known_member {
+                client.niche_ids.retain(|id| id != niche_id);
+            }
+            if !is_member {
+                return Err(AppError::PermissionDenied(format!(
+                    "view niche {} without being a member",
+                    niche_id
+                )));
+            }
+
+            let niche_changed = client.current_niche_id.as_deref() != Some(niche_id);
+            if niche_changed {
+                client.current_channel_id = None;
+            }
+            client.current_niche_id = Some(niche_id.to_string());
+
+            niche_changed
+        };
+
+        if niche_changed {
+            self.stop_typing(client_id, None).await;
+        }
 
-        if let Some(lobbies) = self.lobbies.lock().await.get(niche_id) {
-            let message = RoomResource::generate_active_lobbies_message(lobbies).await;
+        let channels = self.backend.rooms(niche_id).await?;
+        if !channels.is_empty() {
+            let message = OutgoingMessage::ActiveChannels { channels };
             self.send_to_client(client_id, &message).await?;
         }
 
         Ok(())
     }
 
-    pub async fn join(&self, client_id: &str, lobby_id: String, role: String) -> AppResult<()> {
-        tracing::info!("Client {} is attempting to join {}...", client_id, lobby_id);
-        let lobby_service = LobbyService::new(self.connection.clone());
-        let lobby = lobby_service
-            .find_by_id(lobby_id.clone())
-            .await
-            .map_err(AppError::from)?;
-
-        let client = self
-            .clients
-            .lock()
-            .await
-            .get(client_id)
-            .ok_or_else(|| AppError::Anyhow(anyhow::anyhow!("Client not found")))?
-            .clone();
+    /// Sets the text channel the client is looking at, which has to be in
+    /// the niche it is viewing.
+    pub async fn update_channel(
+        &self,
+        client_id: &str,
+        channel_id: Option<String>,
+    ) -> AppResult<()> {
+        if let Some(channel_id) = &channel_id {
+            self.authorize_channel(client_id, channel_id).await?;
+        }
 
-        self.remove_client_from_current_room(client_id).await;
+        let previous_channel_id = {
+            let mut client = self
+                .clients
+                .get_mut(client_id)
+                .ok_or_else(|| AppError::Anyhow(anyhow::anyhow!("Client not found")))?;
 
-        let lobby_niche_id = lobby.niche_id.clone();
-        let lobby_id = lobby.id.clone();
+            std::mem::replace(&mut client.current_channel_id, channel_id.clone())
+        };
 
-        self.update_niche(client_id, &lobby_niche_id).await?;
+        if previous_channel_id != channel_id {
+            self.stop_typing(client_id, None).await;
+        }
 
-        {
-            let mut lobbies = self.lobbies.lock().await;
-            let rooms = lobbies.entry(lobby_niche_id.clone()).or_default();
+        Ok(())
+    }
 
-            let room = rooms.entry(lobby_id.clone()).or_insert(Room::new(lobby));
+    /// Finds the channel and checks that the client is a member of its niche
+    /// and viewing it.
+    async fn authorize_channel(
+        &self,
+        client_id: &str,
+        channel_id: &str,
+    ) -> AppResult<(ClientInfo, ChannelResource)> {
+        let channel = self
+            .metrics
+            .time_db(
+                "channel.find_optional_by_id",
+                ChannelService::new(self.connection.clone())
+                    .find_optional_by_id(channel_id.to_string()),
+            )
+            .await?
+            .ok_or_else(|| AppError::ChannelNotFound(channel_id.to_string()))?;
 
-            room.add_client(client, role).await;
+        let client = self
+            .clients
+            .get(client_id)
+            .map(|client| client.clone())
+            .ok_or_else(|| AppError::Anyhow(anyhow::anyhow!("Client not found")))?;
+
+        if !client.niche_ids.contains(&channel.niche_id) {
+            return Err(AppError::PermissionDenied(format!(
+                "access channel {} without being a member of its niche",
+                channel_id
+            )));
+        }
+        if client.current_niche_id.as_ref() != Some(&channel.niche_id) {
+            return Err(AppError::PermissionDenied(format!(
+                "access channel {} outside of the current niche",
+                channel_id
+            )));
         }
 
-        tracing::info!(
-            "Added client with id {} to room with id {}. ",
-            client_id,
-            lobby_id
-        );
-
-        self.broadcast_niche_clients(&lobby_niche_id).await;
-
-        Ok(())
+        Ok((client, channel))
     }
 
-    pub async fn handle_chat_message(
-        &self,
-        sender_id: &str,
-        channel_id: String,
-        content: String,
-    ) -> AppResult<()> {
-        let user_id = {
-            self.clients
-                .lock()
-                .await
-                .get(sender_id)
-                .and_then(|s| Some(s.resource.user_id.clone()))
+    pub async fn start_typing(&self, client_id: &str, channel_id: String) -> AppResult<()> {
+        let (user_id, current_channel_id) = {
+            let client = self
+                .clients
+                .get(client_id)
+                .ok_or_else(|| AppError::Anyhow(anyhow::anyhow!("Client not found")))?;
+
+            (
+                client.resource.user_id.clone(),
+                client.current_channel_id.clone(),
+            )
         };
 
-        if let Some(user_id) = user_id {
-            let message_service = MessageService::new(self.connection.clone());
-            let message = message_service
-                .add_chat_message(AddChatMessageArgs {
-                    user_id,
-                    channel_id: channel_id.clone(),
-                    contents: content,
-                })
-                .await;
+        if current_channel_id.as_ref() != Some(&channel_id) {
+            return Err(AppError::PermissionDenied(format!(
+                "type in channel {} without viewing it",
+                channel_id
+            )));
+        }
+
+        let expires_at = Instant::now() + self.config.typing_timeout;
+        let start = self
+            .typing
+            .start(
+                client_id,
+                &channel_id,
+                expires_at,
+                self.config.typing_throttle,
+            )
+            .await;
+
+        if start == TypingStart::Started {
+            self.spawn_typing_expiry(client_id);
+        }
 
-            let broadcast_message = OutgoingMessage::ChatMessageBroadcast {
-                sender_id: (*sender_id).to_string(),
+        if start != TypingStart::Refreshed {
+            let message = OutgoingMessage::Typing {
                 channel_id: channel_id.clone(),
-                message,
+                client_id: client_id.to_string(),
+                user_id,
+                typing: true,
             };
-
-            self.broadcast_all(&broadcast_message).await;
+            self.broadcast_channel(&channel_id, Some(client_id), &message)
+                .await;
         }
+
         Ok(())
     }
 
-    async fn get_client_info_msgs(&self) -> Vec<ClientInfoMsg> {
-        self.clients
-            .lock()
-            .await
-            .values()
-            .map(|c| ClientInfoMsg {
-                user_id: c.resource.user_id.to_string(),
-            })
-            .collect()
+    /// Stops the client typing, optionally only if it was typing in
+    /// `channel_id`, and tells the channel.
+    pub async fn stop_typing(&self, client_id: &str, channel_id: Option<&str>) {
+        if let Some(channel_id) = channel_id {
+            if self.typing.channel(client_id).await.as_deref() != Some(channel_id) {
+                return;
+            }
+        }
+
+        if let Some(channel_id) = self.typing.stop(client_id).await {
+            self.broadcast_typing_stopped(client_id, &channel_id).await;
+        }
+    }
+
+    /// Stops the client typing once it stops sending notices.
+    fn spawn_typing_expiry(&self, client_id: &str) {
+        let state = self.clone();
+        let client_id = client_id.to_string();
+
+        tokio::spawn(async move {
+            while let Some(expires_at) = state.typing.expires_at(&client_id).await {
+                tokio::time::sleep_until(expires_at).await;
+                if let Some(channel_id) = state.typing.expire(&client_id).await {
+                    state
+                        .broadcast_typing_stopped(&client_id, &channel_id)
+                        .await;
+                    break;
+                }
+            }
+        });
+    }
+
+    async fn broadcast_typing_stopped(&self, client_id: &str, channel_id: &str) {
+        let Some(user_id) = self
+            .clients
+            .get(client_id)
+            .map(|client| client.resource.user_id.clone())
+        else {
+            return;
+        };
+
+        let message = OutgoingMessage::Typing {
+            channel_id: channel_id.to_string(),
+            client_id: client_id.to_string(),
+            user_id,
+            typing: false,
+        };
+        self.broadcast_channel(channel_id, Some(client_id), &message)
+            .await;
+    }
+
+    pub async fn join(&self, client_id: &str, lobby_id: String, role: Role) -> AppResult<()> {
+        tracing::info!("Client {} is attempting to join {}...", client_id, lobby_id);
+        if self.is_draining() {
+            return Err(AppError::ShuttingDown);
+        }
+        let lobby_service = LobbyService::new(self.connection.clone());
+        let lobby = self
+            .metrics
+            .time_db(
+                "lobby.find_by_id",
+                lobby_service.find_by_id(lobby_id.clone()),
+            )
+            .await
+            .map_err(AppError::from)?;
+
+        let client = self
+            .clients
+            .get(client_id)
+            .map(|client| client.clone())
+            .ok_or_else(|| AppError::Anyhow(anyhow::anyhow!("Client not found")))?;
+
+        let niche_role = self
+            .metrics
+            .time_db(
+                "niche.find_member_role",
+                NicheService::new(self.connection.clone())
+                    .find_member_role(&lobby.niche_id, &client.resource.user_id),
+            )
+            .await?
+            .ok_or_else(|| {
+                AppError::PermissionDenied(format!(
+                    "join lobby {} without being a member of its niche",
+                    lobby.id
+                ))
+            })?;
+        let role = role.authorize(&lobby, &client.resource.user_id, niche_role)?;
+
+        self.remove_client_from_current_room(client_id).await;
+
+        let lobby_niche_id = lobby.niche_id.clone();
+        let lobby_id = lobby.id.clone();
+
+        self.update_niche(client_id, &lobby_niche_id).await?;
+
+        // Leaving does not lift what moderators imposed
+        let moderation = self
+            .backend
+            .moderation(&lobby_id, &client.resource.user_id)
+            .await?;
+
+        let room_client = RoomClientInfo {
+            client_id: client.id.clone(),
+            user: client.resource.clone(),
+            role,
+            server_muted: moderation.server_muted,
+            server_deafened: moderation.server_deafened,
+            self_muted: false,
+            self_deafened: false,
+            speaking: false,
+        };
+        self.backend.join_room(&lobby, room_client).await?;
+
+        if let Some(mut client) = self.clients.get_mut(client_id) {
+            client.membership = Some(Membership {
+                lobby_id: lobby.id.clone(),
+                niche_id: lobby.niche_id.clone(),
+                channel_id: lobby.channel_id.clone(),
+                role,
+                self_muted: false,
+                self_deafened: false,
+            });
+        }
+
+        self.sfu
+            .join(client, &lobby_id, role.permissions().send_media, moderation)
+            .await?;
+
+        if let Some(recording) = self.sfu.recording(&lobby_id) {
+            self.send_to_client(client_id, &recording_message(&recording, true))
+                .await?;
+        }
+
+        tracing::info!(
+            "Added client with id {} to room with id {}. ",
+            client_id,
+            lobby_id
+        );
+
+        self.broadcast_niche_clients(&lobby_niche_id).await;
+
+        Ok(())
+    }
+
+    /// Carries out what a message addressed to one of this instance's clients
+    /// asks of the server, like leaving the room it was kicked from.
+    async fn apply_to_local_client(
+        &self,
+        client_id: &str,
+        message: &OutgoingMessage,
+    ) -> AppResult<()> {
+        if let OutgoingMessage::Disconnected { .. } = message {
+            self.disconnect_local_client(client_id).await;
+            return Ok(());
+        }
+
+        let lobby_id = match message {
+            OutgoingMessage::LobbyClosed { channel_id }
+            | OutgoingMessage::Kicked { channel_id, .. }
+            | OutgoingMessage::ServerMuted { channel_id, .. }
+            | OutgoingMessage::ServerDeafened { channel_id, .. }
+            | OutgoingMessage::Moved { channel_id, .. } => channel_id,
+            _ => return Ok(()),
+        };
+
+        // The client may have left on its own in the meantime
+        let Some(membership) = self.membership(client_id).await else {
+            return Ok(());
+        };
+        if &membership.lobby_id != lobby_id {
+            return Ok(());
+        }
+
+        match message {
+            OutgoingMessage::LobbyClosed { .. } => {
+                self.leave_sfu(client_id).await;
+                if let Some(mut client) = self.clients.get_mut(client_id) {
+                    client.membership = None;
+                }
+            }
+            OutgoingMessage::Kicked { .. } => {
+                self.remove_client_from_current_room(client_id).await;
+            }
+            OutgoingMessage::ServerMuted { muted, .. } => {
+                self.sfu.set_muted(client_id, *muted).await;
+                self.update_room_client(client_id, |room_client| {
+                    room_client.server_muted = *muted;
+                    room_client.speaking &= !*muted;
+                })
+                .await?;
+            }
+            OutgoingMessage::ServerDeafened { deafened, .. } => {
+                self.sfu.set_deafened(client_id, *deafened).await?;
+                self.update_room_client(client_id, |room_client| {
+                    room_client.server_deafened = *deafened
+                })
+                .await?;
+            }
+            OutgoingMessage::Moved {
+                destination_channel_id,
+                ..
+            } => {
+                // Moderators are not necessarily moderators of the
+                // destination, and fall back to speaking there
+                match self
+                    .join(client_id, destination_channel_id.clone(), membership.role)
+                    .await
+                {
+                    Err(AppError::PermissionDenied(_)) => {
+                        self.join(client_id, destination_channel_id.clone(), Role::Speaker)
+                            .await?
+                    }
+                    result => result?,
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Changes the client's entry in its room and sends the niche the new
+    /// state, if anything changed.
+    async fn update_room_client(
+        &self,
+        client_id: &str,
+        update: impl FnOnce(&mut RoomClientInfo),
+    ) -> AppResult<()> {
+        let Some(membership) = self.membership(client_id).await else {
+            return Ok(());
+        };
+
+        let Some(room_client) = self
+            .backend
+            .room_clients(&membership.lobby_id)
+            .await?
+            .into_iter()
+            .find(|room_client| room_client.client_id == client_id)
+        else {
+            return Ok(());
+        };
+
+        let mut updated = room_client.clone();
+        update(&mut updated);
+        if updated == room_client
+            || !self
+                .backend
+                .update_room_client(&membership.lobby_id, updated.clone())
+                .await?
+        {
+            return Ok(());
+        }
+
+        if let Some(mut client) = self.clients.get_mut(client_id) {
+            if let Some(membership) = client.membership.as_mut() {
+                membership.self_muted = updated.self_muted;
+                membership.self_deafened = updated.self_deafened;
+            }
+        }
+
+        let message = OutgoingMessage::RoomClientUpdate {
+            channel_id: membership.lobby_id.clone(),
+            client: updated.get_resource(),
+        };
+        self.broadcast_niche(&membership.niche_id, None, &message)
+            .await;
+
+        Ok(())
+    }
+
+    pub async fn set_self_muted(&self, client_id: &str, muted: bool) -> AppResult<()> {
+        self.update_room_client(client_id, |room_client| {
+            room_client.self_muted = muted;
+            room_client.speaking &= !muted;
+        })
+        .await
+    }
+
+    pub async fn set_self_deafened(&self, client_id: &str, deafened: bool) -> AppResult<()> {
+        self.update_room_client(client_id, |room_client| {
+            room_client.self_deafened = deafened
+        })
+        .await
+    }
+
+    pub async fn set_speaking(&self, client_id: &str, speaking: bool) -> AppResult<()> {
+        self.update_room_client(client_id, |room_client| {
+            // Muted clients can not be heard, whatever their microphone picks up
+            room_client.speaking = speaking && !room_client.self_muted && !room_client.server_muted;
+        })
+        .await
+    }
+
+    /// Checks that the client may moderate the target in the lobby, which
+    /// takes the moderator role in the room, moderating the niche, or owning
+    /// the lobby. Only the owner may act on the owner.
+    async fn authorize_moderation(
+        &self,
+        moderator_id: &str,
+        lobby_id: &str,
+        target_client_id: &str,
+    ) -> AppResult<(LobbyResource, RoomClientInfo)> {
+        let lobby = self
+            .metrics
+            .time_db(
+                "lobby.find_by_id",
+                LobbyService::new(self.connection.clone()).find_by_id(lobby_id.to_string()),
+            )
+            .await?;
+
+        let user_id = self
+            .clients
+            .get(moderator_id)
+            .map(|client| client.resource.user_id.clone())
+            .ok_or_else(|| AppError::Anyhow(anyhow::anyhow!("Client not found")))?;
+
+        let room_clients = self.backend.room_clients(lobby_id).await?;
+        let target = room_clients
+            .iter()
+            .find(|room_client| room_client.client_id == target_client_id)
+            .cloned()
+            .ok_or_else(|| {
+                AppError::NotInRoom(target_client_id.to_string(), lobby_id.to_string())
+            })?;
+
+        let is_owner = lobby.owner_user_id == user_id;
+        let is_room_moderator = room_clients.iter().any(|room_client| {
+            room_client.client_id == moderator_id && room_client.role.permissions().moderate
+        });
+        self.ensure_lobby_moderator(&lobby, &user_id, is_room_moderator)
+            .await?;
+
+        if target.user.user_id == lobby.owner_user_id && !is_owner {
+            return Err(AppError::PermissionDenied(format!(
+                "moderate the owner of lobby {}",
+                lobby.id
+            )));
+        }
+
+        Ok((lobby, target))
+    }
+
+    /// Checks that the user may moderate the lobby, which takes the moderator
+    /// role in its room, moderating the niche, or owning the lobby.
+    async fn ensure_lobby_moderator(
+        &self,
+        lobby: &LobbyResource,
+        user_id: &str,
+        is_room_moderator: bool,
+    ) -> AppResult<()> {
+        if lobby.owner_user_id == user_id || is_room_moderator {
+            return Ok(());
+        }
+
+        let niche_role = self
+            .metrics
+            .time_db(
+                "niche.find_member_role",
+                NicheService::new(self.connection.clone())
+                    .find_member_role(&lobby.niche_id, user_id),
+            )
+            .await?;
+        if !niche_role.is_some_and(|role| role.can_moderate()) {
+            return Err(AppError::PermissionDenied(format!(
+                "moderate lobby {}",
+                lobby.id
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Sends a moderation action to its target, whose instance carries it
+    /// out, and lets the rest of the niche know.
+    async fn publish_moderation(
+        &self,
+        lobby: &LobbyResource,
+        target_client_id: &str,
+        message: &OutgoingMessage,
+    ) {
+        self.publish(
+            Audience::Client {
+                client_id: target_client_id.to_string(),
+            },
+            message,
+        )
+        .await;
+        self.broadcast_niche(&lobby.niche_id, Some(target_client_id), message)
+            .await;
+    }
+
+    pub async fn kick(
+        &self,
+        moderator_id: &str,
+        channel_id: String,
+        target_client_id: String,
+    ) -> AppResult<()> {
+        let (lobby, _) = self
+            .authorize_moderation(moderator_id, &channel_id, &target_client_id)
+            .await?;
+
+        tracing::info!(
+            "Client {} kicked {} from {}",
+            moderator_id,
+            target_client_id,
+            channel_id
+        );
+
+        let message = OutgoingMessage::Kicked {
+            channel_id,
+            client_id: target_client_id.clone(),
+            moderator_client_id: moderator_id.to_string(),
+        };
+        self.publish_moderation(&lobby, &target_client_id, &message)
+            .await;
+
+        Ok(())
+    }
+
+    pub async fn server_mute(
+        &self,
+        moderator_id: &str,
+        channel_id: String,
+        target_client_id: String,
+        muted: bool,
+    ) -> AppResult<()> {
+        let (lobby, target) = self
+            .authorize_moderation(moderator_id, &channel_id, &target_client_id)
+            .await?;
+        self.backend
+            .update_moderation(&lobby.id, &target.user.user_id, Some(muted), None)
+            .await?;
+
+        let message = OutgoingMessage::ServerMuted {
+            channel_id,
+            client_id: target_client_id.clone(),
+            moderator_client_id: moderator_id.to_string(),
+            muted,
+        };
+        self.publish_moderation(&lobby, &target_client_id, &message)
+            .await;
+
+        Ok(())
+    }
+
+    pub async fn server_deafen(
+        &self,
+        moderator_id: &str,
+        channel_id: String,
+        target_client_id: String,
+        deafened: bool,
+    ) -> AppResult<()> {
+        let (lobby, target) = self
+            .authorize_moderation(moderator_id, &channel_id, &target_client_id)
+            .await?;
+        self.backend
+            .update_moderation(&lobby.id, &target.user.user_id, None, Some(deafened))
+            .await?;
+
+        let message = OutgoingMessage::ServerDeafened {
+            channel_id,
+            client_id: target_client_id.clone(),
+            moderator_client_id: moderator_id.to_string(),
+            deafened,
+        };
+        self.publish_moderation(&lobby, &target_client_id, &message)
+            .await;
+
+        Ok(())
+    }
+
+    pub async fn move_client(
+        &self,
+        moderator_id: &str,
+        channel_id: String,
+        target_client_id: String,
+        destination_channel_id: String,
+    ) -> AppResult<()> {
+        let (lobby, _) = self
+            .authorize_moderation(moderator_id, &channel_id, &target_client_id)
+            .await?;
+
+        let destination = self
+            .metrics
+            .time_db(
+                "lobby.find_by_id",
+                LobbyService::new(self.connection.clone())
+                    .find_by_id(destination_channel_id.clone()),
+            )
+            .await?;
+        if destination.niche_id != lobby.niche_id || destination.id == lobby.id {
+            return Err(AppError::PermissionDenied(format!(
+                "move clients from lobby {} to lobby {}",
+                lobby.id, destination.id
+            )));
+        }
+
+        tracing::info!(
+            "Client {} moved {} from {} to {}",
+            moderator_id,
+            target_client_id,
+            channel_id,
+            destination_channel_id
+        );
+
+        let message = OutgoingMessage::Moved {
+            channel_id,
+            destination_channel_id,
+            client_id: target_client_id.clone(),
+            moderator_client_id: moderator_id.to_string(),
+        };
+        self.publish_moderation(&lobby, &target_client_id, &message)
+            .await;
+
+        Ok(())
+    }
+
+    /// Starts or stops recording the client's room. The SFU of this instance
+    /// makes the recording, so it only hears the clients connected here.
+    pub async fn set_recording(
+        &self,
+        client_id: &str,
+        channel_id: String,
+        recording: bool,
+    ) -> AppResult<()> {
+        let membership = self
+            .membership(client_id)
+            .await
+            .filter(|membership| membership.lobby_id == channel_id)
+            .ok_or_else(|| AppError::NotInRoom(client_id.to_string(), channel_id.clone()))?;
+        let lobby = self
+            .metrics
+            .time_db(
+                "lobby.find_by_id",
+                LobbyService::new(self.connection.clone()).find_by_id(channel_id.clone()),
+            )
+            .await?;
+        let user_id = self.user_id(client_id).await?;
+        self.ensure_lobby_moderator(&lobby, &user_id, membership.role.permissions().moderate)
+            .await?;
+
+        if !recording {
+            let recording = self
+                .sfu
+                .stop_recording(&lobby.id)
+                .await
+                .ok_or_else(|| AppError::NotRecording(lobby.id.clone()))?;
+            tracing::info!("Client {} stopped recording {}", client_id, lobby.id);
+            self.finish_recording(&recording).await;
+            return Ok(());
+        }
+
+        if self.sfu.recording(&lobby.id).is_some() {
+            return Err(AppError::AlreadyRecording(lobby.id.clone()));
+        }
+
+        let recording_service = RecordingService::new(self.connection.clone());
+        let resource = self
+            .metrics
+            .time_db(
+                "recording.create",
+                recording_service.create(&lobby.id, &user_id),
+            )
+            .await?;
+        let recording = Recording::create(
+            resource.id.clone(),
+            lobby.id.clone(),
+            lobby.niche_id.clone(),
+            user_id,
+            &self.config.recordings_dir,
+        )?;
+        let message = recording_message(&recording, true);
+
+        // Another moderator may have been quicker
+        if let Err(e) = self.sfu.start_recording(&lobby.id, recording) {
+            self.metrics
+                .time_db("recording.stop", recording_service.stop(&resource.id))
+                .await?;
+            return Err(e);
+        }
+
+        tracing::info!("Client {} started recording {}", client_id, lobby.id);
+        self.broadcast_niche(&lobby.niche_id, None, &message).await;
+
+        Ok(())
+    }
+
+    /// Drops the client's SFU peer, finishing the room's recording if nobody
+    /// is left in it to record.
+    async fn leave_sfu(&self, client_id: &str) {
+        if let Some(recording) = self.sfu.leave(client_id).await {
+            tracing::info!("Recording {} ended with its room", recording.id());
+            self.finish_recording(&recording).await;
+        }
+    }
+
+    /// Marks a recording the SFU has stopped as done and tells the niche.
+    async fn finish_recording(&self, recording: &Recording) {
+        if let Err(e) = self
+            .metrics
+            .time_db(
+                "recording.stop",
+                RecordingService::new(self.connection.clone()).stop(recording.id()),
+            )
+            .await
+        {
+            tracing::error!("Failed to stop recording {}: {:?}", recording.id(), e);
+        }
+
+        self.broadcast_niche(
+            recording.niche_id(),
+            None,
+            &recording_message(recording, false),
+        )
+        .await;
+    }
+
+    /// Stores a chat message and delivers it to the clients viewing the
+    /// channel's niche. Senders have to be viewing that niche themselves.
+    pub async fn handle_chat_message(
+        &self,
+        sender_id: &str,
+        channel_id: String,
+        content: String,
+    ) -> AppResult<()> {
+        if content.chars().count() > MAX_CHAT_MESSAGE_CHARS {
+            return Err(AppError::TooLong(
+                "content".to_string(),
+                MAX_CHAT_MESSAGE_CHARS,
+            ));
+        }
+
+        let (sender, channel) = self.authorize_channel(sender_id, &channel_id).await?;
+
+        // Listeners stay quiet in the text channel of the lobby they are in
+        if let Some(membership) = &sender.membership {
+            if membership.channel_id == channel_id && !membership.role.permissions().send_chat {
+                return Err(AppError::PermissionDenied(format!(
+                    "chat as {:?} in channel {}",
+                    membership.role, channel_id
+                )));
+            }
+        }
+
+        self.stop_typing(sender_id, Some(&channel_id)).await;
+
+        let message_service = MessageService::new(self.connection.clone());
+        let message = self
+            .metrics
+            .time_db(
+                "message.add_chat_message",
+                message_service.add_chat_message(AddChatMessageArgs {
+                    user_id: sender.resource.user_id.clone(),
+                    channel_id: channel_id.clone(),
+                    contents: content,
+                }),
+            )
+            .await;
+
+        let broadcast_message = OutgoingMessage::ChatMessageBroadcast {
+            sender_id: (*sender_id).to_string(),
+            channel_id,
+            message,
+        };
+
+        self.broadcast_niche(&channel.niche_id, None, &broadcast_message)
+            .await;
+
+        Ok(())
+    }
+
+    /// Stores a message in one of the user's conversations and hands it to
+    /// the participants who are online. The others find it unread the next
+    /// time they list their conversations.
+    pub async fn send_direct_message(
+        &self,
+        sender_id: &str,
+        conversation_id: String,
+        content: String,
+    ) -> AppResult<()> {
+        if content.trim().is_empty() {
+            return Err(AppError::MissingField("content".to_string()));
+        }
+        if content.chars().count() > MAX_DIRECT_MESSAGE_CHARS {
+            return Err(AppError::TooLong(
+                "content".to_string(),
+                MAX_DIRECT_MESSAGE_CHARS,
+            ));
+        }
+
+        let user_id = self.user_id(sender_id).await?;
+
+        let conversation_service = ConversationService::new(self.connection.clone());
+        let member_ids = self
+            .metrics
+            .time_db(
+                "conversation.list_member_ids",
+                conversation_service.list_member_ids(&conversation_id),
+            )
+            .await?;
+        if !member_ids.contains(&user_id) {
+            return Err(AppError::PermissionDenied(format!(
+                "write to conversation {}",
+                conversation_id
+            )));
+        }
+
+        let message = self
+            .metrics
+            .time_db(
+                "conversation.add_message",
+                conversation_service.add_message(&conversation_id, &user_id, &content),
+            )
+            .await?;
+
+        let broadcast_message = OutgoingMessage::DirectMessageBroadcast {
+            sender_id: sender_id.to_string(),
+            conversation_id,
+            message,
+        };
+        for member_id in member_ids {
+            self.publish(Audience::User { user_id: member_id }, &broadcast_message)
+                .await;
+        }
+
+        Ok(())
+    }
+
+    /// Loads a message along with its channel, which the client needs to
+    /// have access to.
+    async fn authorize_message(
+        &self,
+        client_id: &str,
+        message_id: &str,
+    ) -> AppResult<(ClientInfo, ChannelResource, MessageResource)> {
+        let message = self
+            .metrics
+            .time_db(
+                "message.find_by_id",
+                MessageService::new(self.connection.clone()).find_by_id(message_id),
+            )
+            .await?
+            .ok_or_else(|| AppError::MessageNotFound(message_id.to_string()))?;
+
+        let (client, channel) = self
+            .authorize_channel(client_id, &message.channel_id)
+            .await?;
+
+        Ok((client, channel, message))
+    }
+
+    /// Loads a message that is still there to be changed.
+    async fn authorize_message_change(
+        &self,
+        client_id: &str,
+        message_id: &str,
+    ) -> AppResult<(ClientInfo, ChannelResource, MessageResource)> {
+        let (client, channel, message) = self.authorize_message(client_id, message_id).await?;
+
+        if message.deleted {
+            return Err(AppError::PermissionDenied(format!(
+                "change deleted message {}",
+                message_id
+            )));
+        }
+
+        Ok((client, channel, message))
+    }
+
+    /// Messages may only be edited by their author.
+    pub async fn edit_message(
+        &self,
+        client_id: &str,
+        message_id: &str,
+        contents: String,
+    ) -> AppResult<()> {
+        if contents.trim().is_empty() {
+            return Err(AppError::MissingField("contents".to_string()));
+        }
+        if contents.chars().count() > MAX_CHAT_MESSAGE_CHARS {
+            return Err(AppError::TooLong(
+                "contents".to_string(),
+                MAX_CHAT_MESSAGE_CHARS,
+            ));
+        }
+
+        let (client, channel, message) =
+            self.authorize_message_change(client_id, message_id).await?;
+        if message.user_id != client.resource.user_id {
+            return Err(AppError::PermissionDenied(format!(
+                "edit message {} of another user",
+                message_id
+            )));
+        }
+
+        let message = self
+            .metrics
+            .time_db(
+                "message.edit_message",
+                MessageService::new(self.connection.clone()).edit_message(message_id, &contents),
+            )
+            .await?;
+
+        self.broadcast_message_update(&channel, message).await;
+
+        Ok(())
+    }
+
+    /// Messages may be deleted by their author and the niche's moderators.
+    pub async fn delete_message(&self, client_id: &str, message_id: &str) -> AppResult<()> {
+        let (client, channel, message) =
+            self.authorize_message_change(client_id, message_id).await?;
+        let user_id = client.resource.user_id;
+
+        if message.user_id != user_id {
+            let niche_role = self
+                .metrics
+                .time_db(
+                    "niche.find_member_role",
+                    NicheService::new(self.connection.clone())
+                        .find_member_role(&channel.niche_id, &user_id),
+                )
+                .await?;
+            if !niche_role.is_some_and(|role| role.can_moderate()) {
+                return Err(AppError::PermissionDenied(format!(
+                    "delete message {} of another user",
+                    message_id
+                )));
+            }
+        }
+
+        let message = self
+            .metrics
+            .time_db(
+                "message.delete_message",
+                MessageService::new(self.connection.clone()).delete_message(message_id, &user_id),
+            )
+            .await?;
+
+        self.broadcast_message_update(&channel, message).await;
+
+        Ok(())
+    }
+
+    /// Adds or takes back the client's reaction to a message.
+    pub async fn react(
+        &self,
+        client_id: &str,
+        message_id: &str,
+        emoji: String,
+        add: bool,
+    ) -> AppResult<()> {
+        if emoji.is_empty() {
+            return Err(AppError::MissingField("emoji".to_string()));
+        }
+        if emoji.chars().count() > MAX_REACTION_CHARS {
+            return Err(AppError::TooLong("emoji".to_string(), MAX_REACTION_CHARS));
+        }
+
+        let (client, channel, message) = self.authorize_message(client_id, message_id).await?;
+        if message.deleted {
+            return Err(AppError::PermissionDenied(format!(
+                "react to deleted message {}",
+                message_id
+            )));
+        }
+
+        let message_service = MessageService::new(self.connection.clone());
+        let message = if add {
+            self.metrics
+                .time_db(
+                    "message.add_reaction",
+                    message_service.add_reaction(message_id, &client.resource.user_id, &emoji),
+                )
+                .await?
+        } else {
+            self.metrics
+                .time_db(
+                    "message.remove_reaction",
+                    message_service.remove_reaction(message_id, &client.resource.user_id, &emoji),
+                )
+                .await?
+        };
+
+        self.broadcast_message_update(&channel, message).await;
+
+        Ok(())
+    }
+
+    async fn broadcast_message_update(&self, channel: &ChannelResource, message: MessageResource) {
+        let update = OutgoingMessage::ChatMessageUpdated {
+            channel_id: channel.id.clone(),
+            message,
+        };
+        self.broadcast_niche(&channel.niche_id, None, &update).await;
+    }
+
+    async fn user_id(&self, client_id: &str) -> AppResult<String> {
+        self.clients
+            .get(client_id)
+            .map(|client| client.resource.user_id.clone())
+            .ok_or_else(|| AppError::Anyhow(anyhow::anyhow!("Client not found")))
+    }
+
+    async fn publish(&self, audience: Audience, message: &OutgoingMessage) {
+        let envelope = Envelope {
+            audience,
+            message: message.clone(),
+        };
+
+        if let Err(e) = self.backend.publish(envelope).await {
+            tracing::error!("Failed to publish broadcast message: {:?}", e);
+        }
     }
 
-    async fn broadcast_all(&self, message: &OutgoingMessage) {
-        let ws_message = match message.to_ws_message() {
-            Ok(msg) => msg,
-            Err(e) => {
-                tracing::error!("Failed to serialize broadcast message: {}", e);
-                return;
-            }
+    async fn broadcast_niche(
+        &self,
+        niche_id: &str,
+        except_client_id: Option<&str>,
+        message: &OutgoingMessage,
+    ) {
+        let audience = Audience::Niche {
+            niche_id: niche_id.to_string(),
+            except_client_id: except_client_id.map(str::to_string),
         };
 
-        let clients = { self.clients.lock().await.clone() };
-        for (id, client) in clients.iter() {
-            let mut sender_lock = client.sender.lock().await;
-            if let Err(e) = sender_lock.send(ws_message.clone()).await {
-                tracing::warn!("Failed to send broadcast message to client {}: {}", id, e);
-            }
-        }
+        self.publish(audience, message).await;
     }
 
-    async fn broadcast_niche<F>(&self, filter: F, message: &OutgoingMessage)
-    where
-        F: Fn(&ClientInfo) -> bool + Send + Sync,
-    {
-        let ws_message = match message.to_ws_message() {
-            Ok(msg) => msg,
-            Err(e) => {
-                tracing::error!("Failed to serialize broadcast message: {}", e);
-                return;
-            }
+    async fn broadcast_channel(
+        &self,
+        channel_id: &str,
+        except_client_id: Option<&str>,
+        message: &OutgoingMessage,
+    ) {
+        let audience = Audience::Channel {
+            channel_id: channel_id.to_string(),
+            except_client_id: except_client_id.map(str::to_string),
         };
 
-        // Collect relevant clients without holding the lock
-        let clients: Vec<(ClientSender, String)> = {
-            self.clients
-                .lock()
-                .await
-                .values()
-                .filter(|&client| filter(client))
-                .map(|client| (client.sender.clone(), client.id.clone()))
-                .collect()
+        self.publish(audience, message).await;
+    }
+
+    /// Sends the client its own profile and those of everyone online who
+    /// shares one of its niches.
+    async fn send_presence(&self, client: &ClientInfo) -> AppResult<()> {
+        let member_ids = self
+            .metrics
+            .time_db(
+                "niche.list_member_user_ids",
+                NicheService::new(self.connection.clone()).list_member_user_ids(&client.niche_ids),
+            )
+            .await?;
+        let user_ids = self.backend.online_user_ids(&member_ids).await?;
+
+        let user_service = UserService::new(self.connection.clone());
+        let mut clients = self
+            .metrics
+            .time_db(
+                "user.list_profiles_in_niches",
+                user_service.list_profiles_in_niches(&client.niche_ids, &user_ids),
+            )
+            .await?;
+        clients.retain(|profile| {
+            profile.id != client.resource.user_id && profile.status != UserStatus::Invisible
+        });
+        clients.push(
+            self.metrics
+                .time_db(
+                    "user.find_profile",
+                    user_service.find_profile(&client.resource.user_id),
+                )
+                .await?,
+        );
+
+        client.send(&OutgoingMessage::ActiveClientsUpdate { clients })
+    }
+
+    /// Tells the members of the user's niches how the user shows up now,
+    /// and the user's own clients what it picked.
+    async fn publish_presence(
+        &self,
+        user_id: &str,
+        niche_ids: &[String],
+        profile: Option<UserProfileResource>,
+    ) {
+        let visible = profile
+            .clone()
+            .filter(|profile| profile.status != UserStatus::Invisible);
+        let audience = Audience::NicheMembers {
+            niche_ids: niche_ids.to_vec(),
+            except_user_id: Some(user_id.to_string()),
         };
+        let message = OutgoingMessage::PresenceUpdate {
+            user_id: user_id.to_string(),
+            presence: visible,
+        };
+        self.publish(audience, &message).await;
+
+        if profile.is_some() {
+            let audience = Audience::User {
+                user_id: user_id.to_string(),
+            };
+            let message = OutgoingMessage::PresenceUpdate {
+                user_id: user_id.to_string(),
+                presence: profile,
+            };
+            self.publish(audience, &message).await;
+        }
+    }
 
-        for (sender, client_id) in clients.iter() {
-            let mut sender_lock = sender.lock().await;
-            if let Err(e) = sender_lock.send(ws_message.clone()).await {
-                tracing::warn!("Failed to send broadcast message: {}", e);
-            } else {
-                tracing::info!("Sent message {:?} to client {}.", message, client_id);
+    /// Tells the members of the user's niches it went offline, unless it is
+    /// still connected somewhere.
+    async fn publish_offline(&self, user_id: &str) {
+        match self.backend.online_user_ids(&[user_id.to_string()]).await {
+            Ok(user_ids) if !user_ids.is_empty() => return,
+            Ok(_) => {}
+            Err(e) => {
+                tracing::error!(
+                    "Failed to check whether user {} is online: {:?}",
+                    user_id,
+                    e
+                );
+                return;
             }
         }
+
+        let niche_service = NicheService::new(self.connection.clone());
+        match self
+            .metrics
+            .time_db(
+                "niche.list_member_niche_ids",
+                niche_service.list_member_niche_ids(user_id),
+            )
+            .await
+        {
+            Ok(niche_ids) => self.publish_presence(user_id, &niche_ids, None).await,
+            Err(e) => tracing::error!("Failed to load niches of user {}: {:?}", user_id, e),
+        }
     }
 
-    async fn broadcast_active_clients(&self) {
-        let client_infos = self.get_client_info_msgs().await;
-        let update_msg = OutgoingMessage::ActiveClientsUpdate {
-            clients: client_infos,
-        };
+    /// Changes the status the client's user shows in its niches.
+    pub async fn set_status(
+        &self,
+        client_id: &str,
+        status: UserStatus,
+        custom_status: Option<String>,
+    ) -> AppResult<()> {
+        let custom_status = custom_status
+            .map(|text| text.trim().to_string())
+            .filter(|text| !text.is_empty());
+        if custom_status
+            .as_ref()
+            .is_some_and(|text| text.chars().count() > MAX_CUSTOM_STATUS_CHARS)
+        {
+            return Err(AppError::TooLong(
+                "custom_status".to_string(),
+                MAX_CUSTOM_STATUS_CHARS,
+            ));
+        }
+
+        let client = self
+            .clients
+            .get(client_id)
+            .map(|client| client.clone())
+            .ok_or_else(|| AppError::Anyhow(anyhow::anyhow!("Client not found")))?;
+        let user_id = &client.resource.user_id;
+
+        let profile = self
+            .metrics
+            .time_db(
+                "user.set_status",
+                UserService::new(self.connection.clone()).set_status(
+                    user_id,
+                    status,
+                    custom_status.as_deref(),
+                ),
+            )
+            .await?;
+
+        self.publish_presence(user_id, &client.niche_ids, Some(profile))
+            .await;
 
-        // todo broadcast to all of client's niches
-        // self.broadcast_niches(niche_ids, &update_msg).await;
-        self.broadcast_all(&update_msg).await;
-        tracing::info!("Broadcasted active clients");
+        Ok(())
     }
 
     pub async fn broadcast_niche_clients_except(
@@ -425,19 +2055,8 @@ impl AppState {
     ) {
         tracing::info!("Broadcasting to niche with id {}...", niche_id);
 
-        // Retrieve and drop the lock before generating the message
-        {
-            if let Some(lobbies) = self.lobbies.lock().await.get(niche_id) {
-                self.broadcast_niche(
-                    |client| {
-                        client.current_niche_id.as_ref().map(|s| s.as_str()) == Some(niche_id)
-                            && client.id != sender_id
-                    },
-                    &message,
-                )
-                .await;
-            }
-        };
+        self.broadcast_niche(niche_id, Some(sender_id), message)
+            .await;
 
         tracing::info!("Done!");
     }
@@ -445,48 +2064,252 @@ impl AppState {
     async fn broadcast_niche_clients(&self, niche_id: &str) {
         tracing::info!("Broadcasting to niche with id {}...", niche_id);
 
-        // Retrieve and drop the lock before generating the message
-        {
-            if let Some(lobbies) = self.lobbies.lock().await.get(niche_id) {
-                let message = RoomResource::generate_active_lobbies_message(lobbies).await;
-                self.broadcast_niche(
-                    |client| client.current_niche_id.as_ref().map(|s| s.as_str()) == Some(niche_id),
-                    &message,
-                )
-                .await;
+        let channels = match self.backend.rooms(niche_id).await {
+            Ok(channels) => channels,
+            Err(e) => {
+                tracing::error!("Failed to load rooms for niche {}: {:?}", niche_id, e);
+                return;
             }
         };
 
+        let message = OutgoingMessage::ActiveChannels { channels };
+        self.broadcast_niche(niche_id, None, &message).await;
+
         tracing::info!("Done!");
     }
 
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Tells every client of this instance that it is shutting down, waits
+    /// up to the drain deadline for them to leave, and closes whoever is
+    /// left with 1001.
+    pub async fn drain(&self) {
+        if self.draining.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        // Recordings can not follow their rooms to another instance
+        for recording in self.sfu.stop_recordings().await {
+            tracing::info!("Recording {} ended with the instance", recording.id());
+            self.finish_recording(&recording).await;
+        }
+
+        let local_clients = self.local_clients();
+        tracing::info!("Draining {} clients", local_clients.len());
+
+        let notice = OutgoingMessage::Shutdown {
+            reconnect_delay_secs: self.config.reconnect_delay.as_secs() as u32,
+        };
+        for client in local_clients.iter() {
+            if let Err(e) = client.send(&notice) {
+                tracing::warn!(
+                    "Failed to tell client {} about the shutdown: {:?}",
+                    client.id,
+                    e
+                );
+            }
+        }
+
+        let deadline = Instant::now() + self.config.drain_deadline;
+        while Instant::now() < deadline && !self.clients.is_empty() {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+
+        let remaining = self.local_clients();
+        if !remaining.is_empty() {
+            tracing::info!("Closing {} clients that did not leave", remaining.len());
+        }
+        for client in remaining {
+            let _ = client
+                .sender
+                .send(AppError::ShuttingDown.to_ws_close_message());
+            self.remove_client(&client.id).await;
+        }
+
+        // Nobody is left on this instance to resume them
+        for client_id in self.sessions.suspended_clients().await {
+            self.remove_client(&client_id).await;
+        }
+    }
+
+    /// Every connected client, with the details this instance knows about
+    /// its own.
+    pub async fn client_summaries(&self) -> AppResult<Vec<ClientSummary>> {
+        let local_clients: HashMap<String, ClientInfo> = self
+            .local_clients()
+            .into_iter()
+            .map(|client| (client.id.clone(), client))
+            .collect();
+
+        Ok(self
+            .backend
+            .clients()
+            .await?
+            .into_iter()
+            .map(|(client_id, user)| {
+                let local = local_clients.get(&client_id);
+                let membership = local.and_then(|client| client.membership.as_ref());
+                ClientSummary {
+                    user: ClientInfoMsg {
+                        user_id: user.user_id,
+                    },
+                    local: local.is_some(),
+                    current_niche_id: local.and_then(|client| client.current_niche_id.clone()),
+                    current_channel_id: local.and_then(|client| client.current_channel_id.clone()),
+                    lobby_id: membership.map(|membership| membership.lobby_id.clone()),
+                    role: membership.map(|membership| membership.role),
+                    client_id,
+                }
+            })
+            .collect())
+    }
+
+    /// Every room with anyone in it, by niche.
+    pub async fn all_rooms(&self) -> AppResult<HashMap<String, HashMap<String, RoomResource>>> {
+        let mut niches = HashMap::new();
+        for niche_id in self.backend.room_counts().await?.into_keys() {
+            let rooms = self.backend.rooms(&niche_id).await?;
+            niches.insert(niche_id, rooms);
+        }
+
+        Ok(niches)
+    }
+
+    /// Closes the client's connection on whichever instance holds it.
+    pub async fn force_disconnect(&self, client_id: &str) -> AppResult<()> {
+        if !self.backend.has_client(client_id).await? {
+            return Err(AppError::ClientNotFound(client_id.to_string()));
+        }
+
+        self.publish(
+            Audience::Client {
+                client_id: client_id.to_string(),
+            },
+            &OutgoingMessage::Disconnected {
+                reason: AppError::ForceDisconnected.to_string(),
+            },
+        )
+        .await;
+
+        Ok(())
+    }
+
+    /// Empties a room as if its lobby had been deleted. The lobby itself
+    /// stays, so clients may join it again.
+    pub async fn tear_down_room(&self, lobby_id: &str) -> AppResult<()> {
+        if self.backend.room_clients(lobby_id).await?.is_empty() {
+            return Err(AppError::RoomNotFound(lobby_id.to_string()));
+        }
+
+        let lobby = self
+            .metrics
+            .time_db(
+                "lobby.find_by_id",
+                LobbyService::new(self.connection.clone()).find_by_id(lobby_id.to_string()),
+            )
+            .await?;
+        tracing::info!("Tearing down room {}", lobby_id);
+        self.close_lobby(&lobby).await
+    }
+
+    async fn disconnect_local_client(&self, client_id: &str) {
+        let Some(client) = self.clients.get(client_id).map(|client| client.clone()) else {
+            return;
+        };
+
+        tracing::info!("Disconnecting client {} on request", client_id);
+        let _ = client
+            .sender
+            .send(AppError::ForceDisconnected.to_ws_close_message());
+        self.remove_client(client_id).await;
+    }
+
+    /// A snapshot of the clients connected to this instance.
+    fn local_clients(&self) -> Vec<ClientInfo> {
+        self.clients
+            .iter()
+            .map(|client| client.value().clone())
+            .collect()
+    }
+
+    async fn membership(&self, client_id: &str) -> Option<Membership> {
+        self.clients
+            .get(client_id)
+            .and_then(|client| client.membership.clone())
+    }
+
+    async fn permissions(&self, client_id: &str) -> AppResult<Permissions> {
+        match self.membership(client_id).await {
+            Some(membership) => Ok(membership.role.permissions()),
+            None => Err(AppError::PermissionDenied(
+                "not a participant of any lobby".to_string(),
+            )),
+        }
+    }
+
+    async fn ensure_same_room(&self, channel_id: &str, client_ids: &[&str]) -> AppResult<()> {
+        let room_client_ids: Vec<String> = self
+            .backend
+            .room_clients(channel_id)
+            .await?
+            .into_iter()
+            .map(|client| client.client_id)
+            .collect();
+
+        for client_id in client_ids.iter() {
+            if !room_client_ids.iter().any(|id| id == client_id) {
+                return Err(AppError::NotInRoom(
+                    (*client_id).to_string(),
+                    channel_id.to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn relay_to_room_peer(
+        &self,
+        client_id: &str,
+        channel_id: &str,
+        target_client_id: &str,
+        message: &OutgoingMessage,
+    ) -> AppResult<()> {
+        self.ensure_same_room(channel_id, &[client_id, target_client_id])
+            .await?;
+
+        self.send_to_client(target_client_id, message).await?;
+
+        tracing::debug!(
+            "Relayed signaling message from {} to {} in room {}",
+            client_id,
+            target_client_id,
+            channel_id
+        );
+
+        Ok(())
+    }
+
     pub(crate) async fn answer(
         &self,
         client_id: &str,
         sdp: String,
         channel_id: String,
-        niche_id: String,
+        target_client_id: String,
     ) -> AppResult<()> {
-        let valid_client_ids: HashSet<String> = {
-            let rooms_locked = self.lobbies.lock().await;
-            match rooms_locked.get(&niche_id) {
-                Some(channels) => {
-                    if let Some(room) = channels.get(&channel_id) {
-                        room.clients.lock().await.keys().cloned().collect()
-                    } else {
-                        HashSet::new()
-                    }
-                }
-                None => HashSet::new(),
-            }
-        };
-        self.broadcast_niche(
-            |client| client_id != client.id && valid_client_ids.contains(&client.id),
-            &OutgoingMessage::Answer { answer: sdp },
-        )
-        .await;
+        if target_client_id == SFU_CLIENT_ID {
+            return self.sfu.handle_answer(client_id, &channel_id, sdp).await;
+        }
 
-        Ok(())
+        let message = OutgoingMessage::Answer {
+            answer: sdp,
+            sender_client_id: client_id.to_string(),
+            target_client_id: target_client_id.clone(),
+        };
+        self.relay_to_room_peer(client_id, &channel_id, &target_client_id, &message)
+            .await
     }
 
     pub(crate) async fn candidate(
@@ -494,29 +2317,22 @@ impl AppState {
         client_id: &str,
         candidate: Value,
         channel_id: String,
-        niche_id: String,
+        target_client_id: String,
     ) -> AppResult<()> {
-        let valid_client_ids: HashSet<String> = {
-            let rooms_locked = self.lobbies.lock().await;
-            match rooms_locked.get(&niche_id) {
-                Some(channels) => {
-                    if let Some(room) = channels.get(&channel_id) {
-                        room.clients.lock().await.keys().cloned().collect()
-                    } else {
-                        HashSet::new()
-                    }
-                }
-                None => HashSet::new(),
-            }
-        };
-
-        self.broadcast_niche(
-            |client| client_id != client.id && valid_client_ids.contains(&client.id),
-            &OutgoingMessage::Candidate { candidate },
-        )
-        .await;
+        if target_client_id == SFU_CLIENT_ID {
+            return self
+                .sfu
+                .handle_candidate(client_id, &channel_id, candidate)
+                .await;
+        }
 
-        Ok(())
+        let message = OutgoingMessage::Candidate {
+            candidate,
+            sender_client_id: client_id.to_string(),
+            target_client_id: target_client_id.clone(),
+        };
+        self.relay_to_room_peer(client_id, &channel_id, &target_client_id, &message)
+            .await
     }
 
     pub(crate) async fn offer(
@@ -524,28 +2340,362 @@ impl AppState {
         client_id: &str,
         offer: String,
         channel_id: String,
-        niche_id: String,
+        target_client_id: String,
     ) -> AppResult<()> {
-        let valid_client_ids: HashSet<String> = {
-            let rooms_locked = self.lobbies.lock().await;
-            match rooms_locked.get(&niche_id) {
-                Some(channels) => {
-                    if let Some(room) = channels.get(&channel_id) {
-                        room.clients.lock().await.keys().cloned().collect()
-                    } else {
-                        HashSet::new()
-                    }
-                }
-                None => HashSet::new(),
+        if target_client_id == SFU_CLIENT_ID {
+            // The SFU accepts the offer either way, since listeners need it to
+            // receive audio, but drops tracks they try to publish
+            return self.sfu.handle_offer(client_id, &channel_id, offer).await;
+        }
+
+        if !self.permissions(client_id).await?.send_media {
+            return Err(AppError::PermissionDenied(format!(
+                "send media in room {}",
+                channel_id
+            )));
+        }
+
+        let message = OutgoingMessage::Offer {
+            offer,
+            sender_client_id: client_id.to_string(),
+            target_client_id: target_client_id.clone(),
+        };
+        self.relay_to_room_peer(client_id, &channel_id, &target_client_id, &message)
+            .await
+    }
+}
+
+fn recording_message(recording: &Recording, active: bool) -> OutgoingMessage {
+    OutgoingMessage::Recording {
+        channel_id: recording.lobby_id().to_string(),
+        recording_id: recording.id().to_string(),
+        owner_user_id: recording.owner_user_id().to_string(),
+        recording: active,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{app_state, client, connected_client};
+    use futures::StreamExt;
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+    use talky_services::conversation::service::{
+        ConversationResource, DirectMessageResource, ListConversationArgs, ListDirectMessageArgs,
+    };
+    use talky_services::pagination::ListResult;
+
+    #[sqlx::test(migrations = false)]
+    async fn niche_members_join_its_lobbies_and_chat(
+        pool_options: PgPoolOptions,
+        connect_options: PgConnectOptions,
+    ) {
+        let state = app_state(pool_options, connect_options).await;
+        NicheService::new(state.connection.clone())
+            .join("n1", "u2")
+            .await
+            .unwrap();
+        state.add_client(client("c2", "u2").await).await.unwrap();
+
+        state
+            .join("c2", "l1".to_string(), Role::Speaker)
+            .await
+            .unwrap();
+        state
+            .handle_chat_message("c2", "ch1".to_string(), "hello".to_string())
+            .await
+            .unwrap();
+
+        let messages: i64 =
+            sqlx::query_scalar("SELECT count(*) FROM messages WHERE channel_id = 'ch1'")
+                .fetch_one(&*state.connection)
+                .await
+                .unwrap();
+        assert_eq!(messages, 1);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn only_niche_members_join_its_lobbies(
+        pool_options: PgPoolOptions,
+        connect_options: PgConnectOptions,
+    ) {
+        let state = app_state(pool_options, connect_options).await;
+        state.add_client(client("c3", "u3").await).await.unwrap();
+
+        assert!(matches!(
+            state.join("c3", "l1".to_string(), Role::Listener).await,
+            Err(AppError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            state.update_niche("c3", "n1").await,
+            Err(AppError::PermissionDenied(_))
+        ));
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn chat_stays_in_the_niche_of_the_sender(
+        pool_options: PgPoolOptions,
+        connect_options: PgConnectOptions,
+    ) {
+        let state = app_state(pool_options, connect_options).await;
+        NicheService::new(state.connection.clone())
+            .join("n1", "u1")
+            .await
+            .unwrap();
+        state.add_client(client("c1", "u1").await).await.unwrap();
+        state.update_niche("c1", "n1").await.unwrap();
+
+        assert!(matches!(
+            state
+                .handle_chat_message("c1", "ch2".to_string(), "hello".to_string())
+                .await,
+            Err(AppError::PermissionDenied(_))
+        ));
+        let messages: i64 = sqlx::query_scalar("SELECT count(*) FROM messages")
+            .fetch_one(&*state.connection)
+            .await
+            .unwrap();
+        assert_eq!(messages, 0);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn instances_taken_for_dead_register_their_clients_again(
+        pool_options: PgPoolOptions,
+        connect_options: PgConnectOptions,
+    ) {
+        let state = app_state(pool_options, connect_options).await;
+        NicheService::new(state.connection.clone())
+            .join("n1", "u2")
+            .await
+            .unwrap();
+        state.add_client(client("c2", "u2").await).await.unwrap();
+        state
+            .join("c2", "l1".to_string(), Role::Speaker)
+            .await
+            .unwrap();
+        state.set_self_muted("c2", true).await.unwrap();
+
+        // What another instance reaping this one leaves behind
+        state.backend.leave_rooms("c2").await.unwrap();
+        state.backend.remove_client("c2").await.unwrap();
+        state
+            .apply_heartbeat(Heartbeat {
+                was_reaped: true,
+                ..Heartbeat::default()
+            })
+            .await;
+
+        assert!(state.backend.has_client("c2").await.unwrap());
+        let room_clients = state.backend.room_clients("l1").await.unwrap();
+        assert_eq!(room_clients.len(), 1);
+        assert_eq!(room_clients[0].role, Role::Speaker);
+        assert!(room_clients[0].self_muted);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn presence_reaches_members_of_shared_niches_only(
+        pool_options: PgPoolOptions,
+        connect_options: PgConnectOptions,
+    ) {
+        let state = app_state(pool_options, connect_options).await;
+        let niche_service = NicheService::new(state.connection.clone());
+        for (niche_id, user_id) in [("n1", "u1"), ("n1", "u2"), ("n2", "u3")] {
+            niche_service.join(niche_id, user_id).await.unwrap();
+        }
+        state.add_client(client("c2", "u2").await).await.unwrap();
+        state.add_client(client("c3", "u3").await).await.unwrap();
+
+        let (c1, mut socket) = connected_client("c1", "u1").await;
+        state.add_client(c1).await.unwrap();
+
+        let text = socket.next().await.unwrap().unwrap().into_text().unwrap();
+        match serde_json::from_str(&text).unwrap() {
+            OutgoingMessage::ActiveClientsUpdate { clients } => {
+                let user_ids: Vec<_> = clients.iter().map(|profile| profile.id.as_str()).collect();
+                assert_eq!(user_ids, ["u2", "u1"]);
             }
+            message => panic!("expected the presence of the niches, got {:?}", message),
+        }
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn only_authors_edit_messages_and_moderators_delete_them(
+        pool_options: PgPoolOptions,
+        connect_options: PgConnectOptions,
+    ) {
+        let state = app_state(pool_options, connect_options).await;
+        let niche_service = NicheService::new(state.connection.clone());
+        for (client_id, user_id) in [("c1", "u1"), ("c2", "u2"), ("c3", "u3")] {
+            niche_service.join("n1", user_id).await.unwrap();
+            state
+                .add_client(client(client_id, user_id).await)
+                .await
+                .unwrap();
+            state.update_niche(client_id, "n1").await.unwrap();
+        }
+        sqlx::query("UPDATE niche_members SET role = 'moderator' WHERE user_id = 'u3'")
+            .execute(&*state.connection)
+            .await
+            .unwrap();
+
+        state
+            .handle_chat_message("c1", "ch1".to_string(), "hello".to_string())
+            .await
+            .unwrap();
+        let message_id: String = sqlx::query_scalar("SELECT id FROM messages")
+            .fetch_one(&*state.connection)
+            .await
+            .unwrap();
+
+        for client_id in ["c2", "c3"] {
+            assert!(matches!(
+                state
+                    .edit_message(client_id, &message_id, "bye".to_string())
+                    .await,
+                Err(AppError::PermissionDenied(_))
+            ));
+        }
+        assert!(matches!(
+            state
+                .edit_message("c1", &message_id, "a".repeat(MAX_CHAT_MESSAGE_CHARS + 1))
+                .await,
+            Err(AppError::TooLong(..))
+        ));
+        state
+            .edit_message("c1", &message_id, "hello again".to_string())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            state.delete_message("c2", &message_id).await,
+            Err(AppError::PermissionDenied(_))
+        ));
+        state.delete_message("c3", &message_id).await.unwrap();
+        assert!(matches!(
+            state
+                .edit_message("c1", &message_id, "hello".to_string())
+                .await,
+            Err(AppError::PermissionDenied(_))
+        ));
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn only_members_write_to_conversations(
+        pool_options: PgPoolOptions,
+        connect_options: PgConnectOptions,
+    ) {
+        let state = app_state(pool_options, connect_options).await;
+        let conversation = ConversationService::new(state.connection.clone())
+            .create("u1", &["u2".to_string()])
+            .await
+            .unwrap()
+            .unwrap();
+        state.add_client(client("c1", "u1").await).await.unwrap();
+        state.add_client(client("c3", "u3").await).await.unwrap();
+
+        assert!(matches!(
+            state
+                .send_direct_message("c3", conversation.id.clone(), "hello".to_string())
+                .await,
+            Err(AppError::PermissionDenied(_))
+        ));
+        state
+            .send_direct_message("c1", conversation.id, "hello".to_string())
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn conversations_count_unread_messages_and_page_by_activity(
+        pool_options: PgPoolOptions,
+        connect_options: PgConnectOptions,
+    ) {
+        let state = app_state(pool_options, connect_options).await;
+        let service = ConversationService::new(state.connection.clone());
+        // Ids are only ordered across milliseconds
+        let tick = || tokio::time::sleep(Duration::from_millis(2));
+
+        let mut ids = Vec::new();
+        for user_ids in [vec!["u2"], vec!["u3"], vec!["u2", "u3"]] {
+            let user_ids: Vec<_> = user_ids.into_iter().map(String::from).collect();
+            ids.push(service.create("u1", &user_ids).await.unwrap().unwrap().id);
+            tick().await;
+        }
+        let [a, b, c] = <[String; 3]>::try_from(ids).unwrap();
+        for (conversation_id, user_id, contents) in
+            [(&a, "u2", "1"), (&a, "u2", "2"), (&b, "u3", "3")]
+        {
+            service
+                .add_message(conversation_id, user_id, contents)
+                .await
+                .unwrap();
+            tick().await;
+        }
+
+        let page = |after, before, first| ListConversationArgs {
+            before,
+            after,
+            first: Some(first),
+            last: None,
+            user_id: "u1".to_string(),
+        };
+        let conversations = |result: &ListResult<ConversationResource, _>| -> Vec<_> {
+            result
+                .edges
+                .iter()
+                .map(|edge| (edge.node.id.clone(), edge.node.unread_count))
+                .collect()
         };
 
-        self.broadcast_niche(
-            |client| client_id != client.id && valid_client_ids.contains(&client.id),
-            &OutgoingMessage::Offer { offer },
-        )
-        .await;
+        let first_page = service.list(&page(None, None, 2)).await.unwrap();
+        assert_eq!(conversations(&first_page), [(b.clone(), 1), (a.clone(), 2)]);
+        assert!(first_page.page_info.has_next_page);
+        let last_page = service
+            .list(&page(first_page.page_info.end_cursor, None, 2))
+            .await
+            .unwrap();
+        assert_eq!(conversations(&last_page), [(c.clone(), 0)]);
+        let previous_page = service
+            .list(&page(None, last_page.page_info.start_cursor, 1))
+            .await
+            .unwrap();
+        assert_eq!(conversations(&previous_page), [(a.clone(), 2)]);
 
-        Ok(())
+        service.add_message(&a, "u1", "4").await.unwrap();
+        assert_eq!(
+            conversations(&service.list(&page(None, None, 3)).await.unwrap()),
+            [(a.clone(), 0), (b, 1), (c, 0)]
+        );
+
+        let message_page = |after, before, first| ListDirectMessageArgs {
+            before,
+            after,
+            first: Some(first),
+            last: None,
+            conversation_id: a.clone(),
+        };
+        let contents = |result: &ListResult<DirectMessageResource, _>| -> Vec<_> {
+            result
+                .edges
+                .iter()
+                .map(|edge| edge.node.contents.clone())
+                .collect()
+        };
+
+        let first_page = service
+            .list_messages(&message_page(None, None, 2))
+            .await
+            .unwrap();
+        assert_eq!(contents(&first_page), ["4", "2"]);
+        let last_page = service
+            .list_messages(&message_page(first_page.page_info.end_cursor, None, 2))
+            .await
+            .unwrap();
+        assert_eq!(contents(&last_page), ["1"]);
+        let previous_page = service
+            .list_messages(&message_page(None, last_page.page_info.start_cursor, 1))
+            .await
+            .unwrap();
+        assert_eq!(contents(&previous_page), ["2"]);
     }
 }
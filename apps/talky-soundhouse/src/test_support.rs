@@ -0,0 +1,161 @@
+//! Fixtures shared by the unit tests.
+
+use crate::config::{BackendKind, Config};
+use crate::metrics::Metrics;
+use crate::outbound::ClientSender;
+use crate::protocol::Protocol;
+use crate::role::Role;
+use crate::state::{AppState, ClientInfo, RoomClientInfo, UserResource};
+use futures::StreamExt;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use std::sync::Arc;
+use talky_services::lobby::service::LobbyResource;
+use talky_services::DatabasePool;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use warp::Filter;
+
+/// The schema in the order it was migrated.
+const MIGRATIONS: [&str; 8] = [
+    include_str!("../../../libs/data/initial-migration.sql"),
+    include_str!("../../../libs/data/soundhouse-migration.sql"),
+    include_str!("../../../libs/data/temporary-lobbies-migration.sql"),
+    include_str!("../../../libs/data/niche-members-migration.sql"),
+    include_str!("../../../libs/data/message-actions-migration.sql"),
+    include_str!("../../../libs/data/recordings-migration.sql"),
+    include_str!("../../../libs/data/presence-migration.sql"),
+    include_str!("../../../libs/data/direct-messages-migration.sql"),
+];
+
+/// Niches n1 and n2 with the chat channels ch1 and ch2, lobby l1 in ch1
+/// owned by u1, and users u1 to u3 who are not members of anything yet.
+const FIXTURES: &str = "
+    INSERT INTO public.users (id, password) VALUES ('u1', ''), ('u2', ''), ('u3', '');
+    INSERT INTO public.niches (id, slug, name) VALUES ('n1', 'n1', 'n1'), ('n2', 'n2', 'n2');
+    INSERT INTO public.categories (id, name, niche_id) VALUES ('c1', 'c1', 'n1'), ('c2', 'c2', 'n2');
+    INSERT INTO public.channels (id, name, slug, type, category_id)
+        VALUES ('ch1', 'ch1', 'ch1', 'chat', 'c1'), ('ch2', 'ch2', 'ch2', 'chat', 'c2');
+    INSERT INTO public.lobbies (id, name, owner_user_id, channel_id) VALUES ('l1', 'l1', 'u1', 'ch1');
+";
+
+pub(crate) type TestSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A sender writing to the server end of a socket on an ephemeral port, and
+/// the client end to read what it wrote.
+pub(crate) async fn test_sender(capacity: usize) -> (ClientSender, TestSocket) {
+    let (socket_tx, socket_rx) = tokio::sync::oneshot::channel();
+    let socket_tx = Arc::new(std::sync::Mutex::new(Some(socket_tx)));
+    let route = warp::ws().map(move |ws: warp::ws::Ws| {
+        let socket_tx = socket_tx.clone();
+        ws.on_upgrade(move |socket| async move {
+            let (sink, _) = socket.split();
+            if let Some(socket_tx) = socket_tx.lock().unwrap().take() {
+                let _ = socket_tx.send(sink);
+            }
+        })
+    });
+    let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let (client, _) = tokio_tungstenite::connect_async(format!("ws://{}", address))
+        .await
+        .unwrap();
+    let sink = socket_rx.await.unwrap();
+
+    (
+        ClientSender::spawn(sink, capacity, Metrics::new().unwrap()),
+        client,
+    )
+}
+
+pub(crate) async fn client(id: &str, user_id: &str) -> ClientInfo {
+    connected_client(id, user_id).await.0
+}
+
+/// A client and the socket to read what is sent to it.
+pub(crate) async fn connected_client(id: &str, user_id: &str) -> (ClientInfo, TestSocket) {
+    let (sender, socket) = test_sender(8).await;
+    let client = ClientInfo {
+        id: id.to_string(),
+        sender,
+        current_niche_id: None,
+        current_channel_id: None,
+        niche_ids: Vec::new(),
+        resource: user(user_id),
+        membership: None,
+        protocol: Protocol::negotiate(None, &[]).unwrap(),
+    };
+    (client, socket)
+}
+
+pub(crate) fn user(user_id: &str) -> UserResource {
+    UserResource {
+        user_id: user_id.to_string(),
+    }
+}
+
+/// A lobby `id` of niche `n1`, owned by `u1`.
+pub(crate) fn lobby(id: &str) -> LobbyResource {
+    LobbyResource {
+        id: id.to_string(),
+        name: id.to_string(),
+        channel_id: "ch1".to_string(),
+        niche_id: "n1".to_string(),
+        owner_user_id: "u1".to_string(),
+    }
+}
+
+/// A speaker of `u1` as rooms keep it.
+pub(crate) fn room_client(client_id: &str) -> RoomClientInfo {
+    RoomClientInfo {
+        client_id: client_id.to_string(),
+        user: user("u1"),
+        role: Role::Speaker,
+        server_muted: false,
+        server_deafened: false,
+        self_muted: false,
+        self_deafened: false,
+        speaking: false,
+    }
+}
+
+/// A test database holding the schema and the fixtures. Its connections are
+/// closed once released instead of going back to the pool, where one
+/// released while the test ends would keep the database from being dropped.
+pub(crate) async fn database(
+    pool_options: PgPoolOptions,
+    connect_options: PgConnectOptions,
+) -> DatabasePool {
+    let pool = pool_options
+        .after_release(|_, _| Box::pin(async { Ok(false) }))
+        .connect_with(connect_options)
+        .await
+        .unwrap();
+
+    // Without the dump's session settings, which not every server knows
+    for migration in MIGRATIONS {
+        let statements: String = migration
+            .lines()
+            .filter(|line| !line.starts_with("SET ") && !line.starts_with("SELECT pg_catalog"))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        sqlx::raw_sql(&statements).execute(&pool).await.unwrap();
+    }
+    sqlx::raw_sql(FIXTURES).execute(&pool).await.unwrap();
+
+    Arc::new(pool)
+}
+
+/// A state with the memory backend on a test [`database`].
+pub(crate) async fn app_state(
+    pool_options: PgPoolOptions,
+    connect_options: PgConnectOptions,
+) -> AppState {
+    let connection = database(pool_options, connect_options).await;
+
+    let mut config = Config::from_env().unwrap();
+    config.backend = BackendKind::Memory;
+    AppState::with_connection(&config, connection)
+        .await
+        .unwrap()
+}
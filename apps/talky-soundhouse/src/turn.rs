@@ -0,0 +1,73 @@
+use base64::prelude::{Engine, BASE64_STANDARD};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Short-lived TURN credentials in the TURN REST API scheme that coturn
+/// checks with `use-auth-secret`: the username is `<expiry>:<user_id>` and
+/// the credential is the base64 HMAC-SHA1 of the username, keyed with the
+/// secret shared with coturn. Nothing has to be stored, and coturn refuses
+/// them on its own once the expiry has passed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TurnCredentials {
+    pub username: String,
+    pub credential: String,
+}
+
+impl TurnCredentials {
+    pub fn issue(secret: &str, user_id: &str, ttl: Duration) -> Self {
+        let expires_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            + ttl;
+
+        Self::for_username(secret, format!("{}:{}", expires_at.as_secs(), user_id))
+    }
+
+    fn for_username(secret: &str, username: String) -> Self {
+        let mut mac =
+            Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(username.as_bytes());
+        let credential = BASE64_STANDARD.encode(mac.finalize().into_bytes());
+
+        Self {
+            username,
+            credential,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credential_is_the_base64_hmac_sha1_of_the_username() {
+        // RFC 2202, test case 2
+        let credentials =
+            TurnCredentials::for_username("Jefe", "what do ya want for nothing?".to_string());
+        assert_eq!(credentials.credential, "7/zfauXrL6LSdBbV8YTfnCWafHk=");
+
+        let credentials = TurnCredentials::for_username("north", "1700000000:u1".to_string());
+        assert_eq!(credentials.username, "1700000000:u1");
+        assert_eq!(credentials.credential, "V5T4+SMzwlCncdLd960W+gxser0=");
+    }
+
+    #[test]
+    fn username_carries_the_expiry_and_user() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let credentials = TurnCredentials::issue("north", "u1", Duration::from_secs(600));
+
+        let (expires_at, user_id) = credentials.username.split_once(':').unwrap();
+        let expires_at: u64 = expires_at.parse().unwrap();
+        assert_eq!(user_id, "u1");
+        assert!((now + 600..=now + 601).contains(&expires_at));
+        assert_eq!(
+            credentials,
+            TurnCredentials::for_username("north", credentials.username.clone())
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+type ClientId = String;
+
+#[derive(Debug)]
+struct Typing {
+    channel_id: String,
+    notified_at: Instant,
+    expires_at: Instant,
+}
+
+/// What a typing notice from a client amounts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypingStart {
+    /// The client was not typing before.
+    Started,
+    /// The client was typing and the channel was last told long enough ago
+    /// to be told again.
+    Renotify,
+    /// The client was typing and the notice only pushes its expiry back.
+    Refreshed,
+}
+
+/// Which clients of this instance are typing, and in which channel.
+#[derive(Clone, Default)]
+pub struct TypingStore {
+    typing: Arc<Mutex<HashMap<ClientId, Typing>>>,
+}
+
+impl TypingStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn channel(&self, client_id: &str) -> Option<String> {
+        self.typing
+            .lock()
+            .await
+            .get(client_id)
+            .map(|typing| typing.channel_id.clone())
+    }
+
+    /// Records that the client is typing in the channel until `expires_at`.
+    /// The channel is told at most once per `throttle`.
+    pub async fn start(
+        &self,
+        client_id: &str,
+        channel_id: &str,
+        expires_at: Instant,
+        throttle: Duration,
+    ) -> TypingStart {
+        let now = Instant::now();
+        let mut typing = self.typing.lock().await;
+
+        match typing.get_mut(client_id) {
+            Some(current) if current.channel_id == channel_id => {
+                current.expires_at = expires_at;
+                if now.duration_since(current.notified_at) >= throttle {
+                    current.notified_at = now;
+                    TypingStart::Renotify
+                } else {
+                    TypingStart::Refreshed
+                }
+            }
+            _ => {
+                typing.insert(
+                    client_id.to_string(),
+                    Typing {
+                        channel_id: channel_id.to_string(),
+                        notified_at: now,
+                        expires_at,
+                    },
+                );
+                TypingStart::Started
+            }
+        }
+    }
+
+    pub async fn expires_at(&self, client_id: &str) -> Option<Instant> {
+        self.typing
+            .lock()
+            .await
+            .get(client_id)
+            .map(|typing| typing.expires_at)
+    }
+
+    /// Stops the client typing if it has not sent a notice in time, and
+    /// returns the channel it was typing in.
+    pub async fn expire(&self, client_id: &str) -> Option<String> {
+        let mut typing = self.typing.lock().await;
+        match typing.get(client_id) {
+            Some(current) if current.expires_at <= Instant::now() => {
+                typing.remove(client_id).map(|typing| typing.channel_id)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the channel the client was typing in, if it was.
+    pub async fn stop(&self, client_id: &str) -> Option<String> {
+        self.typing
+            .lock()
+            .await
+            .remove(client_id)
+            .map(|typing| typing.channel_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THROTTLE: Duration = Duration::from_secs(3);
+    const TIMEOUT: Duration = Duration::from_secs(8);
+
+    #[tokio::test(start_paused = true)]
+    async fn channel_is_told_at_most_once_per_throttle() {
+        let store = TypingStore::new();
+        let start = || store.start("c1", "ch1", Instant::now() + TIMEOUT, THROTTLE);
+
+        assert_eq!(start().await, TypingStart::Started);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(start().await, TypingStart::Refreshed);
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(start().await, TypingStart::Renotify);
+        assert_eq!(start().await, TypingStart::Refreshed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn switching_channels_starts_over() {
+        let store = TypingStore::new();
+        store
+            .start("c1", "ch1", Instant::now() + TIMEOUT, THROTTLE)
+            .await;
+
+        assert_eq!(
+            store
+                .start("c1", "ch2", Instant::now() + TIMEOUT, THROTTLE)
+                .await,
+            TypingStart::Started
+        );
+        assert_eq!(store.channel("c1").await.as_deref(), Some("ch2"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_only_once_no_notice_came_in_time() {
+        let store = TypingStore::new();
+        store
+            .start("c1", "ch1", Instant::now() + TIMEOUT, THROTTLE)
+            .await;
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        store
+            .start("c1", "ch1", Instant::now() + TIMEOUT, THROTTLE)
+            .await;
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(store.expire("c1").await, None);
+
+        tokio::time::advance(Duration::from_secs(3)).await;
+        assert_eq!(store.expire("c1").await.as_deref(), Some("ch1"));
+        assert_eq!(store.channel("c1").await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stopping_returns_the_channel() {
+        let store = TypingStore::new();
+        store
+            .start("c1", "ch1", Instant::now() + TIMEOUT, THROTTLE)
+            .await;
+
+        assert_eq!(store.stop("c1").await.as_deref(), Some("ch1"));
+        assert_eq!(store.stop("c1").await, None);
+    }
+}
@@ -1,8 +1,141 @@
-export type MessageResource = { id: string; user_id: string; timestamp: string; contents: string }
+/**
+ * Everyone who reacted to a message with the same emoji.
+ */
+export type ReactionResource = { emoji: string; count: number; user_ids: string[] }
+export type MessageResource = { id: string; user_id: string; channel_id: string; timestamp: string; contents: string; edited_at: string | null; deleted: boolean; reactions: ReactionResource[] }
+export type DirectMessageResource = { id: string; conversation_id: string; user_id: string; timestamp: string; contents: string }
 export type UserResource = { user_id: string; type: "UserResource" }
-export type UserRoomResource = { user: UserResource; role: string; type: "UserRoomResource" }
+/**
+ * What a participant is in a lobby.
+ */
+export type Role = "speaker" | "listener" | "moderator"
+export type UserRoomResource = { client_id: string; user: UserResource; role: Role; server_muted: boolean; server_deafened: boolean; self_muted: boolean; self_deafened: boolean; speaking: boolean; type: "UserRoomResource" }
 export type ClientInfoMsg = { user_id: string }
 export type JsonValue = null | boolean | number | string | JsonValue[] | Partial<{ [key in string]: JsonValue }>
 export type RoomResource = { users: Partial<{ [key in string]: UserRoomResource[] }>; type: "RoomResource" }
-export type OutgoingMessage = { type: "init"; auth_code: string } | { type: "update_niche"; niche_id: string } | { type: "join"; channel_id: string; role: string } | { type: "candidate"; candidate: JsonValue; channel_id: string; niche_id: string } | { type: "answer"; answer: string; channel_id: string; niche_id: string } | { type: "offer"; offer: string; channel_id: string; niche_id: string } | { type: "chat_message"; content: string; channel_id: string } | { type: "web_rtc_signal"; target_client_id: string; signal_data: JsonValue }
-export type IncomingMessage = { type: "active_channels"; channels: Partial<{ [key in string]: RoomResource }> } | { type: "candidate"; candidate: JsonValue } | { type: "answer"; answer: string } | { type: "offer"; offer: string } | { type: "active_clients_update"; clients: ClientInfoMsg[] } | { type: "chat_message_broadcast"; sender_id: string; message: MessageResource; channel_id: string } | { type: "web_rtc_signal"; sender_client_id: string; signal_data: JsonValue } | { type: "error"; message: string }
\ No newline at end of file
+export type RateLimit = { per_second: number; burst: number }
+/**
+ * The limits a client has to stay within, sent along with `init_ack`.
+ */
+export type ServerLimits = { heartbeat_interval_secs: number; heartbeat_timeout_secs: number; resume_grace_secs: number; typing_timeout_secs: number; typing_throttle_secs: number; max_reaction_chars: number; max_custom_status_chars: number; max_direct_message_chars: number; max_chat_message_chars: number; chat: RateLimit; signaling: RateLimit; control: RateLimit }
+/**
+ * One entry of an `RTCConfiguration.iceServers` list. STUN servers come
+ * without credentials, which are left out rather than sent as null.
+ */
+export type IceServer = { urls: string[]; username?: string | null; credential?: string | null }
+/**
+ * Which candidates a peer connection may use, as in
+ * `RTCConfiguration.iceTransportPolicy`.
+ */
+export type IceTransportPolicy = "all" | 
+/**
+ * Only candidates relayed through TURN, which hides client addresses.
+ */
+"relay"
+/**
+ * Everything a client needs to configure its peer connections. Clients
+ * use it as is instead of shipping their own server list, so STUN and TURN
+ * can move without a client release.
+ */
+export type IceConfig = { ice_servers: IceServer[]; ice_transport_policy: IceTransportPolicy }
+/**
+ * What a user picked to show to others. Being offline is not a status,
+ * it follows from having no connection.
+ */
+export type UserStatus = "online" | "idle" | "dnd" | 
+/**
+ * Shown to others as offline.
+ */
+"invisible"
+export type UserProfileResource = { id: string; display_name: string; avatar_url: string | null; status: UserStatus; custom_status: string | null }
+export type OutgoingMessage = { type: "init"; auth_code: string; resume_token?: string | null; protocol_version?: number | null; capabilities?: string[] } | { type: "update_niche"; niche_id: string } | 
+/**
+ * The text channel the client is looking at, if any.
+ */
+{ type: "update_channel"; channel_id: string | null } | { type: "join"; channel_id: string; role: Role } | { type: "candidate"; candidate: JsonValue; channel_id: string; target_client_id: string } | { type: "answer"; answer: string; channel_id: string; target_client_id: string } | { type: "offer"; offer: string; channel_id: string; target_client_id: string } | { type: "chat_message"; content: string; channel_id: string } | { type: "web_rtc_signal"; target_client_id: string; signal_data: JsonValue } | { type: "edit_message"; message_id: string; contents: string } | { type: "delete_message"; message_id: string } | { type: "add_reaction"; message_id: string; emoji: string } | { type: "remove_reaction"; message_id: string; emoji: string } | { type: "typing_start"; channel_id: string } | { type: "typing_stop"; channel_id: string } | 
+/**
+ * The client muted or unmuted its microphone.
+ */
+{ type: "self_mute"; muted: boolean } | 
+/**
+ * The client muted or unmuted the lobby's audio.
+ */
+{ type: "self_deafen"; deafened: boolean } | 
+/**
+ * The client started or stopped talking.
+ */
+{ type: "speaking"; speaking: boolean } | 
+/**
+ * Removes a client from the lobby.
+ */
+{ type: "kick"; channel_id: string; target_client_id: string } | 
+/**
+ * Stops forwarding a client's audio to the lobby.
+ */
+{ type: "server_mute"; channel_id: string; target_client_id: string; muted: boolean } | 
+/**
+ * Stops forwarding the lobby's audio to a client.
+ */
+{ type: "server_deafen"; channel_id: string; target_client_id: string; deafened: boolean } | 
+/**
+ * Moves a client to another lobby of the same niche.
+ */
+{ type: "move"; channel_id: string; target_client_id: string; destination_channel_id: string } | 
+/**
+ * Starts or stops recording the lobby the client is in.
+ */
+{ type: "record"; channel_id: string; recording: boolean } | 
+/**
+ * Changes the status the user shows in its niches.
+ */
+{ type: "set_status"; status: UserStatus; custom_status?: string | null } | 
+/**
+ * Writes to a conversation the user takes part in.
+ */
+{ type: "direct_message"; conversation_id: string; content: string }
+export type IncomingMessage = { type: "init_ack"; client_id: string; resume_token: string; protocol_version: number; capabilities: string[]; limits: ServerLimits; ice: IceConfig } | { type: "active_channels"; channels: Partial<{ [key in string]: RoomResource }> } | { type: "candidate"; candidate: JsonValue; sender_client_id: string; target_client_id: string } | { type: "answer"; answer: string; sender_client_id: string; target_client_id: string } | { type: "offer"; offer: string; sender_client_id: string; target_client_id: string } | 
+/**
+ * Everyone online who shares a niche with the client, and the client's
+ * own user. Sent once after connecting, `presence_update` follows.
+ */
+{ type: "active_clients_update"; clients: UserProfileResource[] } | 
+/**
+ * A user sharing a niche with the client came online, changed its
+ * profile or status, or went offline, which leaves `presence` empty.
+ */
+{ type: "presence_update"; user_id: string; presence: UserProfileResource | null } | { type: "chat_message_broadcast"; sender_id: string; message: MessageResource; channel_id: string } | 
+/**
+ * A message in one of the user's conversations, sent to every client of
+ * every participant, the sender's included.
+ */
+{ type: "direct_message_broadcast"; sender_id: string; conversation_id: string; message: DirectMessageResource } | 
+/**
+ * A message was edited, deleted or reacted to.
+ */
+{ type: "chat_message_updated"; channel_id: string; message: MessageResource } | { type: "web_rtc_signal"; sender_client_id: string; signal_data: JsonValue } | 
+/**
+ * The lobby the client was in has expired and was deleted.
+ */
+{ type: "lobby_closed"; channel_id: string } | 
+/**
+ * A client started or stopped typing in the channel.
+ */
+{ type: "typing"; channel_id: string; client_id: string; user_id: string; typing: boolean } | 
+/**
+ * One client's state in a room changed.
+ */
+{ type: "room_client_update"; channel_id: string; client: UserRoomResource } | { type: "kicked"; channel_id: string; client_id: string; moderator_client_id: string } | { type: "server_muted"; channel_id: string; client_id: string; moderator_client_id: string; muted: boolean } | { type: "server_deafened"; channel_id: string; client_id: string; moderator_client_id: string; deafened: boolean } | { type: "moved"; channel_id: string; destination_channel_id: string; client_id: string; moderator_client_id: string } | 
+/**
+ * The lobby started or stopped being recorded. Sent to the niche when
+ * that happens and to everyone who joins while it lasts.
+ */
+{ type: "recording"; channel_id: string; recording_id: string; owner_user_id: string; recording: boolean } | 
+/**
+ * The server is shutting down. Clients should leave and reconnect
+ * after the delay, when they will land on another instance.
+ */
+{ type: "shutdown"; reconnect_delay_secs: number } | 
+/**
+ * The server is about to close the connection.
+ */
+{ type: "disconnected"; reason: string } | { type: "error"; message: string }
\ No newline at end of file
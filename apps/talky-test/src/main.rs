@@ -21,6 +21,7 @@ use webrtc::interceptor::registry::Registry;
 use webrtc::media::Sample;
 use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
 use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::rtp_transceiver::rtp_codec::{
     RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType,
@@ -64,17 +65,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .send(Message::Text(init_msg.into()))
         .await?;
 
+    // Soundhouse hands out the ICE servers with short-lived TURN credentials,
+    // so pass the `ice_servers` of an `init_ack` in as JSON. STUN servers
+    // come without credentials, which `RTCIceServer` can not go without.
+    let ice_servers: Vec<RTCIceServer> = match std::env::var("ICE_SERVERS") {
+        Ok(servers) => serde_json::from_str::<Vec<Value>>(&servers)?
+            .iter()
+            .map(|server| RTCIceServer {
+                urls: server["urls"]
+                    .as_array()
+                    .map(|urls| {
+                        urls.iter()
+                            .filter_map(|url| url.as_str().map(str::to_string))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                username: server["username"].as_str().unwrap_or_default().to_string(),
+                credential: server["credential"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                ..Default::default()
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    let ice_transport_policy = RTCIceTransportPolicy::from(
+        std::env::var("ICE_TRANSPORT_POLICY")
+            .as_deref()
+            .unwrap_or("all"),
+    );
     let config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
-            urls: vec![
-                "stun:server.loc:31899".to_owned(),
-                "turn:server.loc:30665?transport=udp".to_owned(),
-                "turn:server.loc:31953?transport=tcp".to_owned(),
-            ],
-            username: "coturn".to_string(),
-            credential: "password".to_string(),
-            ..Default::default()
-        }],
+        ice_servers,
+        ice_transport_policy,
         ..Default::default()
     };
 
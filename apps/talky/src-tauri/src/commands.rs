@@ -33,7 +33,7 @@ pub async fn connect_audio(
 
     tauri::async_runtime::spawn(async move {
         if let Err(e) = state_clone
-            .start(config, auth_token, channel_id.clone(), niche_id.clone())
+            .start(config, auth_token, channel_id.clone())
             .await
         {
             eprintln!("[ConnectAudio] Failed to start WebRTC session: {e}");
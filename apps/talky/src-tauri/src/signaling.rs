@@ -10,11 +10,18 @@ use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::RTCPeerConnection;
 
+/// `target_client_id` that addresses soundhouse's own peer connection.
+pub const SFU_CLIENT_ID: &str = "soundhouse";
+
 pub type WebSocketSender = futures_util::stream::SplitSink<
     tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
     Message,
 >;
 
+pub type WebSocketReceiver = futures_util::stream::SplitStream<
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+>;
+
 pub async fn connect_to_signaling_server(
     url: &str,
 ) -> Result<(
@@ -49,11 +56,32 @@ pub async fn send_init_message(ws_sender: &mut WebSocketSender, auth_code: &str)
     Ok(())
 }
 
+/// Waits for soundhouse to accept our `init`. The `init_ack` carries what the
+/// connection needs, like TURN credentials.
+pub async fn receive_init_ack(ws_receiver: &mut WebSocketReceiver) -> Result<Value> {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while let Some(message) = ws_receiver.next().await {
+            let Message::Text(text) = message.context("Failed to read from signaling server")?
+            else {
+                continue;
+            };
+            let json_msg: Value =
+                serde_json::from_str(&text).context("Invalid message from signaling server")?;
+            if json_msg.get("type").and_then(|t| t.as_str()) == Some("init_ack") {
+                return Ok(json_msg);
+            }
+        }
+        anyhow::bail!("Signaling server closed the connection before init_ack")
+    })
+    .await
+    .context("Timed out waiting for init_ack")?
+}
+
 pub async fn send_join_message(ws_sender: &mut WebSocketSender, channel_id: &str) -> Result<()> {
     let msg = json!({
         "type": "join",
         "channel_id": channel_id,
-        "role": "offerer"
+        "role": "speaker"
     })
     .to_string();
 
@@ -69,11 +97,11 @@ pub async fn send_offer(
     ws_sender: &mut WebSocketSender,
     sdp: &str,
     channel_id: &str,
-    niche_id: &str,
+    target_client_id: &str,
 ) -> Result<()> {
     let offer_msg = json!({
         "type": "offer",
-        "niche_id": niche_id,
+        "target_client_id": target_client_id,
         "offer": sdp,
         "channel_id": channel_id
     })
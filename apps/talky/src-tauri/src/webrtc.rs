@@ -17,6 +17,7 @@ use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::interceptor::registry::Registry;
 use webrtc::media::Sample;
 use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
@@ -25,7 +26,7 @@ use webrtc::track::track_local::TrackLocal;
 
 use crate::audio::{AudioProcessor, CaptureControl, AUDIO_STREAM};
 use crate::config::{AudioCaptureConfig, CaptureMode};
-use crate::signaling::{self, WebSocketSender};
+use crate::signaling::{self, WebSocketSender, SFU_CLIENT_ID};
 
 pub struct WebRTCAudio {
     pub peer_connection: Arc<RTCPeerConnection>,
@@ -60,44 +61,7 @@ impl WebRTCManager {
         config: AudioCaptureConfig,
         auth_code: String,
         channel_id: String,
-        niche_id: String,
     ) -> Result<()> {
-        // Create WebRTC configuration
-        let rtc_config = RTCConfiguration {
-            ice_servers: vec![RTCIceServer {
-                urls: vec![
-                    "stun:server.loc:31899".to_owned(),
-                    "turn:server.loc:30665?transport=udp".to_owned(),
-                    "turn:server.loc:31953?transport=tcp".to_owned(),
-                ],
-                username: "coturn".to_string(),
-                credential: "password".to_string(),
-                ..Default::default()
-            }],
-            ..Default::default()
-        };
-
-        // Setup media engine
-        let mut media_engine = MediaEngine::default();
-        media_engine
-            .register_default_codecs()
-            .context("Failed to register codecs")?;
-
-        let registry = register_default_interceptors(Registry::new(), &mut media_engine)
-            .context("Failed to register interceptors")?;
-
-        let api = APIBuilder::new()
-            .with_media_engine(media_engine)
-            .with_interceptor_registry(registry)
-            .build();
-
-        // Create peer connection
-        let peer_connection = Arc::new(
-            api.new_peer_connection(rtc_config)
-                .await
-                .context("Failed to create peer connection")?,
-        );
-
         // Connect to signaling server
         let (ws_stream, _) =
             tokio::time::timeout(Duration::from_secs(10), connect_async(&self.signaling_url))
@@ -121,10 +85,12 @@ impl WebRTCManager {
             .await
             .context("Failed to send init message")?;
 
+        let init_ack = signaling::receive_init_ack(&mut ws_receiver).await?;
+
         let msg = json!({
             "type": "join",
             "channel_id": &channel_id,
-            "role": "offerer"
+            "role": "speaker"
         })
         .to_string();
 
@@ -135,15 +101,37 @@ impl WebRTCManager {
             .await
             .context("Failed to send join message")?;
 
+        // The ICE servers, TURN credentials included, come from soundhouse
+        let rtc_config = rtc_configuration(&init_ack);
+
+        // Setup media engine
+        let mut media_engine = MediaEngine::default();
+        media_engine
+            .register_default_codecs()
+            .context("Failed to register codecs")?;
+
+        let registry = register_default_interceptors(Registry::new(), &mut media_engine)
+            .context("Failed to register interceptors")?;
+
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .build();
+
+        // Create peer connection
+        let peer_connection = Arc::new(
+            api.new_peer_connection(rtc_config)
+                .await
+                .context("Failed to create peer connection")?,
+        );
+
         // Setup ICE candidate handling
         let ws_sender_clone = ws_sender.clone();
         {
             let channel_id_inner = channel_id.clone();
-            let niche_id = niche_id.clone();
             peer_connection.on_ice_candidate(Box::new(move |candidate| {
                 let ws_sender_inner = ws_sender_clone.clone();
                 let channel_id = channel_id_inner.clone();
-                let niche_id = niche_id.clone();
                 Box::pin(async move {
                     let channel_id = channel_id.clone();
                     if let Some(candidate) = candidate {
@@ -153,7 +141,7 @@ impl WebRTCManager {
                                 "type": "candidate",
                                 "candidate": candidate_json,
                                 "channel_id": &channel_id,
-                                "niche_id": &niche_id
+                                "target_client_id": SFU_CLIENT_ID
                             })
                             .to_string();
                             if let Err(e) = ws_sender_inner
@@ -203,10 +191,8 @@ impl WebRTCManager {
         let cancellation_token_clone = self.cancellation_token.clone();
 
         let channel_id_inner = channel_id.clone();
-        let niche_id_inner = niche_id.clone();
         tokio::spawn(async move {
             let channel_id = channel_id_inner.clone();
-            let niche_id = niche_id_inner.clone();
             println!("DEBUG: Starting WebSocket receiver loop");
 
             loop {
@@ -232,43 +218,53 @@ impl WebRTCManager {
                                                 match json_msg.get("type").and_then(|t| t.as_str()) {
                                                     Some("active_channels") => {
                                                         println!("INFO: Received active_channels message.");
-                                                        if let Some(channels) = json_msg.get("channels").and_then(|v| v.as_object()) {
-                                                            println!("DEBUG: Channels object: {:?}", channels);
-                                                            if let Some(channel) = channels.get(&channel_id) {
-                                                                if let Some(users) = channel.get("users").and_then(|v| v.as_array()) {
-                                                                    println!("DEBUG: Users array: {:?}", users);
-                                                                    if users.iter().any(|u| u.get("role") == Some(&json!("answerer"))) {
-                                                                        println!("INFO: Answerer detected, attempting to send offer...");
-                                                                        if let Some(local_desc) = pc_clone.local_description().await {
-                                                                            let offer_msg = json!({
-                                                                                "type": "offer",
-                                                                                "offer": local_desc.sdp,
-
-                                                                                "niche_id": &niche_id,
-                                                                                "channel_id": &channel_id,
-
-                                                                            })
-                                                                            .to_string();
-
-                                                                            println!("DEBUG: Offer message: {}", offer_msg);
-                                                                            match ws_sender_clone.lock().await.send(Message::Text(offer_msg.into())).await {
-                                                                                Ok(_) => println!("INFO: Offer sent successfully"),
-                                                                                Err(e) => eprintln!("ERROR: Failed to send offer: {:?}", e),
-                                                                            }
-                                                                        } else {
-                                                                            println!("WARNING: No local description available");
-                                                                        }
-                                                                    } else {
-                                                                        println!("DEBUG: No answerer found in users");
-                                                                    }
-                                                                } else {
-                                                                    println!("WARNING: No users array in channel");
+                                                    }
+                                                    Some("offer") => {
+                                                        if let Some(offer) = json_msg.get("offer").and_then(|v| v.as_str()) {
+                                                            println!("INFO: Received renegotiation offer from soundhouse");
+                                                            let offer = match RTCSessionDescription::offer(offer.to_string()) {
+                                                                Ok(offer) => offer,
+                                                                Err(e) => {
+                                                                    eprintln!("ERROR: Invalid offer SDP: {:?}", e);
+                                                                    continue;
+                                                                }
+                                                            };
+
+                                                            if let Err(e) = pc_clone.set_remote_description(offer).await {
+                                                                eprintln!("ERROR: Setting remote offer failed: {:?}", e);
+                                                                continue;
+                                                            }
+
+                                                            let answer = match pc_clone.create_answer(None).await {
+                                                                Ok(answer) => answer,
+                                                                Err(e) => {
+                                                                    eprintln!("ERROR: Creating answer failed: {:?}", e);
+                                                                    continue;
                                                                 }
-                                                            } else {
-                                                                println!("WARNING: Channel ID not found in channels");
+                                                            };
+
+                                                            if let Err(e) = pc_clone.set_local_description(answer.clone()).await {
+                                                                eprintln!("ERROR: Setting local answer failed: {:?}", e);
+                                                                continue;
+                                                            }
+
+                                                            let sender_client_id = json_msg
+                                                                .get("sender_client_id")
+                                                                .and_then(|v| v.as_str())
+                                                                .unwrap_or(SFU_CLIENT_ID);
+
+                                                            let answer_msg = json!({
+                                                                "type": "answer",
+                                                                "answer": answer.sdp,
+                                                                "target_client_id": sender_client_id,
+                                                                "channel_id": &channel_id,
+                                                            })
+                                                            .to_string();
+
+                                                            match ws_sender_clone.lock().await.send(Message::Text(answer_msg.into())).await {
+                                                                Ok(_) => println!("INFO: Answer sent successfully"),
+                                                                Err(e) => eprintln!("ERROR: Failed to send answer: {:?}", e),
                                                             }
-                                                        } else {
-                                                            println!("WARNING: No channels object in message");
                                                         }
                                                     }
                                                     Some("answer") => {
@@ -361,7 +357,7 @@ impl WebRTCManager {
         if let Some(local_desc) = peer_connection.local_description().await {
             let offer_msg = json!({
                 "type": "offer",
-                "niche_id": &niche_id,
+                "target_client_id": SFU_CLIENT_ID,
                 "offer": &local_desc.sdp,
                 "channel_id": channel_id.clone()
             })
@@ -537,3 +533,41 @@ impl WebRTCManager {
         println!("WebRTCManager shutting down");
     }
 }
+
+/// Builds the peer connection configuration from the `ice` section of an
+/// `init_ack`.
+fn rtc_configuration(init_ack: &Value) -> RTCConfiguration {
+    let ice = &init_ack["ice"];
+    let ice_servers = ice["ice_servers"]
+        .as_array()
+        .map(|servers| {
+            servers
+                .iter()
+                .map(|server| RTCIceServer {
+                    urls: server["urls"]
+                        .as_array()
+                        .map(|urls| {
+                            urls.iter()
+                                .filter_map(|url| url.as_str().map(str::to_string))
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                    username: server["username"].as_str().unwrap_or_default().to_string(),
+                    credential: server["credential"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    ..Default::default()
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    RTCConfiguration {
+        ice_servers,
+        ice_transport_policy: RTCIceTransportPolicy::from(
+            ice["ice_transport_policy"].as_str().unwrap_or("all"),
+        ),
+        ..Default::default()
+    }
+}
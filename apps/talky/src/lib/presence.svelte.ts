@@ -1,17 +1,28 @@
 import { getContext } from 'svelte';
 import { user } from './user.svelte.js';
+import { client } from './client';
 import { connectAudio, isTauri } from './tauri/tauri.js';
 import { page } from '$app/state';
 import {
-	type ClientInfoMsg,
 	type IncomingMessage,
 	type OutgoingMessage,
-	type RoomResource
+	type RoomResource,
+	type IceConfig,
+	type ServerLimits,
+	type UserProfileResource,
+	type UserStatus
 } from '@talky/soundhouse';
 import { env } from '$env/dynamic/public';
 
 const MAX_RETRIES = 5;
+const SFU_CLIENT_ID = 'soundhouse';
 const RETRY_DELAY_MS = 3000;
+const PROTOCOL_VERSION = 1;
+const CAPABILITIES = ['resume', 'typing', 'reactions', 'moderation', 'recording', 'presence', 'direct_messages'];
+// Close code the soundhouse uses for protocol errors, which a retry cannot fix
+const PROTOCOL_ERROR_CODE = 1002;
+// Close code we use when leaving an instance that is shutting down
+const SERVER_SHUTDOWN_CODE = 4000;
 
 class EventEmitter {
 	private events: { [key: string]: CallableFunction[] } = {};
@@ -52,7 +63,23 @@ export class Presence extends EventEmitter {
 	peerConnection = $state<RTCPeerConnection | null>(null);
 	lastMessage = $state<IncomingMessage | null>(null);
 	currentNicheId = $state('');
-	activeClients = $state<ClientInfoMsg[]>([]);
+	clientId = $state<string | null>(null);
+	private resumeToken: string | null = null;
+	// Set by a shutdown notice, overrides the retry delay once
+	private reconnectDelayMs: number | null = null;
+	protocolVersion = $state<number | null>(null);
+	serverCapabilities = $state<string[]>([]);
+	limits = $state<ServerLimits | null>(null);
+	// Issued with every init_ack, so a reconnect also renews TURN credentials
+	private iceConfig: IceConfig | null = null;
+	// Everyone online in the user's niches, the user included
+	activeClients = $state<UserProfileResource[]>([]);
+	// Unread direct messages by conversation id, seed it from conversation_list
+	unreadConversations = $state<Partial<Record<string, number>>>({});
+	// The conversation the user is reading, which never counts as unread
+	openConversationId = $state<string | null>(null);
+	// Lobbies being recorded, by channel id, with the recording's id
+	recordings = $state<Partial<Record<string, string>>>({});
 	activeChannels = $state<
 		Partial<{
 			[x: string]: RoomResource;
@@ -107,11 +134,81 @@ export class Presence extends EventEmitter {
 
 		this.sendMessage({
 			type: 'join',
-			role: 'answerer',
+			role: 'speaker',
 			channel_id: channelId
 		} as OutgoingMessage);
 	}
 
+	editMessage(messageId: string, contents: string) {
+		this.sendMessage({ type: 'edit_message', message_id: messageId, contents } as OutgoingMessage);
+	}
+
+	deleteMessage(messageId: string) {
+		this.sendMessage({ type: 'delete_message', message_id: messageId } as OutgoingMessage);
+	}
+
+	react(messageId: string, emoji: string, add: boolean) {
+		this.sendMessage({
+			type: add ? 'add_reaction' : 'remove_reaction',
+			message_id: messageId,
+			emoji
+		} as OutgoingMessage);
+	}
+
+	viewChannel(channelId: string | null) {
+		this.sendMessage({ type: 'update_channel', channel_id: channelId } as OutgoingMessage);
+	}
+
+	setTyping(channelId: string, typing: boolean) {
+		this.sendMessage({
+			type: typing ? 'typing_start' : 'typing_stop',
+			channel_id: channelId
+		} as OutgoingMessage);
+	}
+
+	setSelfMuted(muted: boolean) {
+		this.sendMessage({ type: 'self_mute', muted } as OutgoingMessage);
+	}
+
+	setSelfDeafened(deafened: boolean) {
+		this.sendMessage({ type: 'self_deafen', deafened } as OutgoingMessage);
+	}
+
+	setSpeaking(speaking: boolean) {
+		this.sendMessage({ type: 'speaking', speaking } as OutgoingMessage);
+	}
+
+	setRecording(channelId: string, recording: boolean) {
+		this.sendMessage({ type: 'record', channel_id: channelId, recording } as OutgoingMessage);
+	}
+
+	sendDirectMessage(conversationId: string, content: string) {
+		this.sendMessage({
+			type: 'direct_message',
+			conversation_id: conversationId,
+			content
+		} as OutgoingMessage);
+	}
+
+	async openConversation(conversationId: string | null) {
+		this.openConversationId = conversationId;
+		if (!conversationId) return;
+
+		delete this.unreadConversations[conversationId];
+		const response = await client.conversation_mark_read.mutate(conversationId);
+		if (response.status !== 'ok') {
+			console.error('[Presence] Failed to mark conversation as read:', response.error);
+		}
+	}
+
+	setStatus(status: UserStatus, customStatus: string | null = null) {
+		this.sendMessage({
+			type: 'set_status',
+			status,
+			custom_status: customStatus
+		} as OutgoingMessage);
+	}
+
 	private attemptConnection(token: string | undefined): void {
 		if (!token) {
 			console.warn('[AttemptConnection] Aborted: No token provided.');
@@ -183,7 +280,10 @@ export class Presence extends EventEmitter {
 
 		const initMsg = {
 			type: 'init',
-			auth_code: token
+			auth_code: token,
+			resume_token: this.resumeToken,
+			protocol_version: PROTOCOL_VERSION,
+			capabilities: CAPABILITIES
 		} as OutgoingMessage;
 		try {
 			this.emit('connectionOpen', initMsg);
@@ -203,19 +303,14 @@ export class Presence extends EventEmitter {
 
 	private createPeerConnection() {
 		console.log('[WebRTC] Starting as answerer...');
+		const iceServers: RTCIceServer[] = (this.iceConfig?.ice_servers ?? []).map((server) => ({
+			urls: server.urls,
+			username: server.username ?? undefined,
+			credential: server.credential ?? undefined
+		}));
 		this.peerConnection = new RTCPeerConnection({
-			iceServers: [
-				{
-					urls: [
-						'stun:server.loc:31899',
-						'turn:server.loc:30665?transport=udp',
-						'turn:server.loc:31953?transport=tcp'
-					],
-					username: 'coturn',
-					credential: 'password'
-				}
-			],
-			iceTransportPolicy: 'all'
+			iceServers,
+			iceTransportPolicy: this.iceConfig?.ice_transport_policy ?? 'all'
 		});
 
 		this.peerConnection.onicecandidate = (event) => {
@@ -226,7 +321,7 @@ export class Presence extends EventEmitter {
 						type: 'candidate',
 						candidate: event.candidate as unknown,
 						channel_id: this.connectedChannel!.id,
-						niche_id: page.params.niche
+						target_client_id: SFU_CLIENT_ID
 					} as OutgoingMessage)
 				);
 			}
@@ -351,7 +446,7 @@ export class Presence extends EventEmitter {
 				type: 'answer',
 				answer: answer.sdp,
 				channel_id: this.connectedChannel!.id,
-				niche_id: page.params.niche
+				target_client_id: message.sender_client_id
 			} as OutgoingMessage;
 			this.socket?.send(JSON.stringify(answerMsg));
 			console.log('[Signaling] Sent SDP answer:', answerMsg);
@@ -367,6 +462,17 @@ export class Presence extends EventEmitter {
 			this.lastMessage = message;
 
 			switch (message.type) {
+				case 'init_ack':
+					if (this.clientId && this.clientId !== message.client_id) {
+						console.log('[Presence] Session could not be resumed, starting a new one.');
+					}
+					this.clientId = message.client_id;
+					this.resumeToken = message.resume_token;
+					this.protocolVersion = message.protocol_version;
+					this.serverCapabilities = message.capabilities;
+					this.limits = message.limits;
+					this.iceConfig = message.ice;
+					break;
 				case 'active_channels':
 					this.activeChannels = message.channels;
 
@@ -383,6 +489,74 @@ export class Presence extends EventEmitter {
 					this.emit('activeClientsUpdated', this.activeClients);
 					console.log('[Presence] Updated active clients:', this.activeClients.length);
 					break;
+				case 'presence_update': {
+					const others = this.activeClients.filter(({ id }) => id !== message.user_id);
+					this.activeClients = message.presence ? [...others, message.presence] : others;
+					this.emit('activeClientsUpdated', this.activeClients);
+					break;
+				}
+				case 'lobby_closed':
+					if (this.channelConnection.id === message.channel_id) {
+						this.channelConnection = { status: 'init', id: '' };
+					}
+					this.emit('lobbyClosed', message.channel_id);
+					break;
+				case 'chat_message_updated':
+					this.emit('chatMessageUpdated', message);
+					break;
+				case 'shutdown': {
+					// Spread reconnects out so the other instances are not hit all at once
+					const delayMs = message.reconnect_delay_secs * 1000;
+					this.reconnectDelayMs = delayMs + Math.random() * delayMs;
+					// Sessions do not outlive the instance holding them
+					this.resumeToken = null;
+					console.log('[Presence] Server is shutting down, leaving.');
+					ws.close(SERVER_SHUTDOWN_CODE, 'Server shutting down');
+					break;
+				}
+				case 'disconnected':
+					console.warn('[Presence] Server is closing the connection:', message.reason);
+					this.emit('disconnected', message.reason);
+					break;
+				case 'typing':
+					this.emit('typing', message);
+					break;
+				case 'room_client_update': {
+					const clients = this.activeChannels[message.channel_id]?.users[message.client.user.user_id];
+					const index = clients?.findIndex((c) => c.client_id === message.client.client_id) ?? -1;
+					if (clients && index !== -1) {
+						clients[index] = message.client;
+					}
+					this.emit('roomClientUpdated', message);
+					break;
+				}
+				case 'kicked':
+					if (message.client_id === this.clientId) {
+						this.channelConnection = { status: 'init', id: '' };
+					}
+					this.emit('kicked', message);
+					break;
+				case 'moved':
+					if (message.client_id === this.clientId) {
+						// The server already joined us, connect once the lobby lists us
+						this.channelConnection = { status: 'init', id: message.destination_channel_id };
+					}
+					this.emit('moved', message);
+					break;
+				case 'server_muted':
+					this.emit('serverMuted', message);
+					break;
+				case 'server_deafened':
+					this.emit('serverDeafened', message);
+					break;
+				case 'recording':
+					if (message.recording) {
+						this.recordings[message.channel_id] = message.recording_id;
+					} else if (this.recordings[message.channel_id] === message.recording_id) {
+						delete this.recordings[message.channel_id];
+					}
+					this.emit('recording', message);
+					break;
 				case 'error':
 					console.error('[Presence] Received server error message:', message.message);
 					this.emit('serverError', message.message);
@@ -391,6 +565,17 @@ export class Presence extends EventEmitter {
 					window.dispatchEvent(new CustomEvent('chat', { detail: message }));
 					this.emit('chatMessageReceived', message);
 					break;
+				case 'direct_message_broadcast': {
+					const conversationId = message.conversation_id;
+					if (conversationId === this.openConversationId) {
+						client.conversation_mark_read.mutate(conversationId);
+					} else if (message.message.user_id !== user.user?.sub) {
+						this.unreadConversations[conversationId] =
+							(this.unreadConversations[conversationId] ?? 0) + 1;
+					}
+					this.emit('directMessageReceived', message);
+					break;
+				}
 
 				default:
 					console.warn('[Presence] Received unhandled message :', message);
@@ -427,12 +612,16 @@ export class Presence extends EventEmitter {
 			user.accessToken &&
 			!this.explicitlyClosed &&
 			this.retryCount < MAX_RETRIES &&
-			event.code !== 1000;
+			event.code !== 1000 &&
+			event.code !== PROTOCOL_ERROR_CODE;
+
+		const retryDelayMs = this.reconnectDelayMs ?? RETRY_DELAY_MS;
+		this.reconnectDelayMs = null;
 
 		if (shouldAttemptRetry) {
 			this.retryCount++;
 			console.log(
-				`[Presence] Connection closed unexpectedly. Scheduling retry ${this.retryCount}/${MAX_RETRIES} in ${RETRY_DELAY_MS}ms...`
+				`[Presence] Connection closed unexpectedly. Scheduling retry ${this.retryCount}/${MAX_RETRIES} in ${retryDelayMs}ms...`
 			);
 			this.status = 'reconnecting';
 			this.retryTimer = setTimeout(() => {
@@ -447,7 +636,7 @@ export class Presence extends EventEmitter {
 					this.status = user.accessToken ? 'closed' : 'idle';
 					if (!user.accessToken) this.retryCount = 0;
 				}
-			}, RETRY_DELAY_MS);
+			}, retryDelayMs);
 		} else {
 			console.log('[Presence] No retry condition met upon close.');
 			if (this.retryCount >= MAX_RETRIES && !this.explicitlyClosed && user.accessToken) {
@@ -522,6 +711,8 @@ export class Presence extends EventEmitter {
 		console.log(`[Presence Disconnect] Manual disconnect called (isLogout: ${isLogout}).`);
 		this.explicitlyClosed = true;
 		this.clearRetryTimer();
+		// A clean close ends the session on the server, so there is nothing to resume
+		this.resumeToken = null;
 
 		const ws = this.socket;
 		if (ws) {
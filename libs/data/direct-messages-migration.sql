@@ -0,0 +1,43 @@
+-- Private conversations between a handful of users, outside of any niche.
+-- Ids are ULIDs and compare bytewise, so ordering by id orders by time.
+
+-- Two users only ever share one conversation of their own, member_pair holds
+-- their ids sorted bytewise. It is null for groups.
+CREATE TABLE IF NOT EXISTS public.conversations (
+    id text COLLATE "C" NOT NULL,
+    member_pair text[] COLLATE "C",
+    created_at timestamp(3) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    CONSTRAINT conversations_pkey PRIMARY KEY (id),
+    CONSTRAINT conversations_member_pair_key UNIQUE (member_pair)
+);
+
+ALTER TABLE public.conversations OWNER TO postgres;
+
+-- Messages after last_read_message_id that someone else sent are unread.
+CREATE TABLE IF NOT EXISTS public.conversation_members (
+    conversation_id text COLLATE "C" NOT NULL,
+    user_id text NOT NULL,
+    last_read_message_id text COLLATE "C",
+    CONSTRAINT conversation_members_pkey PRIMARY KEY (conversation_id, user_id),
+    CONSTRAINT conversation_members_conversation_id_fkey FOREIGN KEY (conversation_id) REFERENCES public.conversations(id) ON UPDATE CASCADE ON DELETE CASCADE,
+    CONSTRAINT conversation_members_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS conversation_members_user_id_idx ON public.conversation_members (user_id);
+
+ALTER TABLE public.conversation_members OWNER TO postgres;
+
+CREATE TABLE IF NOT EXISTS public.direct_messages (
+    id text COLLATE "C" NOT NULL,
+    conversation_id text COLLATE "C" NOT NULL,
+    user_id text NOT NULL,
+    contents text NOT NULL,
+    created_at timestamp(3) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    CONSTRAINT direct_messages_pkey PRIMARY KEY (id),
+    CONSTRAINT direct_messages_conversation_id_fkey FOREIGN KEY (conversation_id) REFERENCES public.conversations(id) ON UPDATE CASCADE ON DELETE CASCADE,
+    CONSTRAINT direct_messages_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id) ON UPDATE CASCADE ON DELETE RESTRICT
+);
+
+CREATE INDEX IF NOT EXISTS direct_messages_conversation_id_idx ON public.direct_messages (conversation_id, id);
+
+ALTER TABLE public.direct_messages OWNER TO postgres;
@@ -0,0 +1,40 @@
+-- Edits, deletions and reactions on chat messages.
+
+-- Previous contents of edited messages. The latest row is when the message
+-- was last edited.
+CREATE TABLE IF NOT EXISTS public.message_edits (
+    id bigserial NOT NULL,
+    message_id text NOT NULL,
+    contents text NOT NULL,
+    edited_at timestamp(3) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    CONSTRAINT message_edits_pkey PRIMARY KEY (id),
+    CONSTRAINT message_edits_message_id_fkey FOREIGN KEY (message_id) REFERENCES public.messages(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS message_edits_message_id_idx ON public.message_edits (message_id);
+
+ALTER TABLE public.message_edits OWNER TO postgres;
+
+-- Deleted messages keep their row so that replies and pagination stay intact.
+CREATE TABLE IF NOT EXISTS public.message_deletions (
+    message_id text NOT NULL,
+    deleted_by text NOT NULL,
+    deleted_at timestamp(3) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    CONSTRAINT message_deletions_pkey PRIMARY KEY (message_id),
+    CONSTRAINT message_deletions_message_id_fkey FOREIGN KEY (message_id) REFERENCES public.messages(id) ON UPDATE CASCADE ON DELETE CASCADE,
+    CONSTRAINT message_deletions_deleted_by_fkey FOREIGN KEY (deleted_by) REFERENCES public.users(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+ALTER TABLE public.message_deletions OWNER TO postgres;
+
+CREATE TABLE IF NOT EXISTS public.message_reactions (
+    message_id text NOT NULL,
+    user_id text NOT NULL,
+    emoji text NOT NULL,
+    created_at timestamp(3) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    CONSTRAINT message_reactions_pkey PRIMARY KEY (message_id, user_id, emoji),
+    CONSTRAINT message_reactions_message_id_fkey FOREIGN KEY (message_id) REFERENCES public.messages(id) ON UPDATE CASCADE ON DELETE CASCADE,
+    CONSTRAINT message_reactions_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+ALTER TABLE public.message_reactions OWNER TO postgres;
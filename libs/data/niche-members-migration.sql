@@ -0,0 +1,34 @@
+-- Per-niche roles. Only users with a row are members of a niche, they get one
+-- when they join it. Users who already own a lobby or posted in one of the
+-- niche's channels are backfilled as regular members.
+
+CREATE TYPE public.niche_role AS ENUM (
+    'member',
+    'moderator',
+    'admin'
+);
+
+ALTER TYPE public.niche_role OWNER TO postgres;
+
+CREATE TABLE public.niche_members (
+    niche_id text NOT NULL,
+    user_id text NOT NULL,
+    role public.niche_role DEFAULT 'member'::public.niche_role NOT NULL,
+    CONSTRAINT niche_members_pkey PRIMARY KEY (niche_id, user_id),
+    CONSTRAINT niche_members_niche_id_fkey FOREIGN KEY (niche_id) REFERENCES public.niches(id) ON UPDATE CASCADE ON DELETE CASCADE,
+    CONSTRAINT niche_members_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+ALTER TABLE public.niche_members OWNER TO postgres;
+
+INSERT INTO public.niche_members (niche_id, user_id)
+SELECT categories.niche_id, lobbies.owner_user_id
+FROM public.lobbies
+JOIN public.channels ON channels.id = lobbies.channel_id
+JOIN public.categories ON categories.id = channels.category_id
+UNION
+SELECT categories.niche_id, messages.user_id
+FROM public.messages
+JOIN public.channels ON channels.id = messages.channel_id
+JOIN public.categories ON categories.id = channels.category_id
+ON CONFLICT DO NOTHING;
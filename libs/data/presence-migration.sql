@@ -0,0 +1,20 @@
+-- Profile data and the status users pick for themselves, which soundhouse
+-- shows to everyone sharing a niche with them. Being offline is not stored,
+-- it follows from having no connection. Presence only reaches users with a
+-- niche_members row in one of the user's niches.
+
+CREATE TYPE public.user_status AS ENUM (
+    'online',
+    'idle',
+    'dnd',
+    'invisible'
+);
+
+ALTER TYPE public.user_status OWNER TO postgres;
+
+ALTER TABLE public.users ADD COLUMN IF NOT EXISTS display_name text;
+ALTER TABLE public.users ADD COLUMN IF NOT EXISTS avatar_url text;
+ALTER TABLE public.users ADD COLUMN IF NOT EXISTS status public.user_status DEFAULT 'online'::public.user_status NOT NULL;
+ALTER TABLE public.users ADD COLUMN IF NOT EXISTS custom_status text;
+
+CREATE INDEX IF NOT EXISTS niche_members_user_id_idx ON public.niche_members (user_id);
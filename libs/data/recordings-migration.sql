@@ -0,0 +1,18 @@
+-- Recordings soundhouse makes of lobbies. The files live under
+-- SOUNDHOUSE_RECORDINGS_DIR in a directory named after the recording id.
+-- Recordings outlive their lobby, which temporary lobbies rarely do.
+
+CREATE TABLE IF NOT EXISTS public.lobby_recordings (
+    id text NOT NULL,
+    lobby_id text,
+    owner_user_id text NOT NULL,
+    started_at timestamp(3) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    stopped_at timestamp(3) without time zone,
+    CONSTRAINT lobby_recordings_pkey PRIMARY KEY (id),
+    CONSTRAINT lobby_recordings_lobby_id_fkey FOREIGN KEY (lobby_id) REFERENCES public.lobbies(id) ON UPDATE CASCADE ON DELETE SET NULL,
+    CONSTRAINT lobby_recordings_owner_user_id_fkey FOREIGN KEY (owner_user_id) REFERENCES public.users(id) ON UPDATE CASCADE ON DELETE RESTRICT
+);
+
+CREATE INDEX IF NOT EXISTS lobby_recordings_lobby_id_idx ON public.lobby_recordings (lobby_id);
+
+ALTER TABLE public.lobby_recordings OWNER TO postgres;
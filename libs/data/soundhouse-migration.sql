@@ -0,0 +1,53 @@
+-- Shared presence for running several soundhouse instances against one database.
+-- The rows only describe live websocket connections, so the tables are unlogged.
+
+-- Every running instance bumps its heartbeat every few seconds. Clients and
+-- room members of instances that stop doing so are reaped by the others.
+CREATE UNLOGGED TABLE IF NOT EXISTS public.soundhouse_instances (
+    instance_id text NOT NULL,
+    heartbeat_at timestamp with time zone DEFAULT now() NOT NULL,
+    CONSTRAINT soundhouse_instances_pkey PRIMARY KEY (instance_id)
+);
+
+CREATE UNLOGGED TABLE IF NOT EXISTS public.soundhouse_clients (
+    client_id text NOT NULL,
+    instance_id text NOT NULL,
+    data jsonb NOT NULL,
+    created_at timestamp with time zone DEFAULT now() NOT NULL,
+    CONSTRAINT soundhouse_clients_pkey PRIMARY KEY (client_id)
+);
+
+CREATE INDEX IF NOT EXISTS soundhouse_clients_instance_id_idx ON public.soundhouse_clients (instance_id);
+CREATE INDEX IF NOT EXISTS soundhouse_clients_user_id_idx ON public.soundhouse_clients ((data->>'user_id'));
+
+CREATE UNLOGGED TABLE IF NOT EXISTS public.soundhouse_room_members (
+    client_id text NOT NULL,
+    niche_id text NOT NULL,
+    lobby_id text NOT NULL,
+    instance_id text NOT NULL,
+    data jsonb NOT NULL,
+    created_at timestamp with time zone DEFAULT now() NOT NULL,
+    CONSTRAINT soundhouse_room_members_pkey PRIMARY KEY (client_id, lobby_id)
+);
+
+CREATE INDEX IF NOT EXISTS soundhouse_room_members_niche_id_idx ON public.soundhouse_room_members (niche_id);
+CREATE INDEX IF NOT EXISTS soundhouse_room_members_lobby_id_idx ON public.soundhouse_room_members (lobby_id);
+
+-- Server mutes and deafens outlast the user leaving the room, and instances
+-- restarting, so unlike the tables above this one is logged.
+CREATE TABLE IF NOT EXISTS public.soundhouse_moderation (
+    lobby_id text NOT NULL,
+    user_id text NOT NULL,
+    server_muted boolean DEFAULT false NOT NULL,
+    server_deafened boolean DEFAULT false NOT NULL,
+    CONSTRAINT soundhouse_moderation_pkey PRIMARY KEY (lobby_id, user_id)
+);
+
+-- NOTIFY payloads are capped at 8000 bytes; larger envelopes are parked here
+-- and only their id is sent over the channel.
+CREATE UNLOGGED TABLE IF NOT EXISTS public.soundhouse_outbox (
+    id bigserial NOT NULL,
+    payload jsonb NOT NULL,
+    created_at timestamp with time zone DEFAULT now() NOT NULL,
+    CONSTRAINT soundhouse_outbox_pkey PRIMARY KEY (id)
+);
@@ -0,0 +1,8 @@
+-- Lobbies created through lobby_create_temporary carry a contract that says
+-- when soundhouse may delete them.
+
+ALTER TABLE public.lobbies ADD COLUMN IF NOT EXISTS expire_when_empty boolean DEFAULT false NOT NULL;
+ALTER TABLE public.lobbies ADD COLUMN IF NOT EXISTS expires_at timestamp with time zone;
+ALTER TABLE public.lobbies ADD COLUMN IF NOT EXISTS created_at timestamp with time zone DEFAULT now() NOT NULL;
+
+CREATE INDEX IF NOT EXISTS lobbies_expires_at_idx ON public.lobbies (expires_at) WHERE expires_at IS NOT NULL;
@@ -20,6 +20,17 @@ use crate::{
 
 use super::service::{ChannelResource, ChannelType, ListChannelArgs};
 
+// id, name, slug, type, category_id, niche_id, lobbies
+type ChannelRow = (
+    String,
+    String,
+    String,
+    ChannelType,
+    String,
+    String,
+    Option<serde_json::Value>,
+);
+
 pub(crate) struct ChannelRepository {
     connection: DatabasePool,
 }
@@ -65,7 +76,21 @@ impl ChannelRepository {
         self.find_one_by("slug", slug).await
     }
 
+    pub async fn find_optional_by_id(&self, id: String) -> AppResult<Option<ChannelModel>> {
+        self.find_optional_by("id", id).await
+    }
+
     async fn find_one_by(&self, column: &str, value: String) -> AppResult<ChannelModel> {
+        self.find_optional_by(column, value)
+            .await?
+            .ok_or_else(|| ServicesError::from(sqlx::Error::RowNotFound))
+    }
+
+    async fn find_optional_by(
+        &self,
+        column: &str,
+        value: String,
+    ) -> AppResult<Option<ChannelModel>> {
         let query_string = format!(
             r#"select
                     id,
@@ -81,20 +106,16 @@ impl ChannelRepository {
             column
         );
 
-        let row: (
-            String,
-            String,
-            String,
-            ChannelType,
-            String,
-            String,
-            Option<serde_json::Value>,
-        ) = query_as(&query_string)
+        let row: Option<ChannelRow> = query_as(&query_string)
             .bind(value)
-            .fetch_one(self.connection.as_ref())
+            .fetch_optional(self.connection.as_ref())
             .await
             .map_err(ServicesError::from)?;
 
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
         let (id, name, slug, r#type, category_id, niche_id, json_lobbies) = row;
 
         // Manually convert JsonValue into Vec<LobbyModel>
@@ -103,7 +124,7 @@ impl ChannelRepository {
             None => vec![],
         };
 
-        Ok(ChannelModel {
+        Ok(Some(ChannelModel {
             id,
             name,
             slug,
@@ -111,7 +132,7 @@ impl ChannelRepository {
             category_id,
             niche_id,
             lobbies,
-        })
+        }))
     }
 }
 
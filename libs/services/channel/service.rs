@@ -112,4 +112,12 @@ impl ChannelService {
     pub async fn find_by_id(&self, id: String) -> AppResult<ChannelResource> {
         Ok(self.repository.find_by_id(id).await?.to_node())
     }
+
+    pub async fn find_optional_by_id(&self, id: String) -> AppResult<Option<ChannelResource>> {
+        Ok(self
+            .repository
+            .find_optional_by_id(id)
+            .await?
+            .map(|channel| channel.to_node()))
+    }
 }
@@ -0,0 +1,2 @@
+mod repository;
+pub mod service;
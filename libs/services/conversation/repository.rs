@@ -0,0 +1,390 @@
+use std::fmt::{self, Display};
+
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use sqlx::{query, query_as, query_scalar, types::time::PrimitiveDateTime};
+
+use crate::{
+    error::{AppResult, ServicesError},
+    pagination::{Cursor, Model},
+    repository::{CursorDirection, Repository},
+    DatabasePool,
+};
+
+use super::service::{
+    ConversationResource, DirectMessageResource, ListConversationArgs, ListDirectMessageArgs,
+};
+
+pub(crate) struct ConversationRepository {
+    connection: DatabasePool,
+}
+
+pub(crate) struct ConversationModel {
+    pub(super) id: String,
+    pub(super) member_ids: Vec<String>,
+    pub(super) unread_count: i64,
+    pub(super) last_message_id: Option<String>,
+    pub(super) last_message_user_id: Option<String>,
+    pub(super) last_message_contents: Option<String>,
+    pub(super) last_message_created_at: Option<PrimitiveDateTime>,
+}
+
+impl Model<ConversationResource> for ConversationModel {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn to_node(&self) -> ConversationResource {
+        let last_message = match (
+            &self.last_message_id,
+            &self.last_message_user_id,
+            &self.last_message_contents,
+            self.last_message_created_at,
+        ) {
+            (Some(id), Some(user_id), Some(contents), Some(created_at)) => Some(
+                DirectMessageModel {
+                    id: id.clone(),
+                    conversation_id: self.id.clone(),
+                    user_id: user_id.clone(),
+                    contents: contents.clone(),
+                    created_at,
+                }
+                .to_node(),
+            ),
+            _ => None,
+        };
+
+        ConversationResource {
+            id: self.id.clone(),
+            member_ids: self.member_ids.clone(),
+            last_message,
+            unread_count: self.unread_count as i32,
+        }
+    }
+}
+
+pub(crate) struct DirectMessageModel {
+    pub(super) id: String,
+    pub(super) conversation_id: String,
+    pub(super) user_id: String,
+    pub(super) contents: String,
+    pub(super) created_at: PrimitiveDateTime,
+}
+
+impl Model<DirectMessageResource> for DirectMessageModel {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn to_node(&self) -> DirectMessageResource {
+        DirectMessageResource {
+            id: self.id.clone(),
+            conversation_id: self.conversation_id.clone(),
+            user_id: self.user_id.clone(),
+            timestamp: (self.created_at.assume_utc().unix_timestamp() * 1000).to_string(),
+            contents: self.contents.clone(),
+        }
+    }
+}
+
+/// Splits a page cursor into the ids to page after and before.
+fn cursor_ids(cursor: Option<(CursorDirection, impl Cursor)>) -> (Option<String>, Option<String>) {
+    match cursor {
+        Some((CursorDirection::After, cursor)) => (Some(cursor.id()), None),
+        Some((CursorDirection::Before, cursor)) => (None, Some(cursor.id())),
+        None => (None, None),
+    }
+}
+
+impl ConversationRepository {
+    pub fn new(connection: DatabasePool) -> Self {
+        Self { connection }
+    }
+
+    /// The user's conversations ordered by their latest message, or by when
+    /// they were started if nobody wrote yet. Pages before a cursor are
+    /// fetched oldest first.
+    async fn find_conversations(
+        &self,
+        user_id: &str,
+        after: Option<String>,
+        before: Option<String>,
+        id: Option<&str>,
+        take: i32,
+    ) -> AppResult<Vec<ConversationModel>> {
+        query_as!(
+            ConversationModel,
+            r#"with member_conversations as (
+                select
+                    cm.conversation_id as id,
+                    (select array_agg(user_id order by user_id) from conversation_members
+                        where conversation_id = cm.conversation_id) as member_ids,
+                    (select count(*) from direct_messages dm
+                        where dm.conversation_id = cm.conversation_id and dm.user_id <> cm.user_id
+                        and dm.id > coalesce(cm.last_read_message_id, '')) as unread_count,
+                    last_message.id as last_message_id,
+                    last_message.user_id as last_message_user_id,
+                    last_message.contents as last_message_contents,
+                    last_message.created_at as last_message_created_at,
+                    coalesce(last_message.id, cm.conversation_id) as activity_id
+                from conversation_members cm
+                left join lateral (
+                    select id, user_id, contents, created_at from direct_messages
+                    where conversation_id = cm.conversation_id
+                    order by id desc limit 1
+                ) last_message on true
+                where cm.user_id = $1
+            )
+            select
+                id as "id!",
+                member_ids as "member_ids!",
+                unread_count as "unread_count!",
+                last_message_id as "last_message_id?",
+                last_message_user_id as "last_message_user_id?",
+                last_message_contents as "last_message_contents?",
+                last_message_created_at as "last_message_created_at?"
+            from member_conversations
+            where ($4::text is null or id = $4)
+            and ($2::text is null
+                or (activity_id, id) < (select activity_id, id from member_conversations where id = $2))
+            and ($3::text is null
+                or (activity_id, id) > (select activity_id, id from member_conversations where id = $3))
+            order by
+                case when $3::text is null then null else activity_id end,
+                case when $3::text is null then null else id end,
+                activity_id desc,
+                id desc
+            limit $5"#,
+            user_id,
+            after,
+            before,
+            id,
+            take as i64
+        )
+        .fetch_all(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)
+    }
+
+    pub async fn find_for_member(
+        &self,
+        id: &str,
+        user_id: &str,
+    ) -> AppResult<Option<ConversationModel>> {
+        Ok(self
+            .find_conversations(user_id, None, None, Some(id), 1)
+            .await?
+            .pop())
+    }
+
+    pub async fn list_member_ids(&self, id: &str) -> AppResult<Vec<String>> {
+        query_scalar!(
+            "select user_id from conversation_members where conversation_id = $1 order by user_id",
+            id
+        )
+        .fetch_all(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)
+    }
+
+    pub async fn count_users(&self, ids: &[String]) -> AppResult<i64> {
+        let count = query_scalar!("select count(*) from users where id = any($1)", ids)
+            .fetch_one(self.connection.as_ref())
+            .await?;
+
+        Ok(count.unwrap_or(0))
+    }
+
+    /// Creates a conversation between the members, which come sorted. Two
+    /// members who have a conversation already get theirs back instead.
+    pub async fn create(&self, member_ids: &[String]) -> AppResult<String> {
+        let id = ulid::Ulid::new().to_string();
+        let member_pair = (member_ids.len() == 2).then_some(member_ids);
+        let mut transaction = self.connection.begin().await?;
+
+        let created = query_scalar!(
+            "insert into conversations (id, member_pair) values ($1, $2)
+                on conflict (member_pair) do nothing
+                returning id",
+            id,
+            member_pair as Option<&[String]>
+        )
+        .fetch_optional(&mut *transaction)
+        .await?;
+        if created.is_none() {
+            return query_scalar!(
+                "select id from conversations where member_pair = $1",
+                member_pair as Option<&[String]>
+            )
+            .fetch_one(&mut *transaction)
+            .await
+            .map_err(ServicesError::from);
+        }
+
+        query!(
+            "insert into conversation_members (conversation_id, user_id) select $1, unnest($2::text[])",
+            id,
+            member_ids
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(id)
+    }
+
+    pub async fn add_message(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        contents: &str,
+    ) -> AppResult<DirectMessageModel> {
+        let id = ulid::Ulid::new().to_string();
+        let mut transaction = self.connection.begin().await?;
+
+        let message = query_as!(
+            DirectMessageModel,
+            "insert into direct_messages (id, conversation_id, user_id, contents)
+                values ($1, $2, $3, $4)
+                returning id, conversation_id, user_id, contents, created_at",
+            id,
+            conversation_id,
+            user_id,
+            contents
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        query!(
+            "update conversation_members set last_read_message_id = $3
+                where conversation_id = $1 and user_id = $2",
+            conversation_id,
+            user_id,
+            id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(message)
+    }
+
+    pub async fn mark_read(&self, id: &str, user_id: &str) -> AppResult<()> {
+        query!(
+            "update conversation_members
+                set last_read_message_id = (select max(id) from direct_messages where conversation_id = $1)
+                where conversation_id = $1 and user_id = $2",
+            id,
+            user_id
+        )
+        .execute(self.connection.as_ref())
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Type, Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ConversationCursor {
+    pub id: String,
+}
+
+impl Cursor for ConversationCursor {
+    type CursorType = ConversationCursor;
+
+    fn encode(cursor: &ConversationCursor) -> String {
+        let cursor_str = cursor.to_string();
+        general_purpose::STANDARD.encode(cursor_str)
+    }
+
+    fn decode(encoded: &str) -> Option<ConversationCursor> {
+        let decoded_bytes = general_purpose::STANDARD.decode(encoded).ok()?;
+        let decoded_str = String::from_utf8(decoded_bytes).ok()?;
+        serde_json::from_str(&decoded_str).ok()
+    }
+
+    fn sort_key(&self) -> String {
+        String::default()
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+impl Display for ConversationCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_string(self) {
+            Ok(json) => write!(f, "{}", json),
+            Err(_) => write!(f, ""),
+        }
+    }
+}
+
+impl Repository<ConversationModel, ListConversationArgs> for ConversationRepository {
+    async fn count(&self, args: &ListConversationArgs) -> AppResult<i32> {
+        let count = query_scalar!(
+            "select count(*) from conversation_members where user_id = $1",
+            args.user_id
+        )
+        .fetch_one(self.connection.as_ref())
+        .await?;
+
+        Ok(count.unwrap_or(0) as i32)
+    }
+
+    async fn find(
+        &self,
+        cursor: Option<(CursorDirection, impl Cursor + Send)>,
+        take: i32,
+        args: &ListConversationArgs,
+    ) -> AppResult<Vec<ConversationModel>> {
+        let (after, before) = cursor_ids(cursor);
+
+        self.find_conversations(&args.user_id, after, before, None, take)
+            .await
+    }
+}
+
+impl Repository<DirectMessageModel, ListDirectMessageArgs> for ConversationRepository {
+    async fn count(&self, args: &ListDirectMessageArgs) -> AppResult<i32> {
+        let count = query_scalar!(
+            "select count(*) from direct_messages where conversation_id = $1",
+            args.conversation_id
+        )
+        .fetch_one(self.connection.as_ref())
+        .await?;
+
+        Ok(count.unwrap_or(0) as i32)
+    }
+
+    async fn find(
+        &self,
+        cursor: Option<(CursorDirection, impl Cursor + Send)>,
+        take: i32,
+        args: &ListDirectMessageArgs,
+    ) -> AppResult<Vec<DirectMessageModel>> {
+        let (after, before) = cursor_ids(cursor);
+
+        // Newest first, pages before a cursor are fetched oldest first
+        query_as!(
+            DirectMessageModel,
+            "select id, conversation_id, user_id, contents, created_at from direct_messages
+                where conversation_id = $1
+                and ($2::text is null or id < $2)
+                and ($3::text is null or id > $3)
+                order by case when $3::text is null then null else id end, id desc
+                limit $4",
+            args.conversation_id,
+            after,
+            before,
+            take as i64
+        )
+        .fetch_all(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)
+    }
+}
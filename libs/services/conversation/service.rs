@@ -0,0 +1,229 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::{
+    error::AppResult,
+    pagination::{
+        connection_from_repository, ListResult, Model, Node, PaginationArgs, WithPagination,
+    },
+    DatabasePool,
+};
+
+use super::repository::{ConversationCursor, ConversationRepository};
+
+// Counting the creator, enough for a small group
+pub const MAX_CONVERSATION_MEMBERS: usize = 10;
+
+pub const MAX_DIRECT_MESSAGE_CHARS: usize = 4000;
+
+#[derive(Type, Serialize, Deserialize, Default, Debug)]
+pub struct ListConversationMeta {}
+
+/// The conversations of `user_id`, most recently active first.
+#[derive(Type, Deserialize, Serialize, Debug)]
+pub struct ListConversationArgs {
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub first: Option<i32>,
+    pub last: Option<i32>,
+    /// Filled in from the session, never by the caller.
+    #[serde(skip)]
+    pub user_id: String,
+}
+
+impl WithPagination for ListConversationArgs {
+    fn pagination(&self) -> PaginationArgs {
+        PaginationArgs {
+            before: self.before.clone(),
+            after: self.after.clone(),
+            first: self.first,
+            last: self.last,
+        }
+    }
+
+    type Meta = ListConversationMeta;
+    type CursorType = ConversationCursor;
+
+    fn get_meta(&self) -> Self::Meta {
+        ListConversationMeta {}
+    }
+
+    fn to_cursor(&self, id: String) -> Self::CursorType {
+        ConversationCursor { id }
+    }
+}
+
+#[derive(Type, Serialize, Deserialize, Default, Debug)]
+pub struct ListDirectMessageMeta {}
+
+/// The messages of a conversation, newest first.
+#[derive(Type, Deserialize, Serialize, Debug)]
+pub struct ListDirectMessageArgs {
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub first: Option<i32>,
+    pub last: Option<i32>,
+    pub conversation_id: String,
+}
+
+impl WithPagination for ListDirectMessageArgs {
+    fn pagination(&self) -> PaginationArgs {
+        PaginationArgs {
+            before: self.before.clone(),
+            after: self.after.clone(),
+            first: self.first,
+            last: self.last,
+        }
+    }
+
+    type Meta = ListDirectMessageMeta;
+    type CursorType = ConversationCursor;
+
+    fn get_meta(&self) -> Self::Meta {
+        ListDirectMessageMeta {}
+    }
+
+    fn to_cursor(&self, id: String) -> Self::CursorType {
+        ConversationCursor { id }
+    }
+}
+
+#[derive(Type, Deserialize, Serialize, Debug)]
+pub struct CreateConversationArgs {
+    /// Everyone to talk to, without the creator.
+    pub user_ids: Vec<String>,
+}
+
+pub struct ConversationService {
+    repository: Arc<ConversationRepository>,
+}
+
+#[derive(Type, Serialize, Deserialize, Debug, Clone)]
+pub struct DirectMessageResource {
+    pub id: String,
+    pub conversation_id: String,
+    pub user_id: String,
+    pub timestamp: String,
+    pub contents: String,
+}
+
+/// A conversation as one of its members sees it.
+#[derive(Type, Serialize, Deserialize, Debug, Clone)]
+pub struct ConversationResource {
+    pub id: String,
+    pub member_ids: Vec<String>,
+    pub last_message: Option<DirectMessageResource>,
+    /// Messages from others the member has not read yet.
+    pub unread_count: i32,
+}
+
+impl Node for ConversationResource {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+impl Node for DirectMessageResource {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+impl ConversationService {
+    pub fn new(pool: DatabasePool) -> Self {
+        Self {
+            repository: Arc::new(ConversationRepository::new(pool)),
+        }
+    }
+
+    pub async fn list(
+        &self,
+        args: &ListConversationArgs,
+    ) -> AppResult<ListResult<ConversationResource, ListConversationMeta>> {
+        let result = connection_from_repository(args, self.repository.clone()).await?;
+
+        Ok(newest_first(result, args.before.is_some()))
+    }
+
+    pub async fn list_messages(
+        &self,
+        args: &ListDirectMessageArgs,
+    ) -> AppResult<ListResult<DirectMessageResource, ListDirectMessageMeta>> {
+        let result = connection_from_repository(args, self.repository.clone()).await?;
+
+        Ok(newest_first(result, args.before.is_some()))
+    }
+
+    /// Starts a conversation between the creator and `member_ids`. Two users
+    /// only ever have one conversation of their own, which is returned if it
+    /// exists. Returns `None` if one of the users does not exist.
+    pub async fn create(
+        &self,
+        creator_id: &str,
+        member_ids: &[String],
+    ) -> AppResult<Option<ConversationResource>> {
+        let mut member_ids = member_ids.to_vec();
+        member_ids.push(creator_id.to_string());
+        member_ids.sort();
+        member_ids.dedup();
+
+        if self.repository.count_users(&member_ids).await? != member_ids.len() as i64 {
+            return Ok(None);
+        }
+
+        let id = self.repository.create(&member_ids).await?;
+
+        self.find_for_member(&id, creator_id).await
+    }
+
+    pub async fn find_for_member(
+        &self,
+        id: &str,
+        user_id: &str,
+    ) -> AppResult<Option<ConversationResource>> {
+        Ok(self
+            .repository
+            .find_for_member(id, user_id)
+            .await?
+            .map(|model| model.to_node()))
+    }
+
+    /// Everyone in the conversation, or nobody if it does not exist.
+    pub async fn list_member_ids(&self, id: &str) -> AppResult<Vec<String>> {
+        self.repository.list_member_ids(id).await
+    }
+
+    /// Stores a message, which its sender has read.
+    pub async fn add_message(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        contents: &str,
+    ) -> AppResult<DirectMessageResource> {
+        Ok(self
+            .repository
+            .add_message(conversation_id, user_id, contents)
+            .await?
+            .to_node())
+    }
+
+    /// Marks every message in the conversation as read by the user.
+    pub async fn mark_read(&self, id: &str, user_id: &str) -> AppResult<()> {
+        self.repository.mark_read(id, user_id).await
+    }
+}
+
+/// Pages before a cursor come from the repository nearest to the cursor
+/// first, so that the one fetched too many is dropped from their far end.
+/// They are turned around here to list newest first like the others.
+fn newest_first<T: Node, M>(mut result: ListResult<T, M>, paging_back: bool) -> ListResult<T, M> {
+    if paging_back {
+        result.edges.reverse();
+        let page_info = &mut result.page_info;
+        std::mem::swap(&mut page_info.start_cursor, &mut page_info.end_cursor);
+    }
+
+    result
+}
@@ -1,10 +1,12 @@
 pub mod category;
 pub mod channel;
+pub mod conversation;
 pub mod error;
 pub mod lobby;
 pub mod message;
 pub mod niche;
 pub mod pagination;
+pub mod recording;
 mod repository;
 pub mod user;
 
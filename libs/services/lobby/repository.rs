@@ -17,7 +17,7 @@ use crate::{
     DatabasePool,
 };
 
-use super::service::{CreateLobbyArgs, ListLobbyArgs, LobbyResource, LobbyType};
+use super::service::{CreateLobbyArgs, ListLobbyArgs, LobbyResource, LobbyType, TemporaryContract};
 
 pub(crate) struct LobbyRepository {
     connection: DatabasePool,
@@ -81,13 +81,20 @@ impl LobbyRepository {
         owner_user_id: &str,
     ) -> AppResult<LobbyModel> {
         let id = ulid::Ulid::new().to_string();
+        let (expire_when_empty, expires_in) = match args.contract {
+            TemporaryContract::ExpireWhenEmpty => (true, None),
+            TemporaryContract::Expires { expires } => (false, Some(expires)),
+        };
 
         query!(
-            "insert into lobbies (id, name, channel_id, owner_user_id) values ($1, $2, $3, $4)",
+            "insert into lobbies (id, name, channel_id, owner_user_id, expire_when_empty, expires_at)
+                values ($1, $2, $3, $4, $5, now() + make_interval(secs => $6))",
             id,
             args.name,
             args.channel_id,
             owner_user_id,
+            expire_when_empty,
+            expires_in.map(f64::from),
         )
         .execute(self.connection.as_ref())
         .await
@@ -95,6 +102,64 @@ impl LobbyRepository {
 
         self.find_by_id(id).await
     }
+
+    pub async fn delete_if_expire_when_empty(&self, id: &str) -> AppResult<bool> {
+        let result = query!(
+            "delete from lobbies where id = $1 and expire_when_empty",
+            id
+        )
+        .execute(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn delete_expired(&self) -> AppResult<Vec<LobbyModel>> {
+        query_as!(
+            LobbyModel,
+            r#"with expired as (
+                delete from lobbies where expires_at <= now() returning *
+            )
+            select
+                expired.id,
+                expired.name,
+                expired.channel_id,
+                expired.owner_user_id,
+                niches.id as niche_id
+
+                from expired
+                join channels on channels.id = expired.channel_id
+                join categories on categories.id = channels.category_id
+                join niches on niches.id = categories.niche_id"#
+        )
+        .fetch_all(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)
+    }
+
+    pub async fn list_expire_when_empty(&self, min_age: i32) -> AppResult<Vec<LobbyModel>> {
+        query_as!(
+            LobbyModel,
+            r#"select
+                lobbies.id,
+                lobbies.name,
+                lobbies.channel_id,
+                lobbies.owner_user_id,
+                niches.id as niche_id
+
+                from lobbies
+                join channels on channels.id = lobbies.channel_id
+                join categories on categories.id = channels.category_id
+                join niches on niches.id = categories.niche_id
+                where lobbies.expire_when_empty
+                and lobbies.created_at < now() - make_interval(secs => $1)"#,
+            f64::from(min_age)
+        )
+        .fetch_all(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)
+    }
 }
 
 #[derive(Type, Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
@@ -56,8 +56,13 @@ pub struct ListLobbyArgs {
     pub niche_id: String,
 }
 
+#[derive(Type, Deserialize, Serialize, Default, Debug, Clone, Copy)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum TemporaryContract {
+    /// Deleted once the last client leaves.
+    #[default]
     ExpireWhenEmpty,
+    /// Deleted `expires` seconds after creation, even if clients are inside.
     Expires { expires: i32 },
 }
 
@@ -65,9 +70,11 @@ pub enum TemporaryContract {
 pub struct CreateLobbyArgs {
     pub name: String,
     pub channel_id: String,
+    #[serde(default)]
+    pub contract: TemporaryContract,
 }
 
-#[derive(Type, Serialize, Debug, Clone)]
+#[derive(Type, Serialize, Deserialize, Debug, Clone)]
 pub struct LobbyResource {
     pub id: String,
     pub name: String,
@@ -105,4 +112,33 @@ impl LobbyService {
     pub async fn find_by_id(&self, id: String) -> AppResult<LobbyResource> {
         Ok(self.repository.find_by_id(id).await?.to_node())
     }
+
+    /// Deletes the lobby if its contract is `ExpireWhenEmpty`. Callers are
+    /// expected to have checked that it is empty.
+    pub async fn delete_if_expire_when_empty(&self, id: &str) -> AppResult<bool> {
+        self.repository.delete_if_expire_when_empty(id).await
+    }
+
+    /// Deletes every lobby whose `Expires` contract has run out.
+    pub async fn delete_expired(&self) -> AppResult<Vec<LobbyResource>> {
+        Ok(self
+            .repository
+            .delete_expired()
+            .await?
+            .iter()
+            .map(|model| model.to_node())
+            .collect())
+    }
+
+    /// `ExpireWhenEmpty` lobbies created more than `min_age` seconds ago, which
+    /// may never have been joined.
+    pub async fn list_expire_when_empty(&self, min_age: i32) -> AppResult<Vec<LobbyResource>> {
+        Ok(self
+            .repository
+            .list_expire_when_empty(min_age)
+            .await?
+            .iter()
+            .map(|model| model.to_node())
+            .collect())
+    }
 }
@@ -3,7 +3,7 @@ use std::fmt::{self, Display};
 use base64::{engine::general_purpose, Engine as _};
 use serde::{Deserialize, Serialize};
 use specta::Type;
-use sqlx::{query, query_as, types::time::PrimitiveDateTime};
+use sqlx::{query, query_as, types::time::PrimitiveDateTime, types::JsonValue};
 
 use crate::{
     error::AppResult,
@@ -12,7 +12,7 @@ use crate::{
     DatabasePool,
 };
 
-use super::service::{ListMessageArgs, MessageResource};
+use super::service::{ListMessageArgs, MessageResource, ReactionResource};
 
 pub(crate) struct MessageRepository {
     connection: DatabasePool,
@@ -24,6 +24,9 @@ pub(crate) struct MessageModel {
     pub(super) channel_id: String,
     pub(super) created_at: PrimitiveDateTime,
     pub(super) user_id: String,
+    pub(super) edited_at: Option<PrimitiveDateTime>,
+    pub(super) deleted: bool,
+    pub(super) reactions: JsonValue,
 }
 
 impl Model<MessageResource> for MessageModel {
@@ -33,11 +36,34 @@ impl Model<MessageResource> for MessageModel {
 
     fn to_node(&self) -> MessageResource {
         let timestamp = (self.created_at.assume_utc().unix_timestamp() * 1000);
+        let edited_at = self
+            .edited_at
+            .map(|edited_at| (edited_at.assume_utc().unix_timestamp() * 1000).to_string());
+
+        // Deleted messages only keep their place in the channel
+        if self.deleted {
+            return MessageResource {
+                id: self.id.clone(),
+                user_id: self.user_id.clone(),
+                channel_id: self.channel_id.clone(),
+                timestamp: timestamp.to_string(),
+                contents: String::new(),
+                edited_at,
+                deleted: true,
+                reactions: vec![],
+            };
+        }
+
         MessageResource {
             id: self.id.clone(),
             user_id: self.user_id.clone(),
+            channel_id: self.channel_id.clone(),
             timestamp: timestamp.to_string(),
             contents: self.contents.clone(),
+            edited_at,
+            deleted: false,
+            reactions: serde_json::from_value::<Vec<ReactionResource>>(self.reactions.clone())
+                .unwrap_or_default(),
         }
     }
 }
@@ -48,10 +74,9 @@ impl MessageRepository {
     }
 
     pub async fn add_chat_message(&self, channel_id: String, message: &MessageResource) {
-        let id = ulid::Ulid::new().to_string();
         query!(
             "insert into messages (id, contents, channel_id, user_id) values ($1, $2, $3, $4)",
-            id,
+            message.id,
             message.contents,
             channel_id,
             message.user_id,
@@ -60,6 +85,95 @@ impl MessageRepository {
         .await
         .ok();
     }
+
+    pub async fn find_by_id(&self, id: &str) -> AppResult<Option<MessageModel>> {
+        let message = query_as!(
+            MessageModel,
+            "select
+                m.id,
+                m.contents,
+                m.channel_id,
+                m.created_at,
+                m.user_id,
+                (select max(edited_at) from message_edits where message_id = m.id) as edited_at,
+                exists(select 1 from message_deletions where message_id = m.id) as \"deleted!\",
+                coalesce((
+                    select json_agg(json_build_object('emoji', emoji, 'count', cardinality(user_ids), 'user_ids', user_ids) order by first_reacted_at)
+                    from (
+                        select emoji, array_agg(user_id order by created_at) as user_ids, min(created_at) as first_reacted_at
+                        from message_reactions where message_id = m.id group by emoji
+                    ) reactions
+                ), '[]'::json) as \"reactions!\"
+            from messages m where m.id = $1",
+            id
+        )
+        .fetch_optional(self.connection.as_ref())
+        .await?;
+
+        Ok(message)
+    }
+
+    /// Replaces the contents and keeps the previous ones as an edit.
+    pub async fn edit_message(&self, id: &str, contents: &str) -> AppResult<()> {
+        let mut transaction = self.connection.begin().await?;
+
+        query!(
+            "insert into message_edits (message_id, contents) select id, contents from messages where id = $1",
+            id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        query!(
+            "update messages set contents = $2 where id = $1",
+            id,
+            contents
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_message(&self, id: &str, deleted_by: &str) -> AppResult<()> {
+        query!(
+            "insert into message_deletions (message_id, deleted_by) values ($1, $2) on conflict do nothing",
+            id,
+            deleted_by
+        )
+        .execute(self.connection.as_ref())
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn add_reaction(&self, id: &str, user_id: &str, emoji: &str) -> AppResult<()> {
+        query!(
+            "insert into message_reactions (message_id, user_id, emoji) values ($1, $2, $3) on conflict do nothing",
+            id,
+            user_id,
+            emoji
+        )
+        .execute(self.connection.as_ref())
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_reaction(&self, id: &str, user_id: &str, emoji: &str) -> AppResult<()> {
+        query!(
+            "delete from message_reactions where message_id = $1 and user_id = $2 and emoji = $3",
+            id,
+            user_id,
+            emoji
+        )
+        .execute(self.connection.as_ref())
+        .await?;
+
+        Ok(())
+    }
 }
 
 #[derive(Type, Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
@@ -117,12 +231,21 @@ impl Repository<MessageModel, ListMessageArgs> for MessageRepository {
         let messages = query_as!(
             MessageModel,
             "select
-                id,
-                contents,
-                channel_id,
-                created_at,
-                user_id
-            from messages where channel_id = $1",
+                m.id,
+                m.contents,
+                m.channel_id,
+                m.created_at,
+                m.user_id,
+                (select max(edited_at) from message_edits where message_id = m.id) as edited_at,
+                exists(select 1 from message_deletions where message_id = m.id) as \"deleted!\",
+                coalesce((
+                    select json_agg(json_build_object('emoji', emoji, 'count', cardinality(user_ids), 'user_ids', user_ids) order by first_reacted_at)
+                    from (
+                        select emoji, array_agg(user_id order by created_at) as user_ids, min(created_at) as first_reacted_at
+                        from message_reactions where message_id = m.id group by emoji
+                    ) reactions
+                ), '[]'::json) as \"reactions!\"
+            from messages m where m.channel_id = $1",
             args.channel_id
         )
         .fetch_all(self.connection.as_ref())
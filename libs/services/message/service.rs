@@ -5,9 +5,10 @@ use serde::{Deserialize, Serialize};
 use specta::Type;
 
 use crate::{
-    error::AppResult,
+    error::{AppResult, ServicesError},
     pagination::{
-        connection_from_repository, Cursor, ListResult, Node, PaginationArgs, WithPagination,
+        connection_from_repository, Cursor, ListResult, Model, Node, PaginationArgs,
+        WithPagination,
     },
     repository::Repository,
     DatabasePool,
@@ -15,6 +16,8 @@ use crate::{
 
 use super::repository::{MessageCursor, MessageRepository};
 
+pub const MAX_CHAT_MESSAGE_CHARS: usize = 4000;
+
 #[derive(Type, Serialize, Deserialize, Default, Debug)]
 pub struct ListMessageMeta {}
 
@@ -63,15 +66,27 @@ pub struct AddChatMessageArgs {
     pub contents: String,
 }
 
-#[derive(Type, Serialize, Debug, Clone)]
+#[derive(Type, Serialize, Deserialize, Debug, Clone)]
 pub struct MessageResource {
     pub id: String,
     pub user_id: String,
+    pub channel_id: String,
     pub timestamp: String,
     pub contents: String,
+    pub edited_at: Option<String>,
+    pub deleted: bool,
+    pub reactions: Vec<ReactionResource>,
     // category_tree: Vec<String>,
 }
 
+/// Everyone who reacted to a message with the same emoji.
+#[derive(Type, Serialize, Deserialize, Debug, Clone)]
+pub struct ReactionResource {
+    pub emoji: String,
+    pub count: i32,
+    pub user_ids: Vec<String>,
+}
+
 impl Node for MessageResource {
     fn id(&self) -> String {
         self.id.clone()
@@ -84,8 +99,12 @@ impl MessageResource {
         MessageResource {
             id,
             user_id: args.user_id,
+            channel_id: args.channel_id,
             timestamp: (Utc::now().timestamp() * 1000).to_string(),
             contents: args.contents,
+            edited_at: None,
+            deleted: false,
+            reactions: vec![],
         }
     }
 }
@@ -108,6 +127,51 @@ impl MessageService {
         resource
     }
 
+    pub async fn find_by_id(&self, id: &str) -> AppResult<Option<MessageResource>> {
+        Ok(self
+            .repository
+            .find_by_id(id)
+            .await?
+            .map(|message| message.to_node()))
+    }
+
+    pub async fn edit_message(&self, id: &str, contents: &str) -> AppResult<MessageResource> {
+        self.repository.edit_message(id, contents).await?;
+        self.find_existing(id).await
+    }
+
+    pub async fn delete_message(&self, id: &str, deleted_by: &str) -> AppResult<MessageResource> {
+        self.repository.delete_message(id, deleted_by).await?;
+        self.find_existing(id).await
+    }
+
+    /// Each user reacts with a given emoji at most once; repeats are ignored.
+    pub async fn add_reaction(
+        &self,
+        id: &str,
+        user_id: &str,
+        emoji: &str,
+    ) -> AppResult<MessageResource> {
+        self.repository.add_reaction(id, user_id, emoji).await?;
+        self.find_existing(id).await
+    }
+
+    pub async fn remove_reaction(
+        &self,
+        id: &str,
+        user_id: &str,
+        emoji: &str,
+    ) -> AppResult<MessageResource> {
+        self.repository.remove_reaction(id, user_id, emoji).await?;
+        self.find_existing(id).await
+    }
+
+    async fn find_existing(&self, id: &str) -> AppResult<MessageResource> {
+        self.find_by_id(id)
+            .await?
+            .ok_or_else(|| ServicesError::from(sqlx::Error::RowNotFound))
+    }
+
     pub fn new(pool: DatabasePool) -> Self {
         Self {
             repository: Arc::new(MessageRepository::new(pool)),
@@ -4,14 +4,16 @@ use base64::{engine::general_purpose, Engine as _};
 use serde::{Deserialize, Serialize};
 use specta::Type;
 
+use sqlx::query_scalar;
+
 use crate::{
-    error::AppResult,
+    error::{AppResult, ServicesError},
     pagination::{Cursor, Model, Node, PaginationArgs, WithPagination},
     repository::Repository,
     DatabasePool,
 };
 
-use super::service::{ListNicheArgs, NicheResource};
+use super::service::{ListNicheArgs, NicheResource, NicheRole};
 
 pub(crate) struct NicheRepository {
     connection: DatabasePool,
@@ -66,6 +68,55 @@ impl NicheRepository {
 
         Ok(NicheModel::new(name))
     }
+
+    pub async fn find_member_role(
+        &self,
+        niche_id: &str,
+        user_id: &str,
+    ) -> AppResult<Option<NicheRole>> {
+        query_scalar!(
+            r#"select role as "role: NicheRole" from niche_members where niche_id = $1 and user_id = $2"#,
+            niche_id,
+            user_id
+        )
+        .fetch_optional(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)
+    }
+
+    pub async fn add_member(&self, niche_id: &str, user_id: &str) -> AppResult<Option<NicheRole>> {
+        query_scalar!(
+            r#"insert into niche_members (niche_id, user_id)
+                select id, $2 from niches where id = $1
+                on conflict (niche_id, user_id) do update set role = niche_members.role
+                returning role as "role: NicheRole""#,
+            niche_id,
+            user_id
+        )
+        .fetch_optional(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)
+    }
+
+    pub async fn list_member_niche_ids(&self, user_id: &str) -> AppResult<Vec<String>> {
+        query_scalar!(
+            "select niche_id from niche_members where user_id = $1",
+            user_id
+        )
+        .fetch_all(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)
+    }
+
+    pub async fn list_member_user_ids(&self, niche_ids: &[String]) -> AppResult<Vec<String>> {
+        query_scalar!(
+            "select distinct user_id from niche_members where niche_id = any($1)",
+            niche_ids
+        )
+        .fetch_all(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)
+    }
 }
 
 #[derive(Type, Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
@@ -63,6 +63,21 @@ pub struct NicheResource {
     // category_tree: Vec<String>,
 }
 
+#[derive(PartialEq, sqlx::Type, Type, Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "niche_role", rename_all = "snake_case")]
+pub enum NicheRole {
+    Member,
+    Moderator,
+    Admin,
+}
+
+impl NicheRole {
+    pub fn can_moderate(&self) -> bool {
+        matches!(self, NicheRole::Moderator | NicheRole::Admin)
+    }
+}
+
 impl Node for NicheResource {
     fn id(&self) -> String {
         self.name.clone()
@@ -86,6 +101,31 @@ impl NicheService {
     pub async fn find_by_slug(&self, slug: String) -> AppResult<NicheResource> {
         Ok(self.repository.find_one(slug)?.to_node())
     }
+
+    /// The user's role in the niche, `None` if the user is not a member.
+    pub async fn find_member_role(
+        &self,
+        niche_id: &str,
+        user_id: &str,
+    ) -> AppResult<Option<NicheRole>> {
+        self.repository.find_member_role(niche_id, user_id).await
+    }
+
+    /// Makes the user a member of the niche, keeping the role of an existing
+    /// member. `None` if there is no such niche.
+    pub async fn join(&self, niche_id: &str, user_id: &str) -> AppResult<Option<NicheRole>> {
+        self.repository.add_member(niche_id, user_id).await
+    }
+
+    /// The niches the user has a `niche_members` row in.
+    pub async fn list_member_niche_ids(&self, user_id: &str) -> AppResult<Vec<String>> {
+        self.repository.list_member_niche_ids(user_id).await
+    }
+
+    /// Everyone who is a member of any of the niches.
+    pub async fn list_member_user_ids(&self, niche_ids: &[String]) -> AppResult<Vec<String>> {
+        self.repository.list_member_user_ids(niche_ids).await
+    }
 }
 
 mod tests {
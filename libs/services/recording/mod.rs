@@ -0,0 +1,2 @@
+pub(crate) mod repository;
+pub mod service;
@@ -0,0 +1,76 @@
+use sqlx::{query_as, types::time::PrimitiveDateTime};
+
+use crate::{
+    error::{AppResult, ServicesError},
+    pagination::Model,
+    DatabasePool,
+};
+
+use super::service::RecordingResource;
+
+pub(crate) struct RecordingRepository {
+    connection: DatabasePool,
+}
+
+pub(crate) struct RecordingModel {
+    pub(super) id: String,
+    pub(super) lobby_id: Option<String>,
+    pub(super) owner_user_id: String,
+    pub(super) started_at: PrimitiveDateTime,
+    pub(super) stopped_at: Option<PrimitiveDateTime>,
+}
+
+impl Model<RecordingResource> for RecordingModel {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn to_node(&self) -> RecordingResource {
+        RecordingResource {
+            id: self.id.clone(),
+            lobby_id: self.lobby_id.clone(),
+            owner_user_id: self.owner_user_id.clone(),
+            started_at: (self.started_at.assume_utc().unix_timestamp() * 1000).to_string(),
+            stopped_at: self
+                .stopped_at
+                .map(|stopped_at| (stopped_at.assume_utc().unix_timestamp() * 1000).to_string()),
+        }
+    }
+}
+
+impl RecordingRepository {
+    pub fn new(connection: DatabasePool) -> Self {
+        Self { connection }
+    }
+
+    pub async fn create(&self, lobby_id: &str, owner_user_id: &str) -> AppResult<RecordingModel> {
+        let id = ulid::Ulid::new().to_string();
+
+        query_as!(
+            RecordingModel,
+            "insert into lobby_recordings (id, lobby_id, owner_user_id)
+                values ($1, $2, $3)
+                returning id, lobby_id, owner_user_id, started_at, stopped_at",
+            id,
+            lobby_id,
+            owner_user_id,
+        )
+        .fetch_one(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)
+    }
+
+    pub async fn stop(&self, id: &str) -> AppResult<RecordingModel> {
+        query_as!(
+            RecordingModel,
+            "update lobby_recordings
+                set stopped_at = coalesce(stopped_at, CURRENT_TIMESTAMP)
+                where id = $1
+                returning id, lobby_id, owner_user_id, started_at, stopped_at",
+            id,
+        )
+        .fetch_one(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::{error::AppResult, pagination::Model, DatabasePool};
+
+use super::repository::RecordingRepository;
+
+pub struct RecordingService {
+    repository: Arc<RecordingRepository>,
+}
+
+/// A recording of a lobby. `lobby_id` is cleared once the lobby is deleted,
+/// and `stopped_at` stays empty while the recording runs.
+#[derive(Type, Serialize, Deserialize, Debug, Clone)]
+pub struct RecordingResource {
+    pub id: String,
+    pub lobby_id: Option<String>,
+    pub owner_user_id: String,
+    pub started_at: String,
+    pub stopped_at: Option<String>,
+}
+
+impl RecordingService {
+    pub fn new(pool: DatabasePool) -> Self {
+        Self {
+            repository: Arc::new(RecordingRepository::new(pool)),
+        }
+    }
+
+    /// Records that `owner_user_id` started recording the lobby.
+    pub async fn create(
+        &self,
+        lobby_id: &str,
+        owner_user_id: &str,
+    ) -> AppResult<RecordingResource> {
+        Ok(self
+            .repository
+            .create(lobby_id, owner_user_id)
+            .await?
+            .to_node())
+    }
+
+    pub async fn stop(&self, id: &str) -> AppResult<RecordingResource> {
+        Ok(self.repository.stop(id).await?.to_node())
+    }
+}
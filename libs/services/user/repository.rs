@@ -3,15 +3,16 @@ use std::fmt::{self, Display};
 use base64::{engine::general_purpose, Engine as _};
 use serde::{Deserialize, Serialize};
 use specta::Type;
+use sqlx::query_as;
 
 use crate::{
-    error::AppResult,
+    error::{AppResult, ServicesError},
     pagination::{Cursor, Model, Node, PaginationArgs, WithPagination},
     repository::Repository,
     DatabasePool,
 };
 
-use super::service::{ListUserArgs, UserResource};
+use super::service::{ListUserArgs, UserProfileResource, UserResource, UserStatus};
 
 pub(crate) struct UserRepository {
     connection: DatabasePool,
@@ -53,10 +54,88 @@ impl Model<UserResource> for UserModel {
     }
 }
 
+pub(crate) struct UserProfileModel {
+    pub(super) id: String,
+    pub(super) display_name: Option<String>,
+    pub(super) avatar_url: Option<String>,
+    pub(super) status: UserStatus,
+    pub(super) custom_status: Option<String>,
+}
+
+impl Model<UserProfileResource> for UserProfileModel {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn to_node(&self) -> UserProfileResource {
+        UserProfileResource {
+            id: self.id.clone(),
+            // Users who never picked a name go by their id
+            display_name: self.display_name.clone().unwrap_or_else(|| self.id.clone()),
+            avatar_url: self.avatar_url.clone(),
+            status: self.status,
+            custom_status: self.custom_status.clone(),
+        }
+    }
+}
+
 impl UserRepository {
     pub fn new(connection: DatabasePool) -> Self {
         Self { connection }
     }
+
+    pub async fn find_profile(&self, id: &str) -> AppResult<UserProfileModel> {
+        query_as!(
+            UserProfileModel,
+            r#"select id, display_name, avatar_url, status as "status: UserStatus", custom_status
+                from users where id = $1"#,
+            id
+        )
+        .fetch_one(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)
+    }
+
+    pub async fn list_profiles_in_niches(
+        &self,
+        niche_ids: &[String],
+        user_ids: &[String],
+    ) -> AppResult<Vec<UserProfileModel>> {
+        query_as!(
+            UserProfileModel,
+            r#"select id, display_name, avatar_url, status as "status: UserStatus", custom_status
+                from users
+                where id = any($2)
+                and exists (
+                    select 1 from niche_members
+                    where niche_members.user_id = users.id and niche_members.niche_id = any($1)
+                )"#,
+            niche_ids,
+            user_ids
+        )
+        .fetch_all(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)
+    }
+
+    pub async fn set_status(
+        &self,
+        id: &str,
+        status: UserStatus,
+        custom_status: Option<&str>,
+    ) -> AppResult<UserProfileModel> {
+        query_as!(
+            UserProfileModel,
+            r#"update users set status = $2, custom_status = $3 where id = $1
+                returning id, display_name, avatar_url, status as "status: UserStatus", custom_status"#,
+            id,
+            status as UserStatus,
+            custom_status
+        )
+        .fetch_one(self.connection.as_ref())
+        .await
+        .map_err(ServicesError::from)
+    }
 }
 
 #[derive(Type, Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
@@ -6,7 +6,7 @@ use specta::Type;
 use crate::{
     error::AppResult,
     pagination::{
-        connection_from_repository, Cursor, ListResult, Node, PaginationArgs, WithPagination,
+        connection_from_repository, Cursor, ListResult, Model, Node, PaginationArgs, WithPagination,
     },
     repository::Repository,
     DatabasePool,
@@ -62,6 +62,28 @@ pub struct UserResource {
     pub avatar_url: Option<String>,
 }
 
+/// What a user picked to show to others. Being offline is not a status,
+/// it follows from having no connection.
+#[derive(PartialEq, Eq, sqlx::Type, Type, Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "user_status", rename_all = "snake_case")]
+pub enum UserStatus {
+    Online,
+    Idle,
+    Dnd,
+    /// Shown to others as offline.
+    Invisible,
+}
+
+#[derive(Type, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserProfileResource {
+    pub id: String,
+    pub display_name: String,
+    pub avatar_url: Option<String>,
+    pub status: UserStatus,
+    pub custom_status: Option<String>,
+}
+
 impl Node for UserResource {
     fn id(&self) -> String {
         self.id.clone()
@@ -81,6 +103,38 @@ impl UserService {
             repository: Arc::new(UserRepository::new(pool)),
         }
     }
+
+    pub async fn find_profile(&self, id: &str) -> AppResult<UserProfileResource> {
+        Ok(self.repository.find_profile(id).await?.to_node())
+    }
+
+    /// Profiles of those of the users who are members of any of the niches.
+    pub async fn list_profiles_in_niches(
+        &self,
+        niche_ids: &[String],
+        user_ids: &[String],
+    ) -> AppResult<Vec<UserProfileResource>> {
+        Ok(self
+            .repository
+            .list_profiles_in_niches(niche_ids, user_ids)
+            .await?
+            .iter()
+            .map(|model| model.to_node())
+            .collect())
+    }
+
+    pub async fn set_status(
+        &self,
+        id: &str,
+        status: UserStatus,
+        custom_status: Option<&str>,
+    ) -> AppResult<UserProfileResource> {
+        Ok(self
+            .repository
+            .set_status(id, status, custom_status)
+            .await?
+            .to_node())
+    }
 }
 
 mod tests {